@@ -1,14 +1,25 @@
 #[cfg(windows)]
 extern crate winresource;
 
-#[cfg(windows)]
 fn main() {
-    let mut res = winresource::WindowsResource::new();
-    res.set_icon("src/resources/icon.ico");
-    res.compile().unwrap_or_default();
-    // static_vcruntime::metabuild();
+    #[cfg(windows)]
+    {
+        let mut res = winresource::WindowsResource::new();
+        res.set_icon("src/resources/icon.ico");
+        res.compile().unwrap_or_default();
+        // static_vcruntime::metabuild();
+    }
+    #[cfg(feature = "sila")]
+    compile_sila_protos();
 }
 
-// Dummy main for non-windows platforms
-#[cfg(not(windows))]
-fn main() {}
\ No newline at end of file
+/// gRPC services of the SiLA 2 server, and their clients for the tests. The proto files are parsed by protox, so protoc is not needed.
+#[cfg(feature = "sila")]
+fn compile_sila_protos() {
+    let file_descriptors = protox::compile(["SiLAService.proto", "CellSpinnerController.proto"], ["src/resources/sila"]).expect("Invalid SiLA proto files");
+    let mut config = prost_build::Config::new();
+    config.service_generator(tonic_build::configure().build_client(true).service_generator());
+    config.compile_fds(file_descriptors).expect("Unable to generate the SiLA services");
+    println!("cargo:rerun-if-changed=src/resources/sila");
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, Write};
+use std::fs::{self, File};
+use std::io::Write;
 use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Arc;
@@ -12,16 +12,30 @@ use dashmap::DashMap;
 use dirs::home_dir;
 use egui::{Color32, FontFamily, FontId, RichText, Sense};
 use egui::TextStyle::{Body, Button, Heading, Monospace, Small};
-use egui_dock::{Style, Tree};
+use egui_dock::{Node, Style, Tree};
 use egui_toast::{Toast, ToastKind, Toasts};
 use parking_lot::Mutex;
 use rfd::FileDialog;
 
 use crate::tabs::Tabs;
+use crate::utils::enums::MessageKind;
+use crate::utils::errors::SerialError;
 use crate::utils::helpers::send_toast;
+#[cfg(feature = "http-api")]
+use crate::utils::http_api::{ApiServer, ApiSettings};
+#[cfg(feature = "mqtt")]
+use crate::utils::mqtt::{MqttBridge, MqttSettings};
+#[cfg(feature = "modbus")]
+use crate::utils::modbus::{BLOCK_SIZE, ModbusServer, ModbusSettings};
+#[cfg(feature = "sila")]
+use crate::utils::sila::{SilaServer, SilaSettings};
+#[cfg(feature = "notifications")]
+use crate::utils::notifications::{self, Notification, Notifier, NotificationFilter, NotificationSettings, SmtpSecurity};
 use crate::utils::motor::Motor;
+use crate::utils::experiment::{Experiment, ExperimentTab};
+use crate::utils::protocol_file::ProtocolDocument;
 use crate::utils::protocols::Protocol;
-use crate::utils::structs::{Channels, Durations, FontAndButtonSize, Message, WindowsState};
+use crate::utils::structs::{Channels, Durations, FontAndButtonSize, Message, SerialSettings, WindowsState};
 use crate::utils::widget_rotating_tube::RotatingTube;
 
 pub const FONT_BUTTON_SIZE: FontAndButtonSize = FontAndButtonSize {
@@ -32,13 +46,6 @@ pub const FONT_BUTTON_SIZE: FontAndButtonSize = FontAndButtonSize {
     button_default: egui::vec2(100.0, 20.0),
 };
 
-pub const THREAD_SLEEP: u64 = 10;
-pub const MAX_ACCELERATION: u32 = 20_000;
-pub const MAX_RPM: u32 = 5_000;
-// 1 year in milliseconds
-pub const MAX_DURATION_MS: u64 = 365 * 24 * 60 * 60 * 1000;
-pub const MAX_POINTS_GRAPHS: usize = 250_000;
-pub const BYTES: usize = 110;
 pub const THEME: Theme = Theme {
     base: Color32::from_rgb(249, 251, 255),
     ..LATTE
@@ -62,8 +69,10 @@ pub struct CellSpinner {
     allowed_to_close: bool,
     // Promises
     promise_serial_connect: Arc<DashMap<usize, Option<()>>>,
+    promise_motor_start: Arc<DashMap<usize, Option<()>>>,
     // Serial
     selected_port: HashMap<usize, String>,
+    serial_settings: HashMap<String, SerialSettings>,
     available_ports: Vec<String>,
     already_connected_ports: Arc<Mutex<Vec<String>>>,
     // Motor
@@ -79,7 +88,36 @@ pub struct CellSpinner {
     added_tabs: Vec<usize>,
     can_tab_close: bool,
     path_config: PathBuf,
-
+    // Session
+    is_auto_connect: bool,
+    session: Option<Experiment>,
+    session_tree: Option<Tree<usize>>,
+    tabs_to_connect: Vec<usize>,
+    // HTTP API
+    #[cfg(feature = "http-api")]
+    api_settings: ApiSettings,
+    #[cfg(feature = "http-api")]
+    api_server: Option<ApiServer>,
+    // MQTT
+    #[cfg(feature = "mqtt")]
+    mqtt_settings: MqttSettings,
+    #[cfg(feature = "mqtt")]
+    mqtt_bridge: Option<MqttBridge>,
+    // Modbus
+    #[cfg(feature = "modbus")]
+    modbus_settings: ModbusSettings,
+    #[cfg(feature = "modbus")]
+    modbus_server: Option<ModbusServer>,
+    // SiLA 2
+    #[cfg(feature = "sila")]
+    sila_settings: SilaSettings,
+    #[cfg(feature = "sila")]
+    sila_server: Option<SilaServer>,
+    // Notifications
+    #[cfg(feature = "notifications")]
+    notification_settings: NotificationSettings,
+    #[cfg(feature = "notifications")]
+    notifier: Option<Notifier>,
 }
 
 impl Default for CellSpinner {
@@ -99,7 +137,9 @@ impl Default for CellSpinner {
             error_log: vec![],
             allowed_to_close: false,
             promise_serial_connect: Arc::new(Default::default()),
+            promise_motor_start: Arc::new(Default::default()),
             selected_port: HashMap::new(),
+            serial_settings: HashMap::new(),
             available_ports: vec![],
             already_connected_ports: Arc::new(Mutex::new(vec![])),
             current_tab_counter: 1,
@@ -112,6 +152,30 @@ impl Default for CellSpinner {
             path_config: home_dir().unwrap(),
             durations: Default::default(),
             rotating_tubes: Default::default(),
+            is_auto_connect: false,
+            session: None,
+            session_tree: None,
+            tabs_to_connect: vec![],
+            #[cfg(feature = "http-api")]
+            api_settings: ApiSettings::default(),
+            #[cfg(feature = "http-api")]
+            api_server: None,
+            #[cfg(feature = "mqtt")]
+            mqtt_settings: MqttSettings::default(),
+            #[cfg(feature = "mqtt")]
+            mqtt_bridge: None,
+            #[cfg(feature = "modbus")]
+            modbus_settings: ModbusSettings::default(),
+            #[cfg(feature = "modbus")]
+            modbus_server: None,
+            #[cfg(feature = "sila")]
+            sila_settings: SilaSettings::default(),
+            #[cfg(feature = "sila")]
+            sila_server: None,
+            #[cfg(feature = "notifications")]
+            notification_settings: NotificationSettings::default(),
+            #[cfg(feature = "notifications")]
+            notifier: None,
         }
     }
 }
@@ -130,7 +194,40 @@ impl CellSpinner {
         ].into();
         cc.egui_ctx.set_style(style);
         catppuccin_egui::set_theme(&cc.egui_ctx, THEME);
-        Default::default()
+        let mut app: CellSpinner = Default::default();
+        if let Some(storage) = cc.storage {
+            app.serial_settings = eframe::get_value(storage, "serial_settings").unwrap_or_default();
+            app.is_auto_connect = eframe::get_value(storage, "auto_connect").unwrap_or_default();
+            app.session = eframe::get_value::<String>(storage, "session").and_then(|json| match Experiment::from_json(&json) {
+                Ok(session) => Some(session),
+                Err(err) => {
+                    tracing::warn!("Unable to read the last session: {}", err);
+                    None
+                }
+            });
+            app.session_tree = eframe::get_value(storage, "dock_tree");
+            #[cfg(feature = "http-api")]
+            {
+                app.api_settings = eframe::get_value(storage, "api_settings").unwrap_or_default();
+            }
+            #[cfg(feature = "mqtt")]
+            {
+                app.mqtt_settings = eframe::get_value(storage, "mqtt_settings").unwrap_or_default();
+            }
+            #[cfg(feature = "modbus")]
+            {
+                app.modbus_settings = eframe::get_value(storage, "modbus_settings").unwrap_or_default();
+            }
+            #[cfg(feature = "sila")]
+            {
+                app.sila_settings = eframe::get_value(storage, "sila_settings").unwrap_or_default();
+            }
+            #[cfg(feature = "notifications")]
+            {
+                app.notification_settings = eframe::get_value(storage, "notification_settings").unwrap_or_default();
+            }
+        }
+        app
     }
 
     /// Function executing on first frame.
@@ -142,27 +239,73 @@ impl CellSpinner {
         let (toast_tx, toast_rx) = std::sync::mpsc::channel();
         self.channels.toast_tx = Some(toast_tx);
         self.channels.toast_rx = Some(toast_rx);
-        let message: Message = Message::new(ToastKind::Info, &format!("Cell Spinner v.{}", self.app_version), None, None, 3, false);
+        let message: Message = Message::new(MessageKind::Info, &format!("Cell Spinner v.{}", self.app_version), None, None, 3, false);
         self.message_handler(message);
         // Setup channels for Message.
         let (message_tx, message_rx) = std::sync::mpsc::channel();
         self.channels.message_tx = Some(message_tx);
         self.channels.message_rx = Some(message_rx);
-        self.init_tab(1);
+        match self.session.take() {
+            Some(session) => self.restore_session(session),
+            None => self.init_tab(1),
+        }
+        #[cfg(feature = "http-api")]
+        self.restart_api_server();
+        #[cfg(feature = "mqtt")]
+        self.restart_mqtt_bridge();
+        #[cfg(feature = "modbus")]
+        self.restart_modbus_server();
+        #[cfg(feature = "sila")]
+        self.restart_sila_server();
+        #[cfg(feature = "notifications")]
+        self.restart_notifier();
         self.is_first_frame = false;
     }
 
+    /// Recreate the tabs and the dock layout of the last session.
+    fn restore_session(&mut self, session: Experiment) {
+        if let Err(err) = self.apply_experiment(&session, true) {
+            self.message_handler(Message::new(MessageKind::Error, "Error while restoring the last session", Some(err), None, 3, false));
+            self.init_tab(1);
+            return;
+        }
+        // The tabs are renumbered in the order of the saved layout.
+        if let Some(mut tree) = self.session_tree.take() {
+            let mut tab_count = 0;
+            tree.iter_mut().for_each(|node| {
+                if let Node::Leaf { tabs, .. } = node {
+                    tabs.iter_mut().for_each(|tab| {
+                        tab_count += 1;
+                        *tab = tab_count;
+                    });
+                }
+            });
+            if tab_count == self.added_tabs.len() {
+                self.tree = tree;
+            }
+        }
+        if self.is_auto_connect {
+            self.tabs_to_connect = self.added_tabs.clone();
+        }
+        self.message_handler(Message::new(MessageKind::Info, "Last session restored", None, None, 3, false));
+    }
+
     /// Message handler.
     fn message_handler(&mut self, message: Message) {
         match message.kind {
-            ToastKind::Error => {
+            MessageKind::Error => {
                 if message.error.is_none() {
                     panic!("Error message without error");
                 }
+                let hint = match message.serial_error() {
+                    Some(err) if err.is_connection_lost() => " - ⚠️YOU SHOULD RECONNECT⚠️",
+                    Some(SerialError::PortInUse { .. }) => " - Refresh the list of serial ports",
+                    _ => "",
+                };
                 let text = if let Some(origin) = message.origin {
-                    format!("{} 💠 {}: {} - {:?}", Local::now().format("%d-%m-%Y %H:%M:%S"), origin, message.message, message.error.unwrap())
+                    format!("{} 💠 {}: {}{} - {:?}", Local::now().format("%d-%m-%Y %H:%M:%S"), origin, message.message, hint, message.error.unwrap())
                 } else {
-                    format!("{} 💠 {} - {:?}", Local::now().format("%d-%m-%Y %H:%M:%S"), message.message, message.error.unwrap())
+                    format!("{} 💠 {}{} - {:?}", Local::now().format("%d-%m-%Y %H:%M:%S"), message.message, hint, message.error.unwrap())
                 };
                 tracing::error!(text);
                 // if message.message.contains("Error while reading serial port") { // Hack to disconnect the motor if the serial port is disconnected
@@ -192,7 +335,7 @@ impl CellSpinner {
                     message.message
                 };
                 if !message.is_waiting {
-                    send_toast(&self.channels.toast_tx, message.kind, text.clone(), message.duration);
+                    send_toast(&self.channels.toast_tx, message.kind.into(), text.clone(), message.duration);
                 } else {
                     self.info_message = text.clone();
                 }
@@ -216,13 +359,17 @@ impl CellSpinner {
             }
             Err(err) => {
                 let error = anyhow!(err);
-                self.message_handler(Message::new(ToastKind::Error, "Error while listing serial ports", Some(error), Some(format!("Motor {}", tab)), 3, false));
+                self.message_handler(Message::new(MessageKind::Error, "Error while listing serial ports", Some(error), Some(format!("Motor {}", tab)), 3, false));
                 vec!["".to_string()]
             }
         };
         self.selected_port.insert(tab, available_ports.get(0).unwrap_or(&"".to_string()).clone());
+        if let Some(settings) = self.serial_settings.get(self.selected_port.get(&tab).unwrap()) {
+            self.motor.get_mut(&tab).unwrap().serial.settings = *settings;
+        }
         self.available_ports = available_ports;
         self.promise_serial_connect.insert(tab, None);
+        self.promise_motor_start.insert(tab, None);
         self.rotating_tubes.insert(tab, (RotatingTube::new(65.0, THEME.sapphire), RotatingTube::new(65.0, THEME.blue)));
     }
 
@@ -255,7 +402,7 @@ impl CellSpinner {
                                 .spawn() {
                                 Ok(_) => { self.windows_state.is_error_log_open = false; }
                                 Err(err) => {
-                                    self.message_handler(Message::new(ToastKind::Error, "Error while opening the log folder", Some(anyhow!(err)), None, 3, false));
+                                    self.message_handler(Message::new(MessageKind::Error, "Error while opening the log folder", Some(anyhow!(err)), None, 3, false));
                                 }
                             }
                         }
@@ -299,6 +446,428 @@ impl CellSpinner {
             });
     }
 
+    /// Metadata of the protocol of the focused tab, asked before saving it.
+    fn window_save_config(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_save_config_open {
+            return;
+        }
+        let tab = self.get_focused_tab();
+        let mut is_saving = false;
+        egui::Window::new("Save config")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let mut motor = self.motor.get_mut(&tab).unwrap();
+                egui::Grid::new("protocol_metadata").num_columns(2).show(ui, |ui| {
+                    ui.label("Name");
+                    ui.text_edit_singleline(&mut motor.protocol_metadata.name);
+                    ui.end_row();
+                    ui.label("Author");
+                    ui.text_edit_singleline(&mut motor.protocol_metadata.author);
+                    ui.end_row();
+                    ui.label("Description");
+                    ui.text_edit_multiline(&mut motor.protocol_metadata.description);
+                    ui.end_row();
+                });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Save").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_saving = true;
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CANCEL").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_save_config_open = false;
+                    }
+                });
+            });
+        if is_saving {
+            self.windows_state.is_save_config_open = false;
+            self.export_configuration(&tab);
+        }
+    }
+
+    /// Stop the HTTP API, then start it again with the current settings if it is enabled.
+    #[cfg(feature = "http-api")]
+    fn restart_api_server(&mut self) {
+        self.api_server = None;
+        if !self.api_settings.is_enabled {
+            return;
+        }
+        match ApiServer::start(&self.api_settings, self.motor.clone(), self.channels.message_tx.clone()) {
+            Ok(server) => {
+                let message = Message::new(MessageKind::Info, &format!("HTTP API listening on port {}", server.port), None, None, 3, false);
+                self.message_handler(message);
+                self.api_server = Some(server);
+            }
+            Err(err) => {
+                let message = Message::new(MessageKind::Error, "Error while starting the HTTP API", Some(anyhow!(err)), None, 3, false);
+                self.message_handler(message);
+            }
+        }
+    }
+
+    /// Settings of the HTTP API.
+    #[cfg(feature = "http-api")]
+    fn window_api(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_api_open {
+            return;
+        }
+        let mut is_applying = false;
+        egui::Window::new("HTTP API")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("api_settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Enabled");
+                    ui.checkbox(&mut self.api_settings.is_enabled, "")
+                        .on_hover_text("Let the programs of this computer control the motors");
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut self.api_settings.port).clamp_range(1024..=65535));
+                    ui.end_row();
+                    ui.label("Token");
+                    ui.add(egui::TextEdit::singleline(&mut self.api_settings.token).password(true))
+                        .on_hover_text("Sent by the programs as \"Authorization: Bearer <token>\". Leave empty to accept every request.\n\
+                        The token is not saved: enter it again after each launch, the server stays disabled until then.");
+                    ui.end_row();
+                    ui.label("Metrics");
+                    ui.checkbox(&mut self.api_settings.is_metrics_enabled, "")
+                        .on_hover_text("Serve the metrics of the motors to Prometheus on /metrics, with the same token");
+                    ui.end_row();
+                });
+                match &self.api_server {
+                    Some(server) => ui.label(format!("Listening on http://127.0.0.1:{}", server.port)),
+                    None => ui.label("Stopped"),
+                };
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Apply").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_applying = true;
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CLOSE").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_api_open = false;
+                    }
+                });
+            });
+        if is_applying {
+            self.restart_api_server();
+        }
+    }
+
+    /// Disconnect from the MQTT broker, then connect again with the current settings if MQTT is enabled.
+    #[cfg(feature = "mqtt")]
+    fn restart_mqtt_bridge(&mut self) {
+        self.mqtt_bridge = None;
+        if self.mqtt_settings.is_enabled {
+            self.mqtt_bridge = Some(MqttBridge::start(&self.mqtt_settings, self.motor.clone(), self.channels.message_tx.clone()));
+        }
+    }
+
+    /// Settings of the MQTT client.
+    #[cfg(feature = "mqtt")]
+    fn window_mqtt(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_mqtt_open {
+            return;
+        }
+        let mut is_applying = false;
+        egui::Window::new("MQTT")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("mqtt_settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Enabled");
+                    ui.checkbox(&mut self.mqtt_settings.is_enabled, "")
+                        .on_hover_text("Publish the states of the motors and receive the start/stop commands");
+                    ui.end_row();
+                    ui.label("Broker");
+                    ui.text_edit_singleline(&mut self.mqtt_settings.host);
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut self.mqtt_settings.port).clamp_range(1..=65535));
+                    ui.end_row();
+                    ui.label("Client ID");
+                    ui.text_edit_singleline(&mut self.mqtt_settings.client_id);
+                    ui.end_row();
+                    ui.label("Username");
+                    ui.text_edit_singleline(&mut self.mqtt_settings.username)
+                        .on_hover_text("Leave empty if the broker does not need authentication");
+                    ui.end_row();
+                    ui.label("Password");
+                    ui.add(egui::TextEdit::singleline(&mut self.mqtt_settings.password).password(true))
+                        .on_hover_text("Not saved: enter it again after each launch, the client stays disabled until then");
+                    ui.end_row();
+                    ui.label("Topic prefix");
+                    ui.text_edit_singleline(&mut self.mqtt_settings.topic_prefix)
+                        .on_hover_text(format!("States on {0}/motors/<tab>/state, commands on {0}/motors/<tab>/command", self.mqtt_settings.topic_prefix));
+                    ui.end_row();
+                });
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Apply").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_applying = true;
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CLOSE").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_mqtt_open = false;
+                    }
+                });
+            });
+        if is_applying {
+            self.restart_mqtt_bridge();
+        }
+    }
+
+    /// Stop the Modbus server, then start it again with the current settings if it is enabled.
+    #[cfg(feature = "modbus")]
+    fn restart_modbus_server(&mut self) {
+        self.modbus_server = None;
+        if !self.modbus_settings.is_enabled {
+            return;
+        }
+        match ModbusServer::start(&self.modbus_settings, self.motor.clone(), self.channels.message_tx.clone()) {
+            Ok(server) => {
+                let message = Message::new(MessageKind::Info, &format!("Modbus server listening on {}", server.address), None, None, 3, false);
+                self.message_handler(message);
+                self.modbus_server = Some(server);
+            }
+            Err(err) => {
+                let message = Message::new(MessageKind::Error, "Error while starting the Modbus server", Some(anyhow!(err)), None, 3, false);
+                self.message_handler(message);
+            }
+        }
+    }
+
+    /// Settings of the Modbus TCP server.
+    #[cfg(feature = "modbus")]
+    fn window_modbus(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_modbus_open {
+            return;
+        }
+        let mut is_applying = false;
+        egui::Window::new("Modbus TCP")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("modbus_settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Enabled");
+                    ui.checkbox(&mut self.modbus_settings.is_enabled, "")
+                        .on_hover_text("Let the PLCs read the states of the motors, change their protocol and start or stop them");
+                    ui.end_row();
+                    ui.label("Address");
+                    ui.text_edit_singleline(&mut self.modbus_settings.address)
+                        .on_hover_text("0.0.0.0 to accept the PLCs of the network. ⚠️ Modbus has no access control.");
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut self.modbus_settings.port).clamp_range(1..=65535))
+                        .on_hover_text("The standard Modbus port 502 needs administrator rights on Linux and macOS.");
+                    ui.end_row();
+                });
+                ui.label(format!("The registers of motor n start at address (n - 1) × {}.", BLOCK_SIZE));
+                match &self.modbus_server {
+                    Some(server) => ui.label(format!("Listening on {}", server.address)),
+                    None => ui.label("Stopped"),
+                };
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Apply").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_applying = true;
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CLOSE").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_modbus_open = false;
+                    }
+                });
+            });
+        if is_applying {
+            self.restart_modbus_server();
+        }
+    }
+
+    /// Stop the SiLA 2 server, then start it again with the current settings if it is enabled.
+    #[cfg(feature = "sila")]
+    fn restart_sila_server(&mut self) {
+        self.sila_server = None;
+        if !self.sila_settings.is_enabled {
+            return;
+        }
+        match SilaServer::start(&self.sila_settings, self.motor.clone(), self.channels.message_tx.clone()) {
+            Ok(server) => {
+                let message = Message::new(MessageKind::Info, &format!("SiLA server listening on {}", server.address), None, None, 3, false);
+                self.message_handler(message);
+                self.sila_server = Some(server);
+            }
+            Err(err) => {
+                let message = Message::new(MessageKind::Error, "Error while starting the SiLA server", Some(anyhow!(err)), None, 3, false);
+                self.message_handler(message);
+            }
+        }
+    }
+
+    /// Settings of the SiLA 2 server.
+    #[cfg(feature = "sila")]
+    fn window_sila(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_sila_open {
+            return;
+        }
+        let mut is_applying = false;
+        egui::Window::new("SiLA 2")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("sila_settings").num_columns(2).show(ui, |ui| {
+                    ui.label("Enabled");
+                    ui.checkbox(&mut self.sila_settings.is_enabled, "")
+                        .on_hover_text("Let the SiLA clients upload protocols, start or stop the motors and follow their runs");
+                    ui.end_row();
+                    ui.label("Address");
+                    ui.text_edit_singleline(&mut self.sila_settings.address)
+                        .on_hover_text("0.0.0.0 to accept the schedulers of the network. ⚠️ The connection is not encrypted.");
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut self.sila_settings.port).clamp_range(1..=65535));
+                    ui.end_row();
+                    ui.label("Server name");
+                    ui.text_edit_singleline(&mut self.sila_settings.server_name);
+                    ui.end_row();
+                    ui.label("Server UUID");
+                    ui.label(&self.sila_settings.server_uuid);
+                    ui.end_row();
+                });
+                ui.label("The motor ids are the numbers of the tabs.");
+                match &self.sila_server {
+                    Some(server) => ui.label(format!("Listening on {}", server.address)),
+                    None => ui.label("Stopped"),
+                };
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Apply").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_applying = true;
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CLOSE").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_sila_open = false;
+                    }
+                });
+            });
+        if is_applying {
+            self.restart_sila_server();
+        }
+    }
+
+    /// Stop the notifier, then start it again with the current settings if a channel is enabled.
+    #[cfg(feature = "notifications")]
+    fn restart_notifier(&mut self) {
+        self.notifier = None;
+        if self.notification_settings.is_enabled() {
+            self.notifier = Some(Notifier::start(&self.notification_settings, self.motor.clone(), self.channels.message_tx.clone()));
+        }
+    }
+
+    /// Settings of the webhook and e-mail notifications.
+    #[cfg(feature = "notifications")]
+    fn window_notifications(&mut self, ctx: &egui::Context) {
+        if !self.windows_state.is_notifications_open {
+            return;
+        }
+        let filter_ui = |ui: &mut egui::Ui, filter: &mut NotificationFilter| {
+            ui.label("Send on");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut filter.is_finished, "Finished");
+                ui.checkbox(&mut filter.is_fault, "Fault");
+                ui.checkbox(&mut filter.is_connection_lost, "Connection lost");
+            });
+            ui.end_row();
+        };
+        let mut is_applying = false;
+        egui::Window::new("Notifications")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.heading("Webhook");
+                egui::Grid::new("webhook_settings").num_columns(2).show(ui, |ui| {
+                    let settings = &mut self.notification_settings.webhook;
+                    ui.label("Enabled");
+                    ui.checkbox(&mut settings.is_enabled, "");
+                    ui.end_row();
+                    ui.label("URL");
+                    ui.text_edit_singleline(&mut settings.url)
+                        .on_hover_text("Receives the notifications as JSON in a POST request");
+                    ui.end_row();
+                    filter_ui(ui, &mut settings.filter);
+                });
+                ui.separator();
+                ui.heading("E-mail");
+                egui::Grid::new("email_settings").num_columns(2).show(ui, |ui| {
+                    let settings = &mut self.notification_settings.email;
+                    ui.label("Enabled");
+                    ui.checkbox(&mut settings.is_enabled, "");
+                    ui.end_row();
+                    ui.label("SMTP server");
+                    ui.text_edit_singleline(&mut settings.host);
+                    ui.end_row();
+                    ui.label("Port");
+                    ui.add(egui::DragValue::new(&mut settings.port).clamp_range(1..=65535));
+                    ui.end_row();
+                    ui.label("Encryption");
+                    egui::ComboBox::from_id_source("smtp_security")
+                        .selected_text(settings.security.to_string())
+                        .show_ui(ui, |ui| {
+                            for security in [SmtpSecurity::StartTls, SmtpSecurity::Tls, SmtpSecurity::None] {
+                                ui.selectable_value(&mut settings.security, security, security.to_string());
+                            }
+                        });
+                    ui.end_row();
+                    ui.label("Username");
+                    ui.text_edit_singleline(&mut settings.username)
+                        .on_hover_text("Leave empty if the server does not need authentication");
+                    ui.end_row();
+                    ui.label("Password");
+                    ui.add(egui::TextEdit::singleline(&mut settings.password).password(true))
+                        .on_hover_text("Not saved: enter it again after each launch, the e-mails stay disabled until then");
+                    ui.end_row();
+                    ui.label("From");
+                    ui.text_edit_singleline(&mut settings.from);
+                    ui.end_row();
+                    ui.label("To");
+                    ui.text_edit_singleline(&mut settings.to)
+                        .on_hover_text("Addresses separated by commas");
+                    ui.end_row();
+                    filter_ui(ui, &mut settings.filter);
+                });
+                match &self.notifier {
+                    Some(_) => ui.label("Watching the motors"),
+                    None => ui.label("Stopped"),
+                };
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Apply").color(Color32::WHITE)).fill(THEME.green)).clicked() {
+                        is_applying = true;
+                    }
+                    ui.separator();
+                    if ui.button("Send test notification")
+                        .on_hover_text("Send a test notification through the enabled channels, with the settings above")
+                        .clicked() {
+                        notifications::notify(&self.notification_settings, Notification::test(), self.channels.message_tx.clone());
+                    }
+                    ui.separator();
+                    if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("CLOSE").color(Color32::WHITE)).fill(THEME.blue)).clicked() {
+                        self.windows_state.is_notifications_open = false;
+                    }
+                });
+            });
+        if is_applying {
+            self.restart_notifier();
+        }
+    }
+
+    fn get_focused_tab(&mut self) -> usize {
+        match self.tree.find_active_focused() {
+            Some(active_tab) => *active_tab.1,
+            None => self.added_tabs[0],
+        }
+    }
+
     // Export the configuration HashMap to a JSON file.
     fn export_configuration(&mut self, tab: &usize) {
         let mut fn_export = || -> Result<(), Error> {
@@ -307,21 +876,125 @@ impl CellSpinner {
                 .save_file()
                 .unwrap_or_default();
             let mut file = File::create(&self.path_config)?;
-            let protocol = self.motor.get(tab).unwrap().protocol;
-            let json = serde_json::to_string_pretty(&protocol).unwrap();
-            file.write_all(json.as_bytes()).unwrap();
+            let document = self.motor.get_mut(tab).unwrap().protocol_document();
+            file.write_all(document.to_json()?.as_bytes())?;
             let current_motor = self.motor.get(tab).unwrap().name.to_string();
-            let message: Message = Message::new(ToastKind::Info, &format!("Configuration exported to {:?}!", &self.path_config.file_name().unwrap_or_default()), None, Some(current_motor), 3, false);
+            let message: Message = Message::new(MessageKind::Info, &format!("Configuration exported to {:?}!", &self.path_config.file_name().unwrap_or_default()), None, Some(current_motor), 3, false);
             self.message_handler(message);
             Ok(())
         };
         if let Err(err) = fn_export() {
             let current_motor = self.motor.get(tab).unwrap().name.to_string();
-            let message: Message = Message::new(ToastKind::Error, "Error while exporting the configuration", Some(err), Some(current_motor), 3, false);
+            let message: Message = Message::new(MessageKind::Error, "Error while exporting the configuration", Some(err), Some(current_motor), 3, false);
+            self.message_handler(message);
+        }
+    }
+
+    /// Save every tab to a JSON file, in the order of the tab bar.
+    fn save_experiment(&mut self) {
+        let mut fn_save = || -> Result<(), Error> {
+            self.path_config = FileDialog::new()
+                .add_filter("json", &["json"])
+                .save_file()
+                .unwrap_or_default();
+            let mut file = File::create(&self.path_config)?;
+            file.write_all(self.current_experiment()?.to_json()?.as_bytes())?;
+            let message: Message = Message::new(MessageKind::Info, &format!("Experiment saved to {:?}!", &self.path_config.file_name().unwrap_or_default()), None, None, 3, false);
+            self.message_handler(message);
+            Ok(())
+        };
+        if let Err(err) = fn_save() {
+            let message: Message = Message::new(MessageKind::Error, "Error while saving the experiment", Some(err), None, 3, false);
+            self.message_handler(message);
+        }
+    }
+
+    /// Every tab, in the order of the tab bar.
+    fn current_experiment(&mut self) -> Result<Experiment, Error> {
+        let mut tabs = vec![];
+        for tab in self.tree.tabs() {
+            let mut motor = self.motor.get_mut(tab).unwrap();
+            let document = motor.protocol_document();
+            let serial_port = self.selected_port.get(tab).cloned().unwrap_or_default();
+            tabs.push(ExperimentTab::new(motor.name.clone(), serial_port, motor.serial.settings, &document)?);
+        }
+        Ok(Experiment::new(tabs))
+    }
+
+    /// Replace every tab with the tabs of an experiment file. All the motors must be disconnected.
+    fn load_experiment(&mut self) {
+        let mut fn_load = || -> Result<(), Error> {
+            if self.motor.iter().any(|motor| motor.get_is_connected()) {
+                return Err(anyhow!("Disconnect all the motors first"));
+            }
+            self.path_config = FileDialog::new()
+                .add_filter("json", &["json"])
+                .pick_file()
+                .unwrap_or_default();
+            let json = fs::read_to_string(&self.path_config)?;
+            let experiment = Experiment::from_json(&json)?;
+            self.apply_experiment(&experiment, false)?;
+            let message: Message = Message::new(MessageKind::Info, &format!("Experiment {:?} loaded with {} motors!", &self.path_config.file_name().unwrap_or_default(), experiment.tabs.len()), None, None, 3, false);
+            self.message_handler(message);
+            Ok(())
+        };
+        if let Err(err) = fn_load() {
+            let message: Message = Message::new(MessageKind::Error, &format!("Error while loading the experiment {:?}", &self.path_config.file_name().unwrap_or_default()), Some(err), None, 3, false);
             self.message_handler(message);
         }
     }
 
+    /// Replace every tab with the tabs of the experiment. Nothing is changed if a protocol cannot be read.
+    /// The protocols of a restored session are put back as they were left, the others are imported only if they are valid.
+    fn apply_experiment(&mut self, experiment: &Experiment, is_restored: bool) -> Result<(), Error> {
+        if experiment.tabs.is_empty() {
+            return Err(anyhow!("The experiment has no motor"));
+        }
+        // Read every protocol before dropping the current tabs.
+        let documents = experiment.tabs.iter().map(|tab| tab.protocol_document()).collect::<Result<Vec<_>, _>>()?;
+        self.added_tabs.clear();
+        self.motor.clear();
+        self.durations.clear();
+        self.motor_name.clear();
+        self.selected_port.clear();
+        self.promise_serial_connect.clear();
+        self.promise_motor_start.clear();
+        self.rotating_tubes.clear();
+        let mut errors_import: Vec<(String, Error)> = vec![];
+        for (index, (experiment_tab, document)) in experiment.tabs.iter().zip(documents.iter()).enumerate() {
+            let tab = index + 1;
+            self.init_tab(tab);
+            self.serial_settings.insert(experiment_tab.serial_port.clone(), experiment_tab.serial_settings);
+            self.selected_port.insert(tab, experiment_tab.serial_port.clone());
+            self.motor_name.insert(tab, experiment_tab.motor_name.clone());
+            let mut motor = self.motor.get_mut(&tab).unwrap();
+            motor.name = experiment_tab.motor_name.clone();
+            motor.serial.settings = experiment_tab.serial_settings;
+            // An untouched tab keeps the default protocol, which is not runnable yet.
+            let is_default = document.protocol == Protocol::default() && document.step_protocol.is_none();
+            if is_restored {
+                motor.restore_document(document);
+            } else if !is_default {
+                if let Err(err) = motor.import_document(document) {
+                    errors_import.push((motor.name.clone(), err.into()));
+                }
+            }
+            self.durations.get_mut(&tab).unwrap().update_from_protocol(&motor.protocol);
+            motor.generate_graph_rotation();
+            motor.generate_graph_agitation();
+            motor.calculate_expected_end_date();
+        }
+        let tab_count = experiment.tabs.len();
+        self.tree = Tree::new((1..=tab_count).collect());
+        self.current_tab_counter = tab_count;
+        self.absolute_tab_counter = tab_count;
+        for (motor_name, err) in errors_import.into_iter() {
+            let message: Message = Message::new(MessageKind::Error, "Error while importing the protocol of the experiment", Some(err), Some(motor_name), 3, false);
+            self.message_handler(message);
+        }
+        Ok(())
+    }
+
     // Import the configuration from a JSON file.
     fn import_configuration(&mut self, tab: &usize, import_for_all: bool) {
         if self.motor.get(tab).unwrap().get_is_running() {
@@ -332,64 +1005,40 @@ impl CellSpinner {
                 .add_filter("json", &["json"])
                 .pick_file()
                 .unwrap_or_default();
-            let file = File::open(&self.path_config)?;
-            let reader = BufReader::new(file);
-            let protocol: Protocol = serde_json::from_reader(reader)?;
+            let json = fs::read_to_string(&self.path_config)?;
+            let mut document = ProtocolDocument::from_json(&json)?;
+            if document.metadata.name.is_empty() {
+                document.metadata.name = self.path_config.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            }
             if import_for_all {
                 let mut errors_import: Vec<(String, Error)> = vec![];
-                self.motor.iter_mut().for_each(|mut motor| match motor.import_protocol(protocol) {
+                self.motor.iter_mut().for_each(|mut motor| match motor.import_document(&document) {
                     Ok(_) => {}
                     Err(err) => {
-                        errors_import.push((motor.name.to_string(), err));
+                        errors_import.push((motor.name.to_string(), err.into()));
                     }
                 });
                 if !errors_import.is_empty() {
                     for (motor_name, err) in errors_import.into_iter() {
-                        let message: Message = Message::new(ToastKind::Error, &format!("Error while importing the configuration {:?}", &self.path_config.file_name().unwrap_or_default()), Some(err), Some(motor_name), 3, false);
+                        let message: Message = Message::new(MessageKind::Error, &format!("Error while importing the configuration {:?}", &self.path_config.file_name().unwrap_or_default()), Some(err), Some(motor_name), 3, false);
                         self.message_handler(message);
                     }
                 } else {
-                    let message: Message = Message::new(ToastKind::Info, &format!("Configuration {:?} imported for all stopped motors!", &self.path_config.file_name().unwrap_or_default()), None, None, 3, false);
+                    let message: Message = Message::new(MessageKind::Info, &format!("Configuration {:?} imported for all stopped motors!", &self.path_config.file_name().unwrap_or_default()), None, None, 3, false);
                     self.message_handler(message);
                 }
-                self.durations.iter_mut().for_each(|(key, durations)| {
-                    durations.duration_of_one_direction_cycle_rotation.self_from_milliseconds(self.motor.get(key).unwrap().protocol.rotation.duration_of_one_direction_cycle_ms);
-                    durations.pause_before_direction_change_rotation.self_from_milliseconds(self.motor.get(key).unwrap().protocol.rotation.pause_before_direction_change_ms);
-                    durations.duration_of_one_direction_cycle_agitation.self_from_milliseconds(self.motor.get(key).unwrap().protocol.agitation.duration_of_one_direction_cycle_ms);
-                    durations.pause_before_direction_change_agitation.self_from_milliseconds(self.motor.get(key).unwrap().protocol.agitation.pause_before_direction_change_ms);
-                    let rotation_duration = self.motor.get(key).unwrap().protocol.rotation_duration_ms;
-                    let agitation_duration = self.motor.get(key).unwrap().protocol.agitation_duration_ms;
-                    durations.rotation_duration.self_from_milliseconds(rotation_duration);
-                    durations.agitation_duration.self_from_milliseconds(agitation_duration);
-                    let pause_pre_agitation = self.motor.get(key).unwrap().protocol.pause_pre_agitation_ms;
-                    let pause_post_agitation = self.motor.get(key).unwrap().protocol.pause_post_agitation_ms;
-                    durations.pause_pre_agitation.self_from_milliseconds(pause_pre_agitation);
-                    durations.pause_post_agitation.self_from_milliseconds(pause_post_agitation);
-                    durations.global_duration.self_from_milliseconds(self.motor.get(key).unwrap().protocol.global_duration_ms);
-                });
+                self.durations.iter_mut().for_each(|(key, durations)| durations.update_from_protocol(&self.motor.get(key).unwrap().protocol));
                 self.motor.iter().for_each(|motor| {
                     motor.generate_graph_rotation();
                     motor.generate_graph_agitation();
                     motor.calculate_expected_end_date();
                 });
             } else {
-                self.motor.get_mut(tab).unwrap().import_protocol(protocol)?;
+                self.motor.get_mut(tab).unwrap().import_document(&document)?;
                 let current_motor = self.motor.get(tab).unwrap().name.to_string();
-                let message: Message = Message::new(ToastKind::Info, &format!("Configuration {:?} imported!", &self.path_config.file_name().unwrap_or_default()), None, Some(current_motor), 3, false);
+                let message: Message = Message::new(MessageKind::Info, &format!("Configuration {:?} imported!", &self.path_config.file_name().unwrap_or_default()), None, Some(current_motor), 3, false);
                 self.message_handler(message);
-                self.durations.get_mut(tab).unwrap().duration_of_one_direction_cycle_rotation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.rotation.duration_of_one_direction_cycle_ms);
-                self.durations.get_mut(tab).unwrap().pause_before_direction_change_rotation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.rotation.pause_before_direction_change_ms);
-                self.durations.get_mut(tab).unwrap().duration_of_one_direction_cycle_agitation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.agitation.duration_of_one_direction_cycle_ms);
-                self.durations.get_mut(tab).unwrap().pause_before_direction_change_agitation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.agitation.pause_before_direction_change_ms);
-                let rotation_duration = self.motor.get(tab).unwrap().protocol.rotation_duration_ms;
-                let agitation_duration = self.motor.get(tab).unwrap().protocol.agitation_duration_ms;
-                self.durations.get_mut(tab).unwrap().rotation_duration.self_from_milliseconds(rotation_duration);
-                self.durations.get_mut(tab).unwrap().agitation_duration.self_from_milliseconds(agitation_duration);
-                let pause_pre_agitation = self.motor.get(tab).unwrap().protocol.pause_pre_agitation_ms;
-                let pause_post_agitation = self.motor.get(tab).unwrap().protocol.pause_post_agitation_ms;
-                self.durations.get_mut(tab).unwrap().pause_pre_agitation.self_from_milliseconds(pause_pre_agitation);
-                self.durations.get_mut(tab).unwrap().pause_post_agitation.self_from_milliseconds(pause_post_agitation);
-                self.durations.get_mut(tab).unwrap().global_duration.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.global_duration_ms);
+                self.durations.get_mut(tab).unwrap().update_from_protocol(&self.motor.get(tab).unwrap().protocol);
                 self.motor.get(tab).unwrap().generate_graph_rotation();
                 self.motor.get(tab).unwrap().generate_graph_agitation();
                 self.motor.get(tab).unwrap().calculate_expected_end_date();
@@ -398,7 +1047,7 @@ impl CellSpinner {
         };
         if let Err(err) = fn_import() {
             let current_motor = self.motor.get(tab).unwrap().name.to_string();
-            let message: Message = Message::new(ToastKind::Error, &format!("Error while importing the configuration {:?}", &self.path_config.file_name().unwrap_or_default()), Some(err), Some(current_motor), 3, false);
+            let message: Message = Message::new(MessageKind::Error, &format!("Error while importing the configuration {:?}", &self.path_config.file_name().unwrap_or_default()), Some(err), Some(current_motor), 3, false);
             self.message_handler(message);
         }
     }
@@ -444,6 +1093,17 @@ impl eframe::App for CellSpinner {
 
         self.window_error_log(ctx);
         self.window_exit_confirmation(ctx);
+        self.window_save_config(ctx);
+        #[cfg(feature = "http-api")]
+        self.window_api(ctx);
+        #[cfg(feature = "mqtt")]
+        self.window_mqtt(ctx);
+        #[cfg(feature = "modbus")]
+        self.window_modbus(ctx);
+        #[cfg(feature = "sila")]
+        self.window_sila(ctx);
+        #[cfg(feature = "notifications")]
+        self.window_notifications(ctx);
 
         if self.allowed_to_close {
             frame.close();
@@ -461,12 +1121,7 @@ impl eframe::App for CellSpinner {
             .show(ctx, |ui| {
                 egui::menu::bar(ui, |ui| {
                     egui::ScrollArea::horizontal().id_source("Top_scroll_area").show(ui, |ui| {
-                        let tab;
-                        if let Some(active_tab) = self.tree.find_active_focused() {
-                            tab = *active_tab.1;
-                        } else {
-                            tab = self.added_tabs[0];
-                        }
+                        let tab = self.get_focused_tab();
                         let is_running = self.motor.get(&tab).unwrap().get_is_running();
                         // Title
                         let response_heading = ui.add(egui::Label::new(RichText::new("Cell Spinner").heading())
@@ -479,7 +1134,7 @@ impl eframe::App for CellSpinner {
                         // Buttons to save and load config.
                         if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("Save config").fill(THEME.surface0))
                             .clicked() {
-                            self.export_configuration(&tab);
+                            self.windows_state.is_save_config_open = true;
                         }
                         ui.separator();
                         ui.add_enabled_ui(!is_running, |ui| {
@@ -491,6 +1146,71 @@ impl eframe::App for CellSpinner {
                                 self.import_configuration(&tab, true);
                             }
                         });
+                        ui.separator();
+                        // Buttons to save and load every tab at once.
+                        if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new("Save experiment").fill(THEME.surface0))
+                            .on_hover_text("Save the name, serial port, serial settings and protocol of every motor")
+                            .clicked() {
+                            self.save_experiment();
+                        }
+                        ui.separator();
+                        let is_any_connected = self.motor.iter().any(|motor| motor.get_is_connected());
+                        ui.add_enabled_ui(!is_any_connected, |ui| {
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new("Load experiment").fill(THEME.surface0))
+                                .on_hover_text("Replace all the tabs with the ones of the experiment")
+                                .on_disabled_hover_text("Disconnect all the motors to load an experiment")
+                                .clicked() {
+                                self.load_experiment();
+                            }
+                        });
+                        ui.separator();
+                        ui.checkbox(&mut self.is_auto_connect, "Auto-connect")
+                            .on_hover_text("Connect to the serial ports of the last session on launch");
+                        #[cfg(feature = "http-api")]
+                        {
+                            ui.separator();
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("HTTP API").fill(THEME.surface0))
+                                .on_hover_text("Control the motors from other programs of this computer")
+                                .clicked() {
+                                self.windows_state.is_api_open = true;
+                            }
+                        }
+                        #[cfg(feature = "mqtt")]
+                        {
+                            ui.separator();
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("MQTT").fill(THEME.surface0))
+                                .on_hover_text("Publish the states of the motors to an MQTT broker")
+                                .clicked() {
+                                self.windows_state.is_mqtt_open = true;
+                            }
+                        }
+                        #[cfg(feature = "modbus")]
+                        {
+                            ui.separator();
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("Modbus").fill(THEME.surface0))
+                                .on_hover_text("Expose the motors to PLCs over Modbus TCP")
+                                .clicked() {
+                                self.windows_state.is_modbus_open = true;
+                            }
+                        }
+                        #[cfg(feature = "sila")]
+                        {
+                            ui.separator();
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("SiLA").fill(THEME.surface0))
+                                .on_hover_text("Let the schedulers of lab automation systems control the motors over SiLA 2")
+                                .clicked() {
+                                self.windows_state.is_sila_open = true;
+                            }
+                        }
+                        #[cfg(feature = "notifications")]
+                        {
+                            ui.separator();
+                            if ui.add_sized(FONT_BUTTON_SIZE.button_top_panel, egui::Button::new("Notifications").fill(THEME.surface0))
+                                .on_hover_text("Send a webhook or an e-mail when a run finishes, a motor faults or a serial port is lost")
+                                .clicked() {
+                                self.windows_state.is_notifications_open = true;
+                            }
+                        }
                         // Info message
                         ui.add_visible_ui(self.info_message_is_waiting, |ui| {
                             ui.separator();
@@ -508,6 +1228,28 @@ impl eframe::App for CellSpinner {
             let mut added_nodes = vec![];
             let show_close = self.current_tab_counter != 1;
             let show_add = self.current_tab_counter < 8;
+            let mut tabs = Tabs {
+                channels: &mut self.channels,
+                main_context: ctx.clone(),
+                // frame,
+                available_ports: &mut self.available_ports,
+                already_connected_ports: &mut self.already_connected_ports,
+                selected_port: &mut self.selected_port,
+                serial_settings: &mut self.serial_settings,
+                motor_name: &mut self.motor_name,
+                motor: &mut self.motor,
+                durations: &mut self.durations,
+                promise_serial_connect: &mut self.promise_serial_connect,
+                promise_motor_start: &mut self.promise_motor_start,
+                added_nodes: &mut added_nodes,
+                added_tabs: &mut self.added_tabs,
+                current_tab_counter: &mut self.current_tab_counter,
+                absolute_tab_counter: &mut self.absolute_tab_counter,
+                can_tab_close: &mut self.can_tab_close,
+                rotating_tubes: &mut self.rotating_tubes,
+            };
+            // Auto-connect of the restored session.
+            tabs.connect_tabs(std::mem::take(&mut self.tabs_to_connect));
             egui_dock::DockArea::new(&mut self.tree)
                 .style({
                     let mut style = Style::from_egui(ctx.style().as_ref());
@@ -518,24 +1260,7 @@ impl eframe::App for CellSpinner {
                 })
                 .show_close_buttons(show_close)
                 .show_add_buttons(show_add)
-                .show_inside(ui, &mut Tabs {
-                    channels: &mut self.channels,
-                    main_context: ctx.clone(),
-                    // frame,
-                    available_ports: &mut self.available_ports,
-                    already_connected_ports: &mut self.already_connected_ports,
-                    selected_port: &mut self.selected_port,
-                    motor_name: &mut self.motor_name,
-                    motor: &mut self.motor,
-                    durations: &mut self.durations,
-                    promise_serial_connect: &mut self.promise_serial_connect,
-                    added_nodes: &mut added_nodes,
-                    added_tabs: &mut self.added_tabs,
-                    current_tab_counter: &mut self.current_tab_counter,
-                    absolute_tab_counter: &mut self.absolute_tab_counter,
-                    can_tab_close: &mut self.can_tab_close,
-                    rotating_tubes: &mut self.rotating_tubes,
-                });
+                .show_inside(ui, &mut tabs);
             added_nodes.drain(..).for_each(|node| {
                 self.tree.set_focused_node(node);
                 self.tree.push_to_focused_leaf(*self.added_tabs.last().unwrap());
@@ -543,6 +1268,33 @@ impl eframe::App for CellSpinner {
         });
     }
 
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, "serial_settings", &self.serial_settings);
+        eframe::set_value(storage, "auto_connect", &self.is_auto_connect);
+        // Saved as JSON so that the embedded protocols are migrated like protocol files.
+        match self.current_experiment().and_then(|session| Ok(session.to_json()?)) {
+            Ok(session) => eframe::set_value(storage, "session", &session),
+            Err(err) => tracing::warn!("Unable to save the session: {}", err),
+        }
+        eframe::set_value(storage, "dock_tree", &self.tree);
+        #[cfg(feature = "http-api")]
+        eframe::set_value(storage, "api_settings", &self.api_settings.without_secrets());
+        #[cfg(feature = "mqtt")]
+        eframe::set_value(storage, "mqtt_settings", &self.mqtt_settings.without_secrets());
+        #[cfg(feature = "modbus")]
+        eframe::set_value(storage, "modbus_settings", &self.modbus_settings);
+        #[cfg(feature = "sila")]
+        {
+            // The clients may have renamed the server.
+            if let Some(server) = &self.sila_server {
+                self.sila_settings.server_name = server.server_name();
+            }
+            eframe::set_value(storage, "sila_settings", &self.sila_settings);
+        }
+        #[cfg(feature = "notifications")]
+        eframe::set_value(storage, "notification_settings", &self.notification_settings.without_secrets());
+    }
+
     fn on_close_event(&mut self) -> bool {
         let any_connected = self.motor.iter().any(|motor| motor.get_is_connected());
         if any_connected {
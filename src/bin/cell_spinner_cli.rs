@@ -0,0 +1,264 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+use std::fs;
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+#[cfg(feature = "sila")]
+use std::thread;
+
+use anyhow::{anyhow, bail, Error};
+use parking_lot::Mutex;
+
+use cell_spinner::utils::enums::{MessageKind, StepperState};
+use cell_spinner::utils::motor::Motor;
+#[cfg(feature = "notifications")]
+use cell_spinner::utils::notifications::{self, Notification, NotificationSettings};
+use cell_spinner::utils::protocol_file::ProtocolDocument;
+use cell_spinner::utils::serial::Serial;
+#[cfg(feature = "sila")]
+use cell_spinner::utils::sila::{SilaServer, SilaSettings};
+use cell_spinner::utils::structs::{Message, SerialSettings};
+
+const USAGE: &str = "Usage:
+  cell_spinner_cli list-ports
+  cell_spinner_cli connect --port <port> [options]
+  cell_spinner_cli status --port <port> [options]
+  cell_spinner_cli run --port <port> --protocol <file.json> [--name <name>] [--detach] [--notifications <file.json>] [options]
+  cell_spinner_cli stop --port <port> [options]
+  cell_spinner_cli serve-sila --port <port> [--sila-port <port>] [options]
+  cell_spinner_cli notify-test --notifications <file.json>
+Options:
+  --settings <file.json>  Serial settings, as saved in an experiment file
+  --baud <rate>           Baud rate, overrides the settings
+  --notifications <file>  Webhook and e-mail settings, notified at the end of the run
+  --json                  Print JSON lines instead of text
+Exit codes: 0 on success, 1 on errors, 2 when the motor reports a fault.";
+
+/// Exit code of a run stopped by the firmware because of a fault.
+const EXIT_FAULT: i32 = 2;
+
+#[derive(Default)]
+struct Options {
+    port: Option<String>,
+    protocol: Option<String>,
+    name: Option<String>,
+    settings: SerialSettings,
+    is_json: bool,
+    is_detached: bool,
+    #[cfg(feature = "sila")]
+    sila_port: Option<u16>,
+    #[cfg(feature = "notifications")]
+    notifications: Option<NotificationSettings>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item=String>) -> Result<Self, Error> {
+        let mut options = Self::default();
+        let mut baud_rate = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {}.\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--port" => options.port = Some(value()?),
+                "--protocol" => options.protocol = Some(value()?),
+                "--name" => options.name = Some(value()?),
+                "--settings" => options.settings = serde_json::from_str(&fs::read_to_string(value()?)?)?,
+                "--baud" => baud_rate = Some(value()?.parse()?),
+                "--json" => options.is_json = true,
+                "--detach" => options.is_detached = true,
+                #[cfg(feature = "sila")]
+                "--sila-port" => options.sila_port = Some(value()?.parse()?),
+                #[cfg(feature = "notifications")]
+                "--notifications" => options.notifications = Some(serde_json::from_str(&fs::read_to_string(value()?)?)?),
+                _ => bail!("Unknown argument {}.\n{}", arg, USAGE),
+            }
+        }
+        if let Some(baud_rate) = baud_rate {
+            options.settings.baud_rate = baud_rate;
+        }
+        Ok(options)
+    }
+
+    fn port(&self) -> Result<&str, Error> {
+        self.port.as_deref().ok_or_else(|| anyhow!("--port is required.\n{}", USAGE))
+    }
+
+    fn motor_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.port.clone().unwrap_or_default())
+    }
+
+    fn connect(&self) -> Result<Motor, Error> {
+        Ok(Motor::new(self.port()?.into(), self.motor_name(), self.settings, Arc::new(Mutex::new(vec![])))?)
+    }
+
+    fn print(&self, text: String, json: serde_json::Value) {
+        if self.is_json {
+            println!("{}", json);
+        } else {
+            println!("{}", text);
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let mut args = std::env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    let options = Options::parse(args)?;
+    match command.as_str() {
+        "list-ports" => list_ports(&options),
+        "connect" => connect(&options),
+        "status" => status(&options),
+        "run" => run(&options),
+        "stop" => stop(&options),
+        #[cfg(feature = "sila")]
+        "serve-sila" => serve_sila(&options),
+        #[cfg(feature = "notifications")]
+        "notify-test" => notify_test(&options),
+        _ => bail!("Unknown command {:?}.\n{}", command, USAGE),
+    }
+}
+
+fn list_ports(options: &Options) -> Result<(), Error> {
+    for port in serialport::available_ports()? {
+        options.print(port.port_name.clone(), serde_json::json!({ "port": port.port_name }));
+    }
+    Ok(())
+}
+
+fn connect(options: &Options) -> Result<(), Error> {
+    let motor = options.connect()?;
+    let state = if motor.get_is_running() { "running" } else { "idle" };
+    options.print(format!("Connected to {}, the motor is {}.", motor.serial.port_name, state),
+                  serde_json::json!({ "port": motor.serial.port_name, "is_running": motor.get_is_running() }));
+    Ok(())
+}
+
+fn status(options: &Options) -> Result<(), Error> {
+    let serial = Serial::new(options.port()?, options.settings, Arc::new(Mutex::new(vec![])))?;
+    let status = serial.query_status(options.settings.timeout_ms)?.unwrap_or_default();
+    let text = if status.is_running {
+        format!("Running for {} ms, {} - {}\n{}", status.elapsed_global_ms, status.main_phase, status.sub_phase, status.protocol)
+    } else {
+        "Idle".to_string()
+    };
+    options.print(text, serde_json::json!({
+        "port": serial.port_name,
+        "is_running": status.is_running,
+        "main_phase": format!("{:?}", status.main_phase),
+        "sub_phase": format!("{:?}", status.sub_phase),
+        "elapsed_global_ms": status.elapsed_global_ms,
+        "protocol": status.protocol,
+    }));
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), Error> {
+    let path = options.protocol.as_deref().ok_or_else(|| anyhow!("--protocol is required.\n{}", USAGE))?;
+    let document = ProtocolDocument::from_json(&fs::read_to_string(path)?)?;
+    let mut motor = options.connect()?;
+    if motor.get_is_running() {
+        bail!("The motor on {} is already running. Stop it first.", motor.serial.port_name);
+    }
+    motor.import_document(&document)?;
+    let events_rx = motor.serial.events.subscribe();
+    let (message_tx, message_rx) = channel();
+    motor.start_motor(Some(message_tx));
+    print_messages(&message_rx);
+    if !motor.get_is_running() {
+        bail!("The protocol was not started.");
+    }
+    options.print(format!("Started on {}, expected to end in {} ms.", motor.serial.port_name, motor.get_global_duration_ms()),
+                  serde_json::json!({ "port": motor.serial.port_name, "started": true, "duration_ms": motor.get_global_duration_ms() }));
+    if options.is_detached {
+        return Ok(());
+    }
+    let exit_code = loop {
+        let event = match events_rx.recv_timeout(Duration::from_millis(500)) {
+            Ok(event) => event,
+            // The listener stops on its own when the connection is lost.
+            Err(RecvTimeoutError::Timeout) if motor.get_is_running() => continue,
+            Err(_) => break 1,
+        };
+        options.print(event.to_string(), event.to_json());
+        #[cfg(feature = "notifications")]
+        if let Some(settings) = &options.notifications {
+            if let Some(notification) = Notification::from_event(&event, &motor) {
+                let (message_tx, message_rx) = channel();
+                notifications::notify(settings, notification, Some(message_tx)).join().ok();
+                print_messages(&message_rx);
+            }
+        }
+        if event.state == StepperState::Finished {
+            break 0;
+        }
+        if event.state.is_fault() {
+            break EXIT_FAULT;
+        }
+    };
+    print_messages(&message_rx);
+    motor.disconnect(None);
+    exit(exit_code);
+}
+
+fn stop(options: &Options) -> Result<(), Error> {
+    let motor = options.connect()?;
+    let (message_tx, message_rx) = channel();
+    motor.stop_motor(Some(message_tx)).join().ok();
+    print_messages(&message_rx);
+    Ok(())
+}
+
+/// Serve the motor as motor 1 of a SiLA 2 server on 127.0.0.1 until killed, e.g. to try a scheduler with the simulator.
+#[cfg(feature = "sila")]
+fn serve_sila(options: &Options) -> Result<(), Error> {
+    let mut settings = SilaSettings { is_enabled: true, ..Default::default() };
+    if let Some(port) = options.sila_port {
+        settings.port = port;
+    }
+    let motors = Arc::new(dashmap::DashMap::new());
+    motors.insert(1, options.connect()?);
+    let (message_tx, message_rx) = channel();
+    let server = SilaServer::start(&settings, motors, Some(message_tx))?;
+    options.print(format!("SiLA server listening on {}, motor 1 being the one of {}.", server.address, options.port()?),
+                  serde_json::json!({ "address": server.address, "port": options.port()?, "server_uuid": settings.server_uuid }));
+    loop {
+        print_messages(&message_rx);
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Send a test notification through the enabled channels of the settings, e.g. to stand-in servers.
+#[cfg(feature = "notifications")]
+fn notify_test(options: &Options) -> Result<(), Error> {
+    let settings = options.notifications.as_ref().ok_or_else(|| anyhow!("--notifications is required.\n{}", USAGE))?;
+    let (message_tx, message_rx) = channel();
+    notifications::notify(settings, Notification::test(), Some(message_tx)).join().ok();
+    let messages: Vec<Message> = message_rx.try_iter().collect();
+    let is_failed = messages.iter().any(|message| message.kind != MessageKind::Success);
+    messages.into_iter().for_each(print_message);
+    if is_failed {
+        bail!("The test notification was not sent through every enabled channel.");
+    }
+    Ok(())
+}
+
+/// Messages meant for the toasts of the app, printed to stderr.
+fn print_messages(message_rx: &Receiver<Message>) {
+    message_rx.try_iter().for_each(print_message);
+}
+
+fn print_message(message: Message) {
+    let kind = match message.kind {
+        MessageKind::Error => "error",
+        MessageKind::Warning => "warning",
+        MessageKind::Success => "success",
+        MessageKind::Info => "info",
+    };
+    match message.error {
+        Some(error) => eprintln!("{}: {}: {}", kind, message.message, error),
+        None => eprintln!("{}: {}", kind, message.message),
+    }
+}
@@ -0,0 +1,36 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+#[cfg(unix)]
+fn main() -> Result<(), anyhow::Error> {
+    use cell_spinner::utils::simulator::{FirmwareSimulator, parse_fault};
+
+    tracing_subscriber::fmt().init();
+
+    // Usage: cell_spinner_sim [--fault code@milliseconds] [--link path]
+    let mut fault = None;
+    let mut link = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fault" => fault = Some(parse_fault(&args.next().unwrap_or_default())?),
+            "--link" => link = args.next(),
+            _ => anyhow::bail!("Unknown argument {}. Usage: cell_spinner_sim [--fault code@milliseconds] [--link path]", arg),
+        }
+    }
+
+    let mut simulator = FirmwareSimulator::new(fault)?;
+    if let Some(link) = link {
+        std::fs::remove_file(&link).ok();
+        std::os::unix::fs::symlink(simulator.slave_path(), &link)?;
+        println!("Virtual Raspberry listening on {} ({})", link, simulator.slave_path().display());
+    } else {
+        println!("Virtual Raspberry listening on {}", simulator.slave_path().display());
+    }
+    simulator.run()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The Raspberry simulator needs a Linux pseudo-terminal.");
+    std::process::exit(1);
+}
@@ -1,7 +1,10 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
+#[cfg(feature = "gui")]
 mod app;
-mod utils;
+pub mod utils;
+#[cfg(feature = "gui")]
 mod tabs;
 
+#[cfg(feature = "gui")]
 pub use app::CellSpinner;
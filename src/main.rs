@@ -94,6 +94,8 @@ fn main() -> eframe::Result<()> {
         // maximized: true,
         icon_data: Some(load_icon(ICON)),
         initial_window_size: Some(egui::Vec2 { x: 1390.0, y: 775.0 }),
+        // Restore the size and position of the window of the last session.
+        persist_window: true,
         ..Default::default()
     };
 
@@ -5,17 +5,23 @@ use std::thread;
 
 use chrono::Local;
 use dashmap::DashMap;
-use egui::{Color32, Pos2, Rect, RichText, Ui, WidgetText};
+use egui::{Color32, Pos2, Rect, Response, RichText, Ui, WidgetText};
 use egui::plot::{Corner, Legend, Line};
 use egui_dock::{NodeIndex, TabViewer};
-use egui_toast::ToastKind;
 use parking_lot::Mutex;
+use serialport::{DataBits, FlowControl, Parity, StopBits};
 
-use crate::app::{FONT_BUTTON_SIZE, MAX_ACCELERATION, MAX_POINTS_GRAPHS, THEME};
-use crate::utils::enums::{Direction, StepperState};
-use crate::utils::motor::Motor;
-use crate::utils::structs::{Channels, DurationHelper, Durations, Message};
+use crate::app::{FONT_BUTTON_SIZE, THEME};
+use crate::utils::constants::{BAUD_RATES, MAX_ACCELERATION, MAX_DURATION_MS, MAX_POINTS_GRAPHS, MAX_RECONNECT_BACKOFF_MS, MAX_RPM};
+use crate::utils::enums::{Direction, MessageKind, StepMode128, StepperState};
+use crate::utils::kinematics::{Kinematics, MAX_RAMP_SHARE};
+use crate::utils::motor::{self, Motor};
+use crate::utils::step_protocol::{Step, StepBlock, StepProtocol};
+use crate::utils::structs::{Channels, DurationHelper, Durations, Message, SerialSettings};
+use crate::utils::validation::{field_issue, ProtocolField, ValidationIssue};
+use crate::utils::timeline::ProtocolTimeline;
 use crate::utils::widget_rotating_tube::RotatingTube;
+use crate::utils::widget_timeline::PhaseTimeline;
 
 pub struct Tabs<'a> {
     pub channels: &'a mut Channels,
@@ -24,10 +30,12 @@ pub struct Tabs<'a> {
     pub available_ports: &'a mut Vec<String>,
     pub already_connected_ports: &'a mut Arc<Mutex<Vec<String>>>,
     pub selected_port: &'a mut HashMap<usize, String>,
+    pub serial_settings: &'a mut HashMap<String, SerialSettings>,
     pub motor_name: &'a mut HashMap<usize, String>,
     pub motor: &'a mut Arc<DashMap<usize, Motor>>,
     pub durations: &'a mut HashMap<usize, Durations>,
     pub promise_serial_connect: &'a mut Arc<DashMap<usize, Option<()>>>,
+    pub promise_motor_start: &'a mut Arc<DashMap<usize, Option<()>>>,
     pub added_nodes: &'a mut Vec<NodeIndex>,
     pub added_tabs: &'a mut Vec<usize>,
     pub current_tab_counter: &'a mut usize,
@@ -39,6 +47,7 @@ pub struct Tabs<'a> {
 impl Tabs<'_> {
     fn init_tab(&mut self, tab: usize) {
         self.promise_serial_connect.insert(tab, None);
+        self.promise_motor_start.insert(tab, None);
         self.motor.insert(tab, Motor::default());
         self.durations.insert(tab, Durations::default());
         self.motor.get_mut(&tab).unwrap().name = format!("Motor {}", tab);
@@ -46,6 +55,7 @@ impl Tabs<'_> {
         self.added_tabs.push(tab);
         self.refresh_available_serial_ports(tab);
         self.selected_port.insert(tab, self.available_ports[0].clone());
+        self.load_serial_settings(tab);
         self.rotating_tubes.insert(tab, (RotatingTube::new(65.0, THEME.sapphire), RotatingTube::new(65.0, THEME.blue)));
     }
 
@@ -54,6 +64,7 @@ impl Tabs<'_> {
         self.motor.get(&tab).unwrap().disconnect(self.channels.message_tx.clone());
         self.selected_port.remove(&tab);
         self.promise_serial_connect.remove(&tab);
+        self.promise_motor_start.remove(&tab);
         self.motor_name.remove(&tab);
         self.motor.remove(&tab);
         self.durations.remove(&tab);
@@ -70,21 +81,66 @@ impl Tabs<'_> {
         let protocol = self.motor.get(&tab).unwrap().protocol;
         let graph = self.motor.get(&tab).unwrap().graph.clone();
         let steps_per_cycle = self.motor.get(&tab).unwrap().steps_per_cycle.clone();
+        let protocol_metadata = self.motor.get(&tab).unwrap().protocol_metadata.clone();
+        let step_protocol = self.motor.get(&tab).unwrap().step_protocol.clone();
+        let is_step_protocol = self.motor.get(&tab).unwrap().is_step_protocol;
+        let settings = self.motor.get(&tab).unwrap().serial.settings;
+        self.serial_settings.insert(serial_port.clone(), settings);
         thread::spawn(move || {
-            let motor = match Motor::new_with_already_loaded_protocol(serial_port.clone(), motor_name, already_connected_ports, protocol, graph, steps_per_cycle) {
+            let mut motor = match Motor::new_with_already_loaded_protocol(serial_port.clone(), motor_name, settings, already_connected_ports, protocol, graph, steps_per_cycle) {
                 Ok(motor) => motor,
                 Err(err) => {
-                    message_channel.as_ref().unwrap().send(Message::new(ToastKind::Error, &format!("Error while connecting to serial port {}", serial_port), Some(err), Some(format!("Motor {}", tab)), 3, false)).ok();
+                    message_channel.as_ref().unwrap().send(Message::new(MessageKind::Error, &format!("Error while connecting to serial port {}", serial_port), Some(err.into()), Some(format!("Motor {}", tab)), 3, false)).ok();
                     promise.insert(tab, None);
                     return;
                 }
             };
+            let is_reattached = motor.is_reattached;
+            if !is_reattached {
+                motor.protocol_metadata = protocol_metadata;
+                motor.step_protocol = step_protocol;
+                motor.is_step_protocol = is_step_protocol;
+            }
+            if is_reattached {
+                motor.serial.listen_to_serial_port(motor.name.clone(), &motor.is_running, &motor.timers_and_phases, message_channel.clone());
+            }
             motors.insert(tab, motor);
             promise.insert(tab, None);
-            message_channel.as_ref().unwrap().send(Message::new(ToastKind::Success, &format!("Successfully connected to serial port {}", serial_port), None, Some(format!("Motor {}", tab)), 3, false)).ok();
+            message_channel.as_ref().unwrap().send(Message::new(MessageKind::Success, &format!("Successfully connected to serial port {}", serial_port), None, Some(format!("Motor {}", tab)), 3, false)).ok();
+            if is_reattached {
+                message_channel.as_ref().unwrap().send(Message::new(MessageKind::Info, "Reattached to the ongoing run", None, Some(format!("Motor {}", tab)), 5, false)).ok();
+            }
         });
     }
 
+    /// Start the motors of the tabs, each in a thread of its own so that the map of the motors is not held while the firmware acknowledges the protocol.
+    /// The durations are updated from the protocol by the tab once started.
+    fn start_motors(&mut self, tabs: Vec<usize>) {
+        for tab in tabs {
+            self.promise_motor_start.insert(tab, Some(()));
+            let promise = self.promise_motor_start.clone();
+            let motors = self.motor.clone();
+            let message_tx = self.channels.message_tx.clone();
+            thread::spawn(move || {
+                motor::start_motor_in(&motors, tab, message_tx);
+                promise.insert(tab, None);
+            });
+        }
+    }
+
+    /// Connect the tabs to their selected serial port.
+    pub fn connect_tabs(&mut self, tabs: Vec<usize>) {
+        for tab in tabs {
+            let selected_port = self.selected_port.get(&tab).unwrap().to_string();
+            if selected_port.is_empty() {
+                continue;
+            }
+            let motor_name = self.motor_name.get(&tab).unwrap().clone();
+            self.thread_spawn_new_motor(tab, selected_port.clone(), motor_name);
+            self.channels.message_tx.as_ref().unwrap().send(Message::new(MessageKind::Info, &format!("Connecting to serial port {}...", selected_port), None, Some(format!("Motor {}", tab)), 0, true)).ok();
+        }
+    }
+
     fn refresh_available_serial_ports(&mut self, tab: usize) {
         let available_ports = match serialport::available_ports() {
             Ok(ports) => {
@@ -95,19 +151,248 @@ impl Tabs<'_> {
                     let selected_port = self.selected_port.get(&tab).unwrap().clone();
                     if !available_ports.contains(&selected_port) {
                         self.selected_port.insert(tab, available_ports.get(0).unwrap_or(&"".to_string()).clone());
+                        self.load_serial_settings(tab);
                     }
                 }
                 available_ports
             }
             Err(err) => {
                 let error = anyhow::anyhow!(err);
-                self.channels.message_tx.as_ref().unwrap().send(Message::new(ToastKind::Error, "Error while listing serial ports", Some(error), Some(format!("Motor {}", tab)), 3, false)).ok();
+                self.channels.message_tx.as_ref().unwrap().send(Message::new(MessageKind::Error, "Error while listing serial ports", Some(error), Some(format!("Motor {}", tab)), 3, false)).ok();
                 vec!["".to_string()]
             }
         };
         *self.available_ports = available_ports;
     }
 
+    /// Load the settings last used with the selected port.
+    fn load_serial_settings(&mut self, tab: usize) {
+        if self.motor.get(&tab).unwrap().get_is_connected() {
+            return;
+        }
+        if let Some(settings) = self.selected_port.get(&tab).and_then(|port| self.serial_settings.get(port)) {
+            self.motor.get_mut(&tab).unwrap().serial.settings = *settings;
+        }
+    }
+
+    fn serial_settings_ui(&mut self, ui: &mut Ui, tab: usize, is_link_editable: bool, is_reconnect_editable: bool) {
+        let mut motor = self.motor.get_mut(&tab).unwrap();
+        let settings = &mut motor.serial.settings;
+        ui.add_enabled_ui(is_link_editable, |ui| {
+            egui::Grid::new("serial_settings").num_columns(2).show(ui, |ui| {
+                ui.label("Baud rate");
+                egui::ComboBox::from_id_source("baud_rate")
+                    .selected_text(settings.baud_rate.to_string())
+                    .show_ui(ui, |ui| {
+                        for baud_rate in BAUD_RATES {
+                            ui.selectable_value(&mut settings.baud_rate, baud_rate, baud_rate.to_string());
+                        }
+                    });
+                ui.end_row();
+                ui.label("Data bits");
+                egui::ComboBox::from_id_source("data_bits")
+                    .selected_text(format!("{:?}", settings.data_bits))
+                    .show_ui(ui, |ui| {
+                        for data_bits in [DataBits::Five, DataBits::Six, DataBits::Seven, DataBits::Eight] {
+                            ui.selectable_value(&mut settings.data_bits, data_bits, format!("{:?}", data_bits));
+                        }
+                    });
+                ui.end_row();
+                ui.label("Parity");
+                egui::ComboBox::from_id_source("parity")
+                    .selected_text(format!("{:?}", settings.parity))
+                    .show_ui(ui, |ui| {
+                        for parity in [Parity::None, Parity::Odd, Parity::Even] {
+                            ui.selectable_value(&mut settings.parity, parity, format!("{:?}", parity));
+                        }
+                    });
+                ui.end_row();
+                ui.label("Stop bits");
+                egui::ComboBox::from_id_source("stop_bits")
+                    .selected_text(format!("{:?}", settings.stop_bits))
+                    .show_ui(ui, |ui| {
+                        for stop_bits in [StopBits::One, StopBits::Two] {
+                            ui.selectable_value(&mut settings.stop_bits, stop_bits, format!("{:?}", stop_bits));
+                        }
+                    });
+                ui.end_row();
+                ui.label("Flow control");
+                egui::ComboBox::from_id_source("flow_control")
+                    .selected_text(format!("{:?}", settings.flow_control))
+                    .show_ui(ui, |ui| {
+                        for flow_control in [FlowControl::None, FlowControl::Software, FlowControl::Hardware] {
+                            ui.selectable_value(&mut settings.flow_control, flow_control, format!("{:?}", flow_control));
+                        }
+                    });
+                ui.end_row();
+                ui.label("Timeout");
+                ui.add(egui::DragValue::new(&mut settings.timeout_ms).clamp_range(100..=60_000).speed(10).suffix(" ms"))
+                    .on_hover_text("Read and write timeout. Increase it for long USB cables.");
+                ui.end_row();
+                ui.label("Handshake retries");
+                ui.add(egui::DragValue::new(&mut settings.handshake_retries).clamp_range(1..=100));
+                ui.end_row();
+                ui.label("Retry delay");
+                ui.add(egui::DragValue::new(&mut settings.retry_delay_ms).clamp_range(0..=10_000).speed(10).suffix(" ms"));
+                ui.end_row();
+                ui.label("Acknowledgement timeout");
+                ui.add(egui::DragValue::new(&mut settings.ack_timeout_ms).clamp_range(10..=10_000).speed(10).suffix(" ms"))
+                    .on_hover_text("Deadline for the Raspberry to acknowledge a command");
+                ui.end_row();
+                ui.label("Command retries");
+                ui.add(egui::DragValue::new(&mut settings.command_retries).clamp_range(0..=10))
+                    .on_hover_text("Times a framed command is sent again when it is not acknowledged");
+                ui.end_row();
+                ui.label("Frame format");
+                egui::ComboBox::from_id_source("frame_format")
+                    .selected_text(settings.frame_format.to_string())
+                    .show_ui(ui, |ui| {
+                        for format in settings.frame_format.get_formats() {
+                            ui.selectable_value(&mut settings.frame_format, format, format.to_string());
+                        }
+                    })
+                    .response
+                    .on_hover_text("Frame format of the protocol upload. Legacy for older firmware.");
+                ui.end_row();
+            });
+        });
+        ui.separator();
+        // Automatic reconnection after a read error.
+        ui.add_enabled_ui(is_reconnect_editable, |ui| {
+            egui::Grid::new("reconnect_policy").num_columns(2).show(ui, |ui| {
+                ui.label("Auto-reconnect");
+                ui.checkbox(&mut settings.reconnect_policy.enabled, "")
+                    .on_hover_text("Reopen the serial port and resume listening after a read error");
+                ui.end_row();
+                ui.add_enabled_ui(settings.reconnect_policy.enabled, |ui| ui.label("Retries"));
+                ui.add_enabled(settings.reconnect_policy.enabled, egui::DragValue::new(&mut settings.reconnect_policy.max_retries).clamp_range(1..=1000));
+                ui.end_row();
+                ui.add_enabled_ui(settings.reconnect_policy.enabled, |ui| ui.label("Backoff"));
+                ui.add_enabled(settings.reconnect_policy.enabled, egui::DragValue::new(&mut settings.reconnect_policy.backoff_ms).clamp_range(100..=MAX_RECONNECT_BACKOFF_MS).speed(10).suffix(" ms"))
+                    .on_hover_text("Delay before the first attempt, doubled after each failed attempt");
+                ui.end_row();
+            });
+        });
+        // The reconnection policy can change while connected, remember it for the port.
+        if motor.get_is_connected() {
+            self.serial_settings.insert(motor.serial.port_name.clone(), motor.serial.settings);
+        }
+    }
+
+    /// Editor of the step protocol of the motor.
+    fn step_protocol_ui(&mut self, ui: &mut Ui, tab: usize) {
+        let mut motor = self.motor.get_mut(&tab).unwrap();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut motor.is_step_protocol, "Run the step protocol")
+                .on_hover_text("Run the steps below instead of the rotation and agitation. Needs the framed format.");
+            if ui.button("Convert current protocol").on_hover_text("Replace the steps with the rotation and agitation protocol").clicked() {
+                motor.step_protocol = StepProtocol::from(motor.protocol);
+            }
+            if ui.button("Add block").clicked() {
+                motor.step_protocol.blocks.push(StepBlock::default());
+            }
+        });
+        let step_protocol = &mut motor.step_protocol;
+        egui::Grid::new("step_protocol_grid").num_columns(2).show(ui, |ui| {
+            ui.label("Loops");
+            ui.add(egui::DragValue::new(&mut step_protocol.loop_count).clamp_range(0..=u32::MAX))
+                .on_hover_text("Times the blocks are run. 0 repeats them until the global duration.");
+            ui.end_row();
+            ui.label("Global duration");
+            ui.add(egui::DragValue::new(&mut step_protocol.global_duration_ms).clamp_range(0..=MAX_DURATION_MS).speed(100).suffix(" ms"))
+                .on_hover_text("Ends the run even if the loops are not over. 0 for no limit when the loops are set.");
+            ui.end_row();
+            ui.label("Total duration");
+            ui.label(DurationHelper::new_from_milliseconds(step_protocol.duration_ms()).to_string());
+            ui.end_row();
+        });
+        // Changes are applied after the loop so that the lists are not modified while they are displayed.
+        let mut removed_block = None;
+        let mut moved_block = None;
+        for (block_index, block) in step_protocol.blocks.iter_mut().enumerate() {
+            ui.push_id(block_index, |ui| {
+                ui.group(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(format!("Block {}", block_index + 1)).color(THEME.sapphire));
+                        ui.add(egui::DragValue::new(&mut block.loop_count).clamp_range(1..=u32::MAX).prefix("x"))
+                            .on_hover_text("Times the block is run in a row");
+                        if ui.small_button("⬆").clicked() && block_index > 0 {
+                            moved_block = Some((block_index, block_index - 1));
+                        }
+                        if ui.small_button("⬇").clicked() {
+                            moved_block = Some((block_index, block_index + 1));
+                        }
+                        if ui.small_button("🗑").clicked() {
+                            removed_block = Some(block_index);
+                        }
+                        if ui.button("Add step").clicked() {
+                            block.steps.push(Step::Pause { duration_ms: 1000 });
+                        }
+                    });
+                    let mut removed_step = None;
+                    let mut moved_step = None;
+                    for (step_index, step) in block.steps.iter_mut().enumerate() {
+                        ui.push_id(step_index, |ui| {
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_id_source("step_kind")
+                                    .selected_text(step.kind_name())
+                                    .show_ui(ui, |ui| {
+                                        for mut kind in Step::get_kinds() {
+                                            if ui.selectable_label(kind.kind_name() == step.kind_name(), kind.kind_name()).clicked() && kind.kind_name() != step.kind_name() {
+                                                *kind.duration_ms_mut() = step.duration_ms();
+                                                *step = kind;
+                                            }
+                                        }
+                                    });
+                                ui.add(egui::DragValue::new(step.duration_ms_mut()).clamp_range(0..=MAX_DURATION_MS).speed(100).suffix(" ms"))
+                                    .on_hover_text("Duration of the step");
+                                match step {
+                                    Step::Rotation { rotation, .. } | Step::Agitation { agitation: rotation, .. } => {
+                                        let max_rpm = rotation.max_rpm_for_stepmode();
+                                        ui.add(egui::DragValue::new(&mut rotation.rpm).clamp_range(1..=max_rpm).suffix(" rpm"));
+                                        ui.add(egui::DragValue::new(&mut rotation.acceleration).clamp_range(1..=MAX_ACCELERATION).prefix("accel: "));
+                                        step_mode_direction_ui(ui, &mut rotation.step_mode, &mut rotation.direction);
+                                        ui.add(egui::DragValue::new(&mut rotation.duration_of_one_direction_cycle_ms).clamp_range(0..=MAX_DURATION_MS).speed(10).prefix("cycle: ").suffix(" ms"))
+                                            .on_hover_text("Duration of one direction cycle");
+                                        ui.add(egui::DragValue::new(&mut rotation.pause_before_direction_change_ms).clamp_range(0..=MAX_DURATION_MS).speed(10).prefix("pause: ").suffix(" ms"))
+                                            .on_hover_text("Pause before direction change");
+                                    }
+                                    Step::Pause { .. } => {}
+                                    Step::Ramp { start_rpm, end_rpm, step_mode, direction, .. } => {
+                                        ui.add(egui::DragValue::new(start_rpm).clamp_range(1..=MAX_RPM).prefix("from: ").suffix(" rpm"));
+                                        ui.add(egui::DragValue::new(end_rpm).clamp_range(1..=MAX_RPM).prefix("to: ").suffix(" rpm"));
+                                        step_mode_direction_ui(ui, step_mode, direction);
+                                    }
+                                }
+                                if ui.small_button("⬆").clicked() && step_index > 0 {
+                                    moved_step = Some((step_index, step_index - 1));
+                                }
+                                if ui.small_button("⬇").clicked() {
+                                    moved_step = Some((step_index, step_index + 1));
+                                }
+                                if ui.small_button("🗑").clicked() {
+                                    removed_step = Some(step_index);
+                                }
+                            });
+                        });
+                    }
+                    if let Some((from, to)) = moved_step.filter(|(_, to)| *to < block.steps.len()) {
+                        block.steps.swap(from, to);
+                    }
+                    if let Some(step_index) = removed_step {
+                        block.steps.remove(step_index);
+                    }
+                });
+            });
+        }
+        if let Some((from, to)) = moved_block.filter(|(_, to)| *to < step_protocol.blocks.len()) {
+            step_protocol.blocks.swap(from, to);
+        }
+        if let Some(block_index) = removed_block {
+            step_protocol.blocks.remove(block_index);
+        }
+    }
+
     pub fn disconnect(&mut self, tab: usize) {
         self.already_connected_ports.lock().retain(|x| *x != self.motor.get(&tab).unwrap().serial.port_name);
         self.motor.get(&tab).unwrap().disconnect(self.channels.message_tx.clone());
@@ -125,6 +410,15 @@ impl TabViewer for Tabs<'_> {
             return;
         }
         self.motor.get_mut(tab).unwrap().frame_hisory.on_new_frame(self.main_context.input(|i| i.time), None);
+        // The protocol of a reattached motor comes from the firmware.
+        if self.motor.get(tab).unwrap().is_reattached {
+            self.motor.get_mut(tab).unwrap().is_reattached = false;
+            self.durations.get_mut(tab).unwrap().update_from_protocol(&self.motor.get(tab).unwrap().protocol);
+        }
+        if self.motor.get(tab).unwrap().is_protocol_updated {
+            self.motor.get_mut(tab).unwrap().is_protocol_updated = false;
+            self.durations.get_mut(tab).unwrap().update_from_protocol(&self.motor.get(tab).unwrap().protocol);
+        }
         let frame_time_sec = 1.0 / self.motor.get(tab).unwrap().frame_hisory.fps();
         let is_connected = self.motor.get(tab).unwrap().get_is_connected();
         // let is_connected = true;
@@ -141,13 +435,19 @@ impl TabViewer for Tabs<'_> {
                         });
                         ui.add_enabled_ui(!is_connected && self.promise_serial_connect.get(tab).unwrap().is_none(), |ui| {
                             let selected_port = self.selected_port.get(tab).unwrap();
+                            let mut is_port_changed = false;
                             egui::ComboBox::from_id_source("available_ports")
                                 .selected_text(selected_port)
                                 .show_ui(ui, |ui| {
                                     for port in self.available_ports.iter() {
-                                        ui.selectable_value(self.selected_port.get_mut(tab).unwrap(), port.to_string(), port.to_string());
+                                        if ui.selectable_value(self.selected_port.get_mut(tab).unwrap(), port.to_string(), port.to_string()).changed() {
+                                            is_port_changed = true;
+                                        }
                                     }
                                 });
+                            if is_port_changed {
+                                self.load_serial_settings(*tab);
+                            }
                         });
                         ui.add_enabled_ui(is_connected && !is_running, |ui| {
                             if ui.add_sized(egui::vec2(100.0, 20.0), egui::TextEdit::singleline(self.motor_name.get_mut(tab).unwrap()))
@@ -168,41 +468,33 @@ impl TabViewer for Tabs<'_> {
                         ui.add_enabled_ui(!is_connected && self.promise_serial_connect.get(tab).unwrap().is_none() &&
                                               !self.available_ports.is_empty(), |ui| {
                             if ui.add_sized(FONT_BUTTON_SIZE.button_default, egui::Button::new(RichText::new("Connect").color(Color32::WHITE)).fill(THEME.green)).clicked() {
-                                let selected_port = self.selected_port.get(tab).unwrap().to_string();
-                                let motor_name = self.motor_name.get(tab).unwrap().clone();
-                                self.thread_spawn_new_motor(*tab, selected_port.clone(), motor_name);
-                                self.channels.message_tx.as_ref().unwrap().send(Message::new(ToastKind::Info, &format!("Connecting to serial port {}...", selected_port), None, Some(format!("Motor {}", tab)), 0, true)).ok();
+                                self.connect_tabs(vec![*tab]);
                             };
                         });
+                        // Serial settings, applied at the next connection. The reconnection policy is applied at the next run.
+                        ui.menu_button("⚙ Serial", |ui| {
+                            let is_pending = self.promise_serial_connect.get(tab).unwrap().is_some();
+                            self.serial_settings_ui(ui, *tab, !is_connected && !is_pending, !is_running);
+                        }).response.on_hover_text("Serial settings of the motor");
                     });
                 ////////////////////////////
                 ui.separator();
                 ui.horizontal_centered(|ui| {
                     // Button to send the parameters to the motor and run it. Focus is check to prevent the button from being pressed when the user is typing in the text field.
-                    ui.add_enabled_ui(is_connected && !is_running && self.main_context.memory(|mem| mem.focus().is_none()), |ui| { // && self.motor.get(tab).unwrap().protocol.global_duration_ms != 0
+                    let is_starting = self.promise_motor_start.get(tab).unwrap().is_some();
+                    ui.add_enabled_ui(is_connected && !is_running && !is_starting && self.main_context.memory(|mem| mem.focus().is_none()), |ui| { // && self.motor.get(tab).unwrap().protocol.global_duration_ms != 0
                         let run_response = ui.add_sized(egui::vec2(FONT_BUTTON_SIZE.button_default.x, FONT_BUTTON_SIZE.button_default.y * 2.0), egui::Button::new(RichText::new("Run")
                             .color(Color32::WHITE)).fill(THEME.green))
                             .on_hover_text("Right click to start all motors");
                         if run_response.clicked() {
-                            self.motor.get_mut(tab).unwrap().start_motor(self.channels.message_tx.clone());
-                            self.durations.get_mut(tab).unwrap().rotation_duration.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.rotation_duration_ms);
-                            self.durations.get_mut(tab).unwrap().agitation_duration.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.agitation_duration_ms);
-                            self.durations.get_mut(tab).unwrap().pause_pre_agitation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.pause_pre_agitation_ms);
-                            self.durations.get_mut(tab).unwrap().pause_post_agitation.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.pause_post_agitation_ms);
-                            self.durations.get_mut(tab).unwrap().global_duration.self_from_milliseconds(self.motor.get(tab).unwrap().protocol.global_duration_ms);
+                            self.start_motors(vec![*tab]);
                         } else if run_response.secondary_clicked() {
                             // Start all the connected motors that are not running
-                            self.motor.iter_mut().for_each(|mut motor| {
-                                if motor.get_is_connected() && !motor.get_is_running() {
-                                    motor.start_motor(self.channels.message_tx.clone());
-                                    let tab = *motor.key();
-                                    self.durations.get_mut(&tab).unwrap().rotation_duration.self_from_milliseconds(motor.protocol.rotation_duration_ms);
-                                    self.durations.get_mut(&tab).unwrap().agitation_duration.self_from_milliseconds(motor.protocol.agitation_duration_ms);
-                                    self.durations.get_mut(&tab).unwrap().pause_pre_agitation.self_from_milliseconds(motor.protocol.pause_pre_agitation_ms);
-                                    self.durations.get_mut(&tab).unwrap().pause_post_agitation.self_from_milliseconds(motor.protocol.pause_post_agitation_ms);
-                                    self.durations.get_mut(&tab).unwrap().global_duration.self_from_milliseconds(motor.protocol.global_duration_ms);
-                                }
-                            });
+                            let tabs = self.motor.iter()
+                                .filter(|motor| motor.get_is_connected() && !motor.get_is_running() && self.promise_motor_start.get(motor.key()).map_or(false, |promise| promise.is_none()))
+                                .map(|motor| *motor.key())
+                                .collect();
+                            self.start_motors(tabs);
                         }
                     });
                     ui.add_enabled_ui(is_connected && is_running, |ui| {
@@ -226,11 +518,10 @@ impl TabViewer for Tabs<'_> {
                     .fill(THEME.peach))
                     .on_hover_text("Stop all the motors and disconnect them.")
                     .clicked() {
-                    let message = Message::new(ToastKind::Warning, "Emergency stop", None, Some(self.motor.get(tab).unwrap().name.clone()), 5, false);
+                    let message = Message::new(MessageKind::Warning, "Emergency stop", None, Some(self.motor.get(tab).unwrap().name.clone()), 5, false);
                     self.channels.message_tx.as_ref().unwrap().send(message).ok();
                     self.motor.iter().for_each(|motor| {
-                        motor.stop_motor(self.channels.message_tx.clone());
-                        motor.disconnect(self.channels.message_tx.clone());
+                        motor.emergency_stop(self.channels.message_tx.clone());
                     });
                 }
                 ui.separator();
@@ -265,6 +556,9 @@ impl TabViewer for Tabs<'_> {
                         } else {
                             ui.label(RichText::new(format!("Expected end date ➡️ {}", expected_end_date)).size(FONT_BUTTON_SIZE.font_default + 2.0));
                         }
+                    } else if is_running {
+                        ui.label(RichText::new("Expected end date ➡️ Unknown").size(FONT_BUTTON_SIZE.font_default + 2.0))
+                            .on_hover_text("The duration of the step protocol running on the motor is unknown.");
                     } else {
                         ui.label(RichText::new("Expected end date ➡️ None").size(FONT_BUTTON_SIZE.font_default + 2.0));
                     }
@@ -273,6 +567,7 @@ impl TabViewer for Tabs<'_> {
         });
         ui.separator();
         ////// SETUP //////
+        let issues = self.motor.get(tab).unwrap().protocol.validate_run();
         egui::ScrollArea::horizontal().id_source("setup").show(ui, |ui| {
             ui.horizontal(|ui| {
                 // Setup rotation phase
@@ -303,18 +598,27 @@ impl TabViewer for Tabs<'_> {
                             egui::Grid::new("rotation_grid")
                                 .show(ui, |ui| {
                                     // Slider for RPM
-                                    ui.label("RPM:");
+                                    field_label(ui, "RPM:", &issues, ProtocolField::RotationRpm);
                                     let max_rpm = self.motor.get(tab).unwrap().protocol.rotation.max_rpm_for_stepmode();
                                     if ui.add(egui::Slider::new(&mut self.motor.get_mut(tab).unwrap().protocol.rotation.rpm, 1..=max_rpm)).changed() {
                                         rotation_graph_needs_update = true;
                                     }
                                     ui.end_row();
                                     // Slider for acceleration
-                                    ui.label("Acceleration:");
+                                    field_label(ui, "Acceleration:", &issues, ProtocolField::RotationAcceleration);
                                     if ui.add(egui::Slider::new(&mut self.motor.get_mut(tab).unwrap().protocol.rotation.acceleration, 1..=MAX_ACCELERATION)).changed() {
                                         rotation_graph_needs_update = true;
                                     }
                                     ui.end_row();
+                                    // Speed actually reached during a cycle
+                                    ui.label("Profile:");
+                                    let kinematics = *self.motor.get(tab).unwrap().graph.rotation_kinematics.lock();
+                                    if kinematics_ui(ui, kinematics) {
+                                        let rotation = self.motor.get(tab).unwrap().protocol.rotation;
+                                        self.motor.get_mut(tab).unwrap().protocol.rotation = rotation.with_feasible_kinematics();
+                                        rotation_graph_needs_update = true;
+                                    }
+                                    ui.end_row();
                                     // List for stepmode
                                     let modes = self.motor.get(tab).unwrap().protocol.rotation.step_mode.get_modes();
                                     let selected_mode = self.motor.get(tab).unwrap().protocol.rotation.step_mode;
@@ -336,7 +640,7 @@ impl TabViewer for Tabs<'_> {
 
                                     ui.end_row();
                                     // Duration for 1 direction cycle
-                                    ui.label("Cycle duration:").on_hover_text("Duration of a cycle of rotations in one direction.");
+                                    field_label(ui, "Cycle duration:", &issues, ProtocolField::RotationCycleDuration).on_hover_text("Duration of a cycle of rotations in one direction.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().duration_of_one_direction_cycle_rotation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.rotation.duration_of_one_direction_cycle_ms = self.durations.get(tab).unwrap().duration_of_one_direction_cycle_rotation.to_milliseconds();
@@ -373,7 +677,7 @@ impl TabViewer for Tabs<'_> {
                                         });
                                     ui.end_row();
                                     // Pause before direction change
-                                    ui.label("Pause:").on_hover_text("Pause before changing the direction of rotation.");
+                                    field_label(ui, "Pause:", &issues, ProtocolField::RotationPause).on_hover_text("Pause before changing the direction of rotation.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().pause_before_direction_change_rotation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.rotation.pause_before_direction_change_ms = self.durations.get(tab).unwrap().pause_before_direction_change_rotation.to_milliseconds();
@@ -393,7 +697,7 @@ impl TabViewer for Tabs<'_> {
                                     });
                                     ui.end_row();
                                     // Slider for rotation duration
-                                    ui.label("Rotation duration:").on_hover_text("Duration of the rotation phase.");
+                                    field_label(ui, "Rotation duration:", &issues, ProtocolField::RotationDuration).on_hover_text("Duration of the rotation phase.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().rotation_duration.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.rotation_duration_ms = self.durations.get(tab).unwrap().rotation_duration.to_milliseconds();
@@ -413,7 +717,7 @@ impl TabViewer for Tabs<'_> {
                                     });
                                     ui.end_row();
                                     // Slider for pause before agitation
-                                    ui.label("Pause pre-agitation:").on_hover_text("Pause before the agitation phase.");
+                                    field_label(ui, "Pause pre-agitation:", &issues, ProtocolField::PausePreAgitation).on_hover_text("Pause before the agitation phase.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().pause_pre_agitation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.pause_pre_agitation_ms = self.durations.get(tab).unwrap().pause_pre_agitation.to_milliseconds();
@@ -472,18 +776,27 @@ impl TabViewer for Tabs<'_> {
                             egui::Grid::new("agitation_grid")
                                 .show(ui, |ui| {
                                     // Slider for RPM
-                                    ui.label("RPM:");
+                                    field_label(ui, "RPM:", &issues, ProtocolField::AgitationRpm);
                                     let max_rpm = self.motor.get(tab).unwrap().protocol.agitation.max_rpm_for_stepmode();
                                     if ui.add(egui::Slider::new(&mut self.motor.get_mut(tab).unwrap().protocol.agitation.rpm, 1..=max_rpm)).changed() {
                                         agitation_graph_needs_update = true;
                                     }
                                     ui.end_row();
                                     // Slider for acceleration
-                                    ui.label("Acceleration:");
+                                    field_label(ui, "Acceleration:", &issues, ProtocolField::AgitationAcceleration);
                                     if ui.add(egui::Slider::new(&mut self.motor.get_mut(tab).unwrap().protocol.agitation.acceleration, 1..=MAX_ACCELERATION)).changed() {
                                         agitation_graph_needs_update = true;
                                     }
                                     ui.end_row();
+                                    // Speed actually reached during a cycle
+                                    ui.label("Profile:");
+                                    let kinematics = *self.motor.get(tab).unwrap().graph.agitation_kinematics.lock();
+                                    if kinematics_ui(ui, kinematics) {
+                                        let agitation = self.motor.get(tab).unwrap().protocol.agitation;
+                                        self.motor.get_mut(tab).unwrap().protocol.agitation = agitation.with_feasible_kinematics();
+                                        agitation_graph_needs_update = true;
+                                    }
+                                    ui.end_row();
                                     // List for stepmode
                                     let modes = self.motor.get(tab).unwrap().protocol.agitation.step_mode.get_modes();
                                     let selected_mode = self.motor.get(tab).unwrap().protocol.agitation.step_mode;
@@ -505,7 +818,7 @@ impl TabViewer for Tabs<'_> {
 
                                     ui.end_row();
                                     // Duration for 1 direction cycle
-                                    ui.label("Cycle duration:").on_hover_text("Duration of a cycle of agitations in one direction.");
+                                    field_label(ui, "Cycle duration:", &issues, ProtocolField::AgitationCycleDuration).on_hover_text("Duration of a cycle of agitations in one direction.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().duration_of_one_direction_cycle_agitation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.agitation.duration_of_one_direction_cycle_ms = self.durations.get(tab).unwrap().duration_of_one_direction_cycle_agitation.to_milliseconds();
@@ -542,7 +855,7 @@ impl TabViewer for Tabs<'_> {
                                         });
                                     ui.end_row();
                                     // Pause before direction change
-                                    ui.label("Pause:").on_hover_text("Pause before changing the direction of agitation.");
+                                    field_label(ui, "Pause:", &issues, ProtocolField::AgitationPause).on_hover_text("Pause before changing the direction of agitation.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().pause_before_direction_change_agitation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.agitation.pause_before_direction_change_ms = self.durations.get(tab).unwrap().pause_before_direction_change_agitation.to_milliseconds();
@@ -562,7 +875,7 @@ impl TabViewer for Tabs<'_> {
                                     });
                                     ui.end_row();
                                     // Slider for agitation duration
-                                    ui.label("Agitation duration:").on_hover_text("Duration of the agitation phase.");
+                                    field_label(ui, "Agitation duration:", &issues, ProtocolField::AgitationDuration).on_hover_text("Duration of the agitation phase.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().agitation_duration.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.agitation_duration_ms = self.durations.get(tab).unwrap().agitation_duration.to_milliseconds();
@@ -582,7 +895,7 @@ impl TabViewer for Tabs<'_> {
                                     });
                                     ui.end_row();
                                     // Slider for pause after agitation
-                                    ui.label("Pause post-agitation:").on_hover_text("Pause after the agitation phase.");
+                                    field_label(ui, "Pause post-agitation:", &issues, ProtocolField::PausePostAgitation).on_hover_text("Pause after the agitation phase.");
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().pause_post_agitation.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                             self.motor.get_mut(tab).unwrap().protocol.pause_post_agitation_ms = self.durations.get(tab).unwrap().pause_post_agitation.to_milliseconds();
@@ -639,8 +952,7 @@ impl TabViewer for Tabs<'_> {
                         ui.add_enabled_ui(!is_running, |ui| {
                             // Global duration of the protocol
                             ui.horizontal(|ui| {
-                                let color = if self.motor.get(tab).unwrap().protocol.global_duration_ms == 0 { THEME.red } else { THEME.text };
-                                ui.label(RichText::new("Global duration:").color(color).size(15.0)).on_hover_text("Global duration of the protocol.");
+                                issue_label(ui, RichText::new("Global duration:").size(15.0), &issues, ProtocolField::GlobalDuration).on_hover_text("Global duration of the protocol.");
                                 ui.horizontal(|ui| {
                                     if ui.add(egui::DragValue::new(&mut self.durations.get_mut(tab).unwrap().global_duration.days).suffix(" d").speed(2.0).clamp_range(0..=364)).changed() {
                                         self.motor.get_mut(tab).unwrap().protocol.global_duration_ms = self.durations.get(tab).unwrap().global_duration.to_milliseconds();
@@ -699,13 +1011,7 @@ impl TabViewer for Tabs<'_> {
                                 // Rotation
                                 if is_running && current_main_phase == StepperState::StartRotation && current_sub_phase != StepperState::StartPausePreAgitation && current_sub_phase != StepperState::StartPauseRotation {
                                     self.rotating_tubes.get_mut(tab).unwrap().1.angle_degrees = 0.0;
-                                    let mut rpm = 0;
-                                    self.motor.get(tab).unwrap().graph.rotation_points_sec_rpm.lock().iter().any(|point| {
-                                        if point[0] * 1000.0 >= run_time_current_sub_phase_ms as f64 {
-                                            rpm = point[1].round() as u32;
-                                            true
-                                        } else { false }
-                                    });
+                                    let rpm = self.motor.get(tab).unwrap().current_rpm();
                                     self.rotating_tubes.get_mut(tab).unwrap().0.rpm = rpm;
                                     let direction = self.motor.get(tab).unwrap().timers_and_phases.lock().rotation_direction;
                                     if direction == Direction::Forward {
@@ -730,13 +1036,7 @@ impl TabViewer for Tabs<'_> {
                                 // Agitation
                                 if is_running && current_main_phase == StepperState::StartAgitation && current_sub_phase != StepperState::StartPausePostAgitation && current_sub_phase != StepperState::StartPauseAgitation {
                                     self.rotating_tubes.get_mut(tab).unwrap().0.angle_degrees = 0.0;
-                                    let mut rpm = 0;
-                                    self.motor.get(tab).unwrap().graph.agitation_points_sec_rpm.lock().iter().any(|point| {
-                                        if point[0] * 1000.0 >= run_time_current_sub_phase_ms as f64 {
-                                            rpm = point[1].round() as u32;
-                                            true
-                                        } else { false }
-                                    });
+                                    let rpm = self.motor.get(tab).unwrap().current_rpm();
                                     self.rotating_tubes.get_mut(tab).unwrap().1.rpm = rpm;
                                     let direction = self.motor.get(tab).unwrap().timers_and_phases.lock().agitation_direction;
                                     if direction == Direction::Forward {
@@ -764,6 +1064,26 @@ impl TabViewer for Tabs<'_> {
             });
         });
         ui.separator();
+        ///// Timeline /////
+        if !self.motor.get(tab).unwrap().is_step_protocol {
+            let motor = self.motor.get(tab).unwrap();
+            let timeline = ProtocolTimeline::new(&motor.protocol);
+            let (elapsed_ms, expected_end_date) = {
+                let timers_and_phases = motor.timers_and_phases.lock();
+                (is_running.then(|| timers_and_phases.get_elapsed_time_since_global_start_as_millis()), timers_and_phases.expected_end_date)
+            };
+            ui.add(PhaseTimeline::new(timeline, elapsed_ms, expected_end_date));
+            ui.separator();
+        }
+        ///// Step protocol /////
+        egui::CollapsingHeader::new(RichText::new("Step protocol").size(FONT_BUTTON_SIZE.font_default + 2.0))
+            .id_source("step_protocol")
+            .show(ui, |ui| {
+                ui.add_enabled_ui(!is_running, |ui| {
+                    self.step_protocol_ui(ui, *tab);
+                });
+            });
+        ui.separator();
         ///// Graphs /////
         let default_color = ui.visuals().extreme_bg_color;
         ui.visuals_mut().extreme_bg_color = THEME.base;
@@ -848,7 +1168,7 @@ impl TabViewer for Tabs<'_> {
     fn on_close(&mut self, tab: &mut Self::Tab) -> bool {
         let is_running = self.motor.get(tab).unwrap().get_is_running();
         if is_running {
-            let message: Message = Message::new(ToastKind::Warning,
+            let message: Message = Message::new(MessageKind::Warning,
                                                 "Motor is running! Please stop the motor before closing the tab."
                                                 , None, Some(self.motor.get(tab).unwrap().name.to_string())
                                                 , 3, false);
@@ -873,3 +1193,63 @@ impl TabViewer for Tabs<'_> {
         tracing::info!("Added tab {} with {}", self.absolute_tab_counter, self.motor.get(self.absolute_tab_counter).unwrap().name);
     }
 }
+
+/// Step mode and direction combo boxes of a step.
+fn step_mode_direction_ui(ui: &mut Ui, step_mode: &mut StepMode128, direction: &mut Direction) {
+    egui::ComboBox::from_id_source("step_mode")
+        .width(60.0)
+        .selected_text(step_mode.to_string())
+        .show_ui(ui, |ui| {
+            for mode in step_mode.get_modes() {
+                ui.selectable_value(step_mode, mode, mode.to_string());
+            }
+        });
+    egui::ComboBox::from_id_source("direction")
+        .width(80.0)
+        .selected_text(direction.to_string())
+        .show_ui(ui, |ui| {
+            for value in [Direction::Forward, Direction::Backward] {
+                ui.selectable_value(direction, value, value.to_string());
+            }
+        });
+}
+
+/// Label of a protocol field, colored by the severity of its issue, which is shown on hover.
+fn field_label(ui: &mut Ui, text: &str, issues: &[ValidationIssue], field: ProtocolField) -> Response {
+    issue_label(ui, RichText::new(text), issues, field)
+}
+
+fn issue_label(ui: &mut Ui, text: RichText, issues: &[ValidationIssue], field: ProtocolField) -> Response {
+    match field_issue(issues, field) {
+        Some(issue) => {
+            let color = if issue.is_error() { THEME.red } else { THEME.peach };
+            ui.label(text.color(color)).on_hover_text(RichText::new(&issue.message).color(color))
+        }
+        None => ui.label(text),
+    }
+}
+
+/// Peak speed, cruise time and ramp share of a cycle. Returns true when the fix button is clicked.
+fn kinematics_ui(ui: &mut Ui, kinematics: Option<Kinematics>) -> bool {
+    let Some(kinematics) = kinematics else {
+        ui.label("-");
+        return false;
+    };
+    let mut is_fix_clicked = false;
+    ui.horizontal(|ui| {
+        let color = if kinematics.is_flagged() { THEME.peach } else { THEME.text };
+        let text = format!("Peak {:.0} RPM, cruise {}, ramp {:.0} %", kinematics.peak_rpm, DurationHelper::new_from_milliseconds(kinematics.cruise_ms), kinematics.ramp_share() * 100.0);
+        let response = ui.label(RichText::new(text).color(color));
+        if !kinematics.is_target_reached() {
+            response.on_hover_text(format!("The acceleration is too low to reach {} RPM within one cycle.", kinematics.target_rpm));
+        } else if kinematics.is_flagged() {
+            response.on_hover_text(format!("More than {:.0} % of each cycle is spent accelerating and decelerating.", MAX_RAMP_SHARE * 100.0));
+        } else {
+            response.on_hover_text("Speed profile of one cycle.");
+        }
+        if kinematics.is_flagged() {
+            is_fix_clicked = ui.button("Fix").on_hover_text("Raise the acceleration, or lower the RPM if the acceleration is already at its maximum.").clicked();
+        }
+    });
+    is_fix_clicked
+}
@@ -1,9 +1,38 @@
+pub mod constants;
 pub mod structs;
+#[cfg(feature = "gui")]
 pub mod helpers;
 pub mod enums;
 pub mod protocols;
+pub mod kinematics;
+pub mod timeline;
+pub mod validation;
+pub mod step_protocol;
+pub mod protocol_file;
+pub mod experiment;
+pub mod frame;
+pub mod status;
+pub mod errors;
+pub mod events;
+pub mod metrics;
 pub mod serial;
 pub mod graph;
 pub mod motor;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "sila")]
+pub mod sila;
+#[cfg(feature = "notifications")]
+pub mod notifications;
+#[cfg(feature = "gui")]
 pub mod widget_rotating_tube;
-pub mod frame_history;
\ No newline at end of file
+#[cfg(feature = "gui")]
+pub mod widget_timeline;
+#[cfg(feature = "gui")]
+pub mod frame_history;
+#[cfg(unix)]
+pub mod simulator;
\ No newline at end of file
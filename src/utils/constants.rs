@@ -0,0 +1,13 @@
+pub const THREAD_SLEEP: u64 = 10;
+pub const MAX_ACCELERATION: u32 = 20_000;
+pub const MAX_RPM: u32 = 5_000;
+// 1 year in milliseconds
+pub const MAX_DURATION_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+pub const MAX_POINTS_GRAPHS: usize = 250_000;
+pub const BYTES: usize = 110;
+/// Steps of a step protocol, the firmware numbering them on one byte.
+pub const MAX_STEPS: usize = 256;
+/// Wait for the answer to the status query sent when a motor is connected, kept short since an idle firmware may not answer.
+pub const STATUS_QUERY_TIMEOUT_MS: u64 = 300;
+pub const MAX_RECONNECT_BACKOFF_MS: u64 = 60_000;
+pub const BAUD_RATES: [u32; 10] = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 500000, 921600, 1000000];
@@ -2,6 +2,8 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
+use crate::utils::errors::DecodeError;
+
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub enum StepMode128 {
     #[default]
@@ -29,6 +31,21 @@ impl StepMode128 {
         }
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        match bytes.first() {
+            Some(0) => Ok(StepMode128::Full),
+            Some(1) => Ok(StepMode128::M2),
+            Some(2) => Ok(StepMode128::M4),
+            Some(3) => Ok(StepMode128::M8),
+            Some(4) => Ok(StepMode128::M16),
+            Some(5) => Ok(StepMode128::M32),
+            Some(6) => Ok(StepMode128::M64),
+            Some(7) => Ok(StepMode128::M128),
+            Some(byte) => Err(DecodeError::UnknownEnumByte { field: "step mode", byte: *byte }),
+            None => Err(DecodeError::Truncated { expected: 1, found: 0 }),
+        }
+    }
+
     pub fn get_modes(&self) -> [StepMode128; 8] {
         [StepMode128::Full, StepMode128::M2, StepMode128::M4, StepMode128::M8, StepMode128::M16, StepMode128::M32, StepMode128::M64, StepMode128::M128]
     }
@@ -78,6 +95,15 @@ impl Direction {
         }
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        match bytes.first() {
+            Some(0) => Ok(Direction::Forward),
+            Some(1) => Ok(Direction::Backward),
+            Some(byte) => Err(DecodeError::UnknownEnumByte { field: "direction", byte: *byte }),
+            None => Err(DecodeError::Truncated { expected: 1, found: 0 }),
+        }
+    }
+
     pub fn reverse(&self) -> Self {
         match self {
             Direction::Forward => Direction::Backward,
@@ -95,9 +121,44 @@ impl Display for Direction {
     }
 }
 
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FrameFormat {
+    /// 110 bytes bracketed by `a` and `z`, for older firmware.
+    #[default]
+    Legacy,
+    /// Versioned frame with a sequence id and a CRC.
+    Framed,
+}
+
+impl FrameFormat {
+    pub fn get_formats(&self) -> [FrameFormat; 2] {
+        [FrameFormat::Legacy, FrameFormat::Framed]
+    }
+}
+
+impl Display for FrameFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameFormat::Legacy => write!(f, "Legacy"),
+            FrameFormat::Framed => write!(f, "Framed"),
+        }
+    }
+}
+
+/// Severity of a `Message`. The app shows it as a toast, the other front ends print or forward it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageKind {
+    Info,
+    Success,
+    Warning,
+    Error,
+}
+
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub enum StepperState {
     CommandReceived,
+    Acknowledged(u8),
+    NotAcknowledged(u8),
     #[default]
     Finished,
     EmergencyStop,
@@ -112,6 +173,8 @@ pub enum StepperState {
     StartAgitation,
     StartPauseAgitation,
     StartPausePostAgitation,
+    /// Index of the step of a step protocol, counted across blocks.
+    StartStep(u8),
     StepgenRotationError,
     StepgenAgitationError,
     Invalid,
@@ -121,6 +184,8 @@ impl From<&[u8; 3]> for StepperState {
     fn from(bytes: &[u8; 3]) -> Self {
         match bytes {
             [b'o', b'k', b'!'] => StepperState::CommandReceived,
+            [b'a', b'k', sequence_id] => StepperState::Acknowledged(*sequence_id),
+            [b'n', b'k', sequence_id] => StepperState::NotAcknowledged(*sequence_id),
             [b'f', b'i', b'n'] => StepperState::Finished,
             [b'e', b'm', b'r'] => StepperState::EmergencyStop,
             [b'e', b'r', b'1'] => StepperState::OpenLoad,
@@ -134,6 +199,7 @@ impl From<&[u8; 3]> for StepperState {
             [b's', b't', b'a'] => StepperState::StartAgitation,
             [b's', b't', b'b'] => StepperState::StartPauseAgitation,
             [b's', b't', b'c'] => StepperState::StartPausePostAgitation,
+            [b's', b'p', index] => StepperState::StartStep(*index),
             [b'e', b'r', b'p'] => StepperState::StepgenRotationError,
             [b'e', b'a', b'p'] => StepperState::StepgenAgitationError,
             _ => StepperState::Invalid,
@@ -141,10 +207,46 @@ impl From<&[u8; 3]> for StepperState {
     }
 }
 
+impl StepperState {
+    /// State to the 3 bytes sent by the firmware.
+    pub fn as_bytes(&self) -> [u8; 3] {
+        match *self {
+            StepperState::CommandReceived => *b"ok!",
+            StepperState::Acknowledged(sequence_id) => [b'a', b'k', sequence_id],
+            StepperState::NotAcknowledged(sequence_id) => [b'n', b'k', sequence_id],
+            StepperState::Finished => *b"fin",
+            StepperState::EmergencyStop => *b"emr",
+            StepperState::OpenLoad => *b"er1",
+            StepperState::OverCurrent => *b"er2",
+            StepperState::OverHeat => *b"er3",
+            StepperState::OscillationRotation => *b"osr",
+            StepperState::OscillationAgitation => *b"osa",
+            StepperState::StartRotation => *b"str",
+            StepperState::StartPauseRotation => *b"stp",
+            StepperState::StartPausePreAgitation => *b"stq",
+            StepperState::StartAgitation => *b"sta",
+            StepperState::StartPauseAgitation => *b"stb",
+            StepperState::StartPausePostAgitation => *b"stc",
+            StepperState::StartStep(index) => [b's', b'p', index],
+            StepperState::StepgenRotationError => *b"erp",
+            StepperState::StepgenAgitationError => *b"eap",
+            StepperState::Invalid => *b"???",
+        }
+    }
+
+    /// The firmware stopped the motor because of a problem.
+    pub fn is_fault(&self) -> bool {
+        matches!(self, StepperState::EmergencyStop | StepperState::OpenLoad | StepperState::OverCurrent | StepperState::OverHeat
+            | StepperState::StepgenRotationError | StepperState::StepgenAgitationError | StepperState::Invalid)
+    }
+}
+
 impl Display for StepperState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StepperState::CommandReceived => write!(f, "Command received"),
+            StepperState::Acknowledged(sequence_id) => write!(f, "Frame {} acknowledged", sequence_id),
+            StepperState::NotAcknowledged(sequence_id) => write!(f, "⚠️Frame {} rejected⚠️", sequence_id),
             StepperState::Finished => write!(f, "Idle - Finished"),
             StepperState::EmergencyStop => write!(f, "⚠️Emergency stop⚠️"),
             StepperState::OpenLoad => write!(f, "⚠️Open load⚠️"),
@@ -158,9 +260,41 @@ impl Display for StepperState {
             StepperState::StartAgitation => write!(f, "Agitation"),
             StepperState::StartPauseAgitation => write!(f, "Pause agitation"),
             StepperState::StartPausePostAgitation => write!(f, "Pause post agitation"),
+            StepperState::StartStep(index) => write!(f, "Step {}", *index as u16 + 1),
             StepperState::StepgenRotationError => write!(f, "⚠️Error generating rotation steps⚠️"),
             StepperState::StepgenAgitationError => write!(f, "⚠️Error generating agitation steps⚠️"),
             StepperState::Invalid => write!(f, "⚠️Invalid⚠️"),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_mode_round_trip() {
+        for step_mode in StepMode128::default().get_modes() {
+            assert_eq!(StepMode128::from_bytes(step_mode.convert_to_bytes_slice()), Ok(step_mode));
+        }
+    }
+
+    #[test]
+    fn direction_round_trip() {
+        for direction in [Direction::Forward, Direction::Backward] {
+            assert_eq!(Direction::from_bytes(direction.convert_to_byte_slice()), Ok(direction));
+        }
+    }
+
+    #[test]
+    fn unknown_enum_bytes_are_rejected() {
+        assert_eq!(StepMode128::from_bytes(&[8]), Err(DecodeError::UnknownEnumByte { field: "step mode", byte: 8 }));
+        assert_eq!(Direction::from_bytes(&[2]), Err(DecodeError::UnknownEnumByte { field: "direction", byte: 2 }));
+    }
+
+    #[test]
+    fn empty_enum_bytes_are_truncated() {
+        assert_eq!(StepMode128::from_bytes(&[]), Err(DecodeError::Truncated { expected: 1, found: 0 }));
+        assert_eq!(Direction::from_bytes(&[]), Err(DecodeError::Truncated { expected: 1, found: 0 }));
+    }
+}
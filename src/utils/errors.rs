@@ -0,0 +1,187 @@
+use std::fmt::{Display, Formatter};
+
+use crate::utils::validation::ValidationIssue;
+
+/// Error while decoding bytes received from or sent to the firmware.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    InvalidMarker { index: usize, expected: u8, found: u8 },
+    UnknownEnumByte { field: &'static str, byte: u8 },
+    Truncated { expected: usize, found: usize },
+    /// Count read from the bytes, larger than what they can hold or than the firmware accepts.
+    TooMany { field: &'static str, count: usize, max: usize },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidMarker { index, expected, found } => write!(f, "Invalid marker at byte {}: expected {:?}, found {:?}", index, *expected as char, *found as char),
+            DecodeError::UnknownEnumByte { field, byte } => write!(f, "Unknown value {} for {}", byte, field),
+            DecodeError::Truncated { expected, found } => write!(f, "Truncated input: expected {} bytes, found {}", expected, found),
+            DecodeError::TooMany { field, count, max } => write!(f, "Too many {}: {}, at most {}", field, count, max),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Error of the serial layer.
+#[derive(Debug)]
+pub enum SerialError {
+    PortInUse { port_name: String },
+    PortOpen { port_name: String, source: serialport::Error },
+    Handshake { port_name: String, reason: String },
+    Read { port_name: String, source: std::io::Error },
+    InvalidState { port_name: String, bytes: [u8; 3] },
+    InvalidStatus { port_name: String, source: DecodeError },
+    WriteTimeout { port_name: String },
+    NotConnected { port_name: String },
+    AckTimeout { port_name: String, attempts: u32 },
+    Rejected { port_name: String, sequence_id: u8 },
+    Write { port_name: String, source: std::io::Error },
+    Encode { port_name: String, reason: String },
+}
+
+impl SerialError {
+    /// The link to the Raspberry is broken and the port should be reconnected.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, SerialError::Read { .. } | SerialError::InvalidState { .. } | SerialError::WriteTimeout { .. } | SerialError::Write { .. })
+    }
+}
+
+impl Display for SerialError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialError::PortInUse { port_name } => write!(f, "Serial port {} is already in use", port_name),
+            SerialError::PortOpen { port_name, source } => write!(f, "Unable to open serial port {}: {}", port_name, source),
+            SerialError::Handshake { port_name, reason } => write!(f, "Raspberry handshake failed on {}: {}", port_name, reason),
+            SerialError::Read { port_name, source } => write!(f, "Error while reading serial port {}: {}", port_name, source),
+            SerialError::InvalidState { port_name, bytes } => write!(f, "Invalid state {:?} received on {}", bytes, port_name),
+            SerialError::InvalidStatus { port_name, source } => write!(f, "Invalid status received on {}: {}", port_name, source),
+            SerialError::WriteTimeout { port_name } => write!(f, "Timeout while writing to serial port {}", port_name),
+            SerialError::NotConnected { port_name } => write!(f, "Serial port {} is not connected", port_name),
+            SerialError::AckTimeout { port_name, attempts } => write!(f, "No acknowledgement from {} after {} attempts", port_name, attempts),
+            SerialError::Rejected { port_name, sequence_id } => write!(f, "Frame {} corrupted or rejected by the firmware on {}", sequence_id, port_name),
+            SerialError::Write { port_name, source } => write!(f, "Error while writing to serial port {}: {}", port_name, source),
+            SerialError::Encode { port_name, reason } => write!(f, "Unable to encode the command for {}: {}", port_name, reason),
+        }
+    }
+}
+
+impl std::error::Error for SerialError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SerialError::PortOpen { source, .. } => Some(source),
+            SerialError::Read { source, .. } | SerialError::Write { source, .. } => Some(source),
+            SerialError::InvalidStatus { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Error of the motor layer.
+#[derive(Debug)]
+pub enum MotorError {
+    Serial(SerialError),
+    /// The errors found by `Protocol::validate` or `StepProtocol::validate`.
+    InvalidProtocol(Vec<ValidationIssue>),
+}
+
+impl From<SerialError> for MotorError {
+    fn from(error: SerialError) -> Self {
+        MotorError::Serial(error)
+    }
+}
+
+impl Display for MotorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MotorError::Serial(error) => write!(f, "{}", error),
+            MotorError::InvalidProtocol(issues) => {
+                let issues: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+                write!(f, "Invalid protocol: {}", issues.join(" "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for MotorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MotorError::Serial(error) => Some(error),
+            MotorError::InvalidProtocol(_) => None,
+        }
+    }
+}
+
+/// Error while reading or writing a protocol or experiment file.
+#[derive(Debug)]
+pub enum ProtocolFileError {
+    Json(serde_json::Error),
+    InvalidSchemaVersion,
+    NewerSchema { found: u64, supported: u32 },
+    UnknownFormat,
+}
+
+impl From<serde_json::Error> for ProtocolFileError {
+    fn from(error: serde_json::Error) -> Self {
+        ProtocolFileError::Json(error)
+    }
+}
+
+impl Display for ProtocolFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolFileError::Json(error) => write!(f, "Invalid file: {}", error),
+            ProtocolFileError::InvalidSchemaVersion => write!(f, "The schema version of the file is not a number"),
+            ProtocolFileError::NewerSchema { found, supported } => write!(f, "The file uses schema version {} but this version of Cell Spinner reads up to version {}. Please update Cell Spinner.", found, supported),
+            ProtocolFileError::UnknownFormat => write!(f, "The file is not a Cell Spinner protocol or experiment"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolFileError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Error of the servers exposing the motors to other programs.
+#[derive(Debug)]
+pub enum ApiError {
+    Bind { address: String, reason: String },
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Bind { address, reason } => write!(f, "Unable to listen on {}: {}", address, reason),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Error while sending a notification.
+#[derive(Debug)]
+pub enum NotificationError {
+    /// The reason starts with the URL.
+    Webhook { reason: String },
+    InvalidAddress { address: String, reason: String },
+    Email { host: String, reason: String },
+}
+
+impl Display for NotificationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotificationError::Webhook { reason } => write!(f, "Unable to call the webhook {}", reason),
+            NotificationError::InvalidAddress { address, reason } => write!(f, "Invalid e-mail address {:?}: {}", address, reason),
+            NotificationError::Email { host, reason } => write!(f, "Unable to send the e-mail through {}: {}", host, reason),
+        }
+    }
+}
+
+impl std::error::Error for NotificationError {}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use chrono::{DateTime, Local};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::utils::enums::StepperState;
+use crate::utils::motor::Motor;
+
+/// State reported by the firmware of a motor during a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotorEvent {
+    pub motor_name: String,
+    pub port_name: String,
+    pub timestamp: DateTime<Local>,
+    pub state: StepperState,
+    /// Set on the `Invalid` event of a port lost during the run, rather than of an unknown state received.
+    pub is_connection_lost: bool,
+}
+
+impl MotorEvent {
+    pub fn new(motor_name: &str, port_name: &str, state: StepperState) -> Self {
+        Self {
+            motor_name: motor_name.into(),
+            port_name: port_name.into(),
+            timestamp: Local::now(),
+            state,
+            is_connection_lost: false,
+        }
+    }
+
+    pub fn connection_lost(motor_name: &str, port_name: &str) -> Self {
+        Self {
+            is_connection_lost: true,
+            ..Self::new(motor_name, port_name, StepperState::Invalid)
+        }
+    }
+
+    /// One JSON object per event, for the tools that read the states line by line.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp.to_rfc3339(),
+            "motor": self.motor_name,
+            "port": self.port_name,
+            "state": format!("{:?}", self.state),
+            "description": self.state.to_string(),
+            "is_fault": self.state.is_fault(),
+        })
+    }
+}
+
+impl Display for MotorEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ({}): {}", self.timestamp.format("%Y/%m/%d %H:%M:%S%.3f"), self.motor_name, self.port_name, self.state)
+    }
+}
+
+/// Fan-out of the `MotorEvent`s of a port to every subscriber. Subscribers that were dropped are removed on the next event.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<MotorEvent>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<MotorEvent> {
+        let (event_tx, event_rx) = channel();
+        self.subscribers.lock().push(event_tx);
+        event_rx
+    }
+
+    pub fn publish(&self, event: MotorEvent) {
+        self.subscribers.lock().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Both buses are clones of the same one, e.g. the bus of a motor that was not reconnected since the subscription.
+    pub fn is_same(&self, other: &EventBus) -> bool {
+        Arc::ptr_eq(&self.subscribers, &other.subscribers)
+    }
+}
+
+/// Subscriptions to the buses of the motors of the tabs, for the consumers polling them.
+#[derive(Default)]
+pub struct Subscriptions {
+    receivers: HashMap<usize, (EventBus, Receiver<MotorEvent>)>,
+}
+
+impl Subscriptions {
+    /// Subscribe to the buses of the new motors and drop the subscriptions of the removed ones.
+    /// A motor gets a new bus each time its tab connects to a port.
+    pub fn refresh(&mut self, motors: &DashMap<usize, Motor>) {
+        motors.iter().for_each(|motor| {
+            let bus = &motor.serial.events;
+            if !self.receivers.get(motor.key()).map_or(false, |(subscribed, _)| subscribed.is_same(bus)) {
+                self.receivers.insert(*motor.key(), (bus.clone(), bus.subscribe()));
+            }
+        });
+        self.receivers.retain(|tab, _| motors.contains_key(tab));
+    }
+
+    /// Events received since the last call, with the tab of their motor.
+    pub fn try_iter(&self) -> impl Iterator<Item=(usize, MotorEvent)> + '_ {
+        self.receivers.iter().flat_map(|(tab, (_, events_rx))| events_rx.try_iter().map(move |event| (*tab, event)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(motors: &DashMap<usize, Motor>, tab: usize, state: StepperState) {
+        motors.get(&tab).unwrap().serial.events.publish(MotorEvent::new("Motor", "port", state));
+    }
+
+    fn received(subscriptions: &Subscriptions) -> Vec<(usize, StepperState)> {
+        subscriptions.try_iter().map(|(tab, event)| (tab, event.state)).collect()
+    }
+
+    #[test]
+    fn subscriptions_follow_the_motors() {
+        let motors = DashMap::new();
+        motors.insert(1, Motor::default());
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.refresh(&motors);
+        publish(&motors, 1, StepperState::StartRotation);
+        subscriptions.refresh(&motors);
+        assert_eq!(received(&subscriptions), [(1, StepperState::StartRotation)]);
+        // Connected again, the motor publishes on a new bus.
+        let previous_bus = motors.get(&1).unwrap().serial.events.clone();
+        motors.insert(1, Motor::default());
+        subscriptions.refresh(&motors);
+        previous_bus.publish(MotorEvent::new("Motor", "port", StepperState::OpenLoad));
+        publish(&motors, 1, StepperState::Finished);
+        assert_eq!(received(&subscriptions), [(1, StepperState::Finished)]);
+        motors.remove(&1);
+        subscriptions.refresh(&motors);
+        assert!(previous_bus.subscribers.lock().is_empty());
+        assert_eq!(subscriptions.try_iter().count(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::errors::ProtocolFileError;
+use crate::utils::protocol_file::ProtocolDocument;
+use crate::utils::structs::SerialSettings;
+
+/// Schema version written by `save_experiment`.
+pub const EXPERIMENT_SCHEMA_VERSION: u32 = 1;
+
+/// Everything needed to recreate one tab.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExperimentTab {
+    pub motor_name: String,
+    pub serial_port: String,
+    pub serial_settings: SerialSettings,
+    /// Migrated like a protocol file when the experiment is loaded.
+    pub protocol: Value,
+}
+
+/// Every tab of the app, in the order of the tab bar.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Experiment {
+    pub schema_version: u32,
+    pub created_at: DateTime<Local>,
+    pub tabs: Vec<ExperimentTab>,
+}
+
+impl Experiment {
+    pub fn new(tabs: Vec<ExperimentTab>) -> Self {
+        Self {
+            schema_version: EXPERIMENT_SCHEMA_VERSION,
+            created_at: Local::now(),
+            tabs,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProtocolFileError> {
+        let value: Value = serde_json::from_str(json)?;
+        if value.get("tabs").is_none() {
+            return Err(ProtocolFileError::UnknownFormat);
+        }
+        let schema_version = value.get("schema_version").and_then(Value::as_u64).filter(|version| *version > 0).ok_or(ProtocolFileError::InvalidSchemaVersion)?;
+        if schema_version > EXPERIMENT_SCHEMA_VERSION as u64 {
+            return Err(ProtocolFileError::NewerSchema { found: schema_version, supported: EXPERIMENT_SCHEMA_VERSION });
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, ProtocolFileError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl ExperimentTab {
+    pub fn new(motor_name: String, serial_port: String, serial_settings: SerialSettings, protocol: &ProtocolDocument) -> Result<Self, ProtocolFileError> {
+        Ok(Self {
+            motor_name,
+            serial_port,
+            serial_settings,
+            protocol: serde_json::to_value(protocol)?,
+        })
+    }
+
+    pub fn protocol_document(&self) -> Result<ProtocolDocument, ProtocolFileError> {
+        ProtocolDocument::from_value(self.protocol.clone())
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{bail, Error};
+
+/// Marker starting every framed command. Never the first byte of a legacy command.
+pub const FRAME_HEADER: [u8; 2] = *b"CS";
+pub const FRAME_VERSION: u8 = 1;
+/// Header (2) + version (1) + sequence id (1) + command (1) + payload length (2) + CRC (2).
+pub const FRAME_OVERHEAD: usize = 9;
+const FRAME_PREFIX: usize = 7;
+/// Largest payload accepted by the firmware. A longer length field is a corrupted one.
+pub const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameCommand {
+    StartProtocol,
+    /// Payload is a `StepProtocol`. The firmware sends `sp<step index>` when each step starts.
+    StartStepProtocol,
+}
+
+impl FrameCommand {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            FrameCommand::StartProtocol => b'p',
+            FrameCommand::StartStepProtocol => b's',
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'p' => Some(FrameCommand::StartProtocol),
+            b's' => Some(FrameCommand::StartStepProtocol),
+            _ => None,
+        }
+    }
+}
+
+/// Versioned command frame with a sequence id and a CRC-16 over everything after the header.
+/// The firmware answers `ak<sequence id>` when the frame is valid and `nk<sequence id>` otherwise.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandFrame {
+    pub version: u8,
+    pub sequence_id: u8,
+    pub command: FrameCommand,
+    pub payload: Vec<u8>,
+}
+
+impl CommandFrame {
+    pub fn new(sequence_id: u8, command: FrameCommand, payload: Vec<u8>) -> Self {
+        Self {
+            version: FRAME_VERSION,
+            sequence_id,
+            command,
+            payload,
+        }
+    }
+
+    /// Frame to bytes for serial communication. Fails if the payload is longer than `MAX_FRAME_PAYLOAD`.
+    pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        if self.payload.len() > MAX_FRAME_PAYLOAD {
+            bail!("Frame payload of {} bytes longer than the maximum of {}", self.payload.len(), MAX_FRAME_PAYLOAD);
+        }
+        let mut bytes = Vec::with_capacity(FRAME_OVERHEAD + self.payload.len());
+        bytes.extend_from_slice(&FRAME_HEADER);
+        bytes.push(self.version);
+        bytes.push(self.sequence_id);
+        bytes.push(self.command.as_byte());
+        bytes.extend_from_slice(&u16::try_from(self.payload.len())?.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        let crc = crc16(&bytes[FRAME_HEADER.len()..]);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Total length of the frame starting at `bytes`, or None while the length field is not received yet.
+    pub fn expected_length(bytes: &[u8]) -> Option<usize> {
+        if bytes.len() < FRAME_PREFIX {
+            return None;
+        }
+        Some(FRAME_OVERHEAD + u16::from_le_bytes([bytes[5], bytes[6]]) as usize)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < FRAME_OVERHEAD {
+            bail!("Frame too short: {} bytes", bytes.len());
+        }
+        if bytes[0..2] != FRAME_HEADER {
+            bail!("Invalid frame header {:?}", &bytes[0..2]);
+        }
+        let version = bytes[2];
+        if version != FRAME_VERSION {
+            bail!("Unsupported frame version {}", version);
+        }
+        let expected_length = Self::expected_length(bytes).unwrap_or_default();
+        if expected_length > FRAME_OVERHEAD + MAX_FRAME_PAYLOAD {
+            bail!("Frame payload of {} bytes longer than the maximum of {}", expected_length - FRAME_OVERHEAD, MAX_FRAME_PAYLOAD);
+        }
+        if bytes.len() != expected_length {
+            bail!("Frame length mismatch: expected {} bytes, got {}", expected_length, bytes.len());
+        }
+        let crc_index = bytes.len() - 2;
+        let crc = u16::from_le_bytes([bytes[crc_index], bytes[crc_index + 1]]);
+        if crc != crc16(&bytes[FRAME_HEADER.len()..crc_index]) {
+            bail!("Frame CRC mismatch");
+        }
+        let Some(command) = FrameCommand::from_byte(bytes[4]) else {
+            bail!("Unknown frame command {}", bytes[4]);
+        };
+        Ok(Self {
+            version,
+            sequence_id: bytes[3],
+            command,
+            payload: bytes[FRAME_PREFIX..crc_index].to_vec(),
+        })
+    }
+}
+
+/// CRC-16/CCITT-FALSE, as computed by the firmware.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> CommandFrame {
+        CommandFrame::new(42, FrameCommand::StartStepProtocol, (0..=255).collect())
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = frame();
+        let bytes = frame.as_bytes().unwrap();
+        assert_eq!(bytes.len(), FRAME_OVERHEAD + 256);
+        assert_eq!(CommandFrame::expected_length(&bytes), Some(bytes.len()));
+        assert_eq!(CommandFrame::from_bytes(&bytes).unwrap(), frame);
+    }
+
+    #[test]
+    fn every_corrupted_byte_is_rejected() {
+        let bytes = frame().as_bytes().unwrap();
+        for index in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 0x01;
+            assert!(CommandFrame::from_bytes(&corrupted).is_err(), "byte {} corrupted", index);
+        }
+    }
+
+    #[test]
+    fn too_long_payload_is_rejected() {
+        let mut bytes = CommandFrame::new(1, FrameCommand::StartProtocol, vec![]).as_bytes().unwrap();
+        bytes[5..7].copy_from_slice(&(MAX_FRAME_PAYLOAD as u16 + 1).to_le_bytes());
+        assert!(CommandFrame::from_bytes(&bytes).unwrap_err().to_string().contains("longer than the maximum"));
+        let frame = CommandFrame::new(1, FrameCommand::StartStepProtocol, vec![0; MAX_FRAME_PAYLOAD + 1]);
+        assert!(frame.as_bytes().unwrap_err().to_string().contains("longer than the maximum"));
+        let frame = CommandFrame::new(1, FrameCommand::StartStepProtocol, vec![0; usize::from(u16::MAX) + 1]);
+        assert!(frame.as_bytes().is_err());
+    }
+}
@@ -2,6 +2,8 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use parking_lot::Mutex;
 
+use crate::utils::kinematics::Kinematics;
+
 #[derive(Debug, Default, Clone)]
 pub struct Graph {
     pub rotation_points_sec_rpm: Arc<Mutex<Vec<[f64; 2]>>>,
@@ -10,4 +12,6 @@ pub struct Graph {
     pub agitation_thread_index: Arc<AtomicUsize>,
     pub is_generating_rotation_graph: Arc<AtomicBool>,
     pub is_generating_agitation_graph: Arc<AtomicBool>,
-}
\ No newline at end of file
+    pub rotation_kinematics: Arc<Mutex<Option<Kinematics>>>,
+    pub agitation_kinematics: Arc<Mutex<Option<Kinematics>>>,
+}
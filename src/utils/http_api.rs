@@ -0,0 +1,310 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::utils::enums::MessageKind;
+use crate::utils::errors::{ApiError, MotorError};
+use crate::utils::metrics;
+use crate::utils::motor::{self, Motor};
+use crate::utils::protocol_file::ProtocolDocument;
+use crate::utils::structs::Message;
+
+/// Time between two checks of the stop flag while waiting for requests.
+const POLL_INTERVAL_MS: u64 = 100;
+/// Bodies are cut above this size, a protocol file being a few kilobytes.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Settings of the HTTP API, saved with the app.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiSettings {
+    pub is_enabled: bool,
+    pub port: u16,
+    /// Expected as `Authorization: Bearer <token>`. Every request is accepted when empty.
+    pub token: String,
+    /// Serve `GET /metrics` for Prometheus.
+    pub is_metrics_enabled: bool,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            port: 8787,
+            token: String::new(),
+            is_metrics_enabled: false,
+        }
+    }
+}
+
+impl ApiSettings {
+    /// Settings to save, without the token. A server protected by a token is saved disabled, so that it does not start open to every program.
+    pub fn without_secrets(&self) -> Self {
+        Self {
+            is_enabled: self.is_enabled && self.token.is_empty(),
+            token: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// JSON API on `127.0.0.1` to control the motors of the app from other programs, e.g. the scripts of a LIMS:
+/// - `GET /motors`: status of every motor
+/// - `GET /motors/{id}`: status of one motor
+/// - `PUT /motors/{id}/protocol`: protocol file of the motor, as exported by the app
+/// - `POST /motors/{id}/start` and `POST /motors/{id}/stop`
+/// - `POST /motors/start` and `POST /motors/stop`: every connected motor
+/// - `GET /metrics`: metrics of every motor in the text format of Prometheus, if enabled in the settings
+///
+/// The ids are the numbers of the tabs. The server stops when dropped.
+pub struct ApiServer {
+    pub port: u16,
+    is_stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    /// Listen in a thread of its own. The messages of the motors are sent to `message_tx`, like the ones of the tabs.
+    pub fn start(settings: &ApiSettings, motors: Arc<DashMap<usize, Motor>>, message_tx: Option<Sender<Message>>) -> Result<Self, ApiError> {
+        let address = format!("127.0.0.1:{}", settings.port);
+        let server = Server::http(&address).map_err(|err| ApiError::Bind { address: address.clone(), reason: err.to_string() })?;
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let handler = Handler { motors, token: settings.token.clone(), is_metrics_enabled: settings.is_metrics_enabled, message_tx };
+        let handle = {
+            let is_stopped = is_stopped.clone();
+            thread::spawn(move || {
+                while !is_stopped.load(Ordering::SeqCst) {
+                    match server.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                        Ok(Some(request)) => handler.handle(request),
+                        Ok(None) => {}
+                        Err(err) => {
+                            tracing::error!("HTTP API stopped: {}", err);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+        tracing::info!("HTTP API listening on {}", address);
+        Ok(Self { port: settings.port, is_stopped, handle: Some(handle) })
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+        tracing::info!("HTTP API stopped.");
+    }
+}
+
+struct Handler {
+    motors: Arc<DashMap<usize, Motor>>,
+    token: String,
+    is_metrics_enabled: bool,
+    message_tx: Option<Sender<Message>>,
+}
+
+impl Handler {
+    fn handle(&self, mut request: Request) {
+        let is_metrics = self.is_metrics_enabled && request.method() == &Method::Get && request.url().split('?').next() == Some("/metrics");
+        let (status_code, content_type, body) = if !self.is_authorized(&request) {
+            (401, "application/json", error("Missing or invalid token").to_string())
+        } else if is_metrics {
+            (200, METRICS_CONTENT_TYPE, metrics::render(&self.motors))
+        } else {
+            let mut body = String::new();
+            let (status_code, body) = match request.as_reader().take(MAX_BODY_BYTES).read_to_string(&mut body) {
+                Ok(_) => self.route(request.method(), request.url(), &body),
+                Err(err) => (400, error(&format!("Unable to read the body: {}", err))),
+            };
+            (status_code, "application/json", body.to_string())
+        };
+        let response = Response::from_string(body)
+            .with_status_code(status_code)
+            .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+        if let Err(err) = request.respond(response) {
+            tracing::warn!("HTTP API - Unable to respond: {}", err);
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        self.token.is_empty() || request.headers().iter()
+            .any(|header| header.field.equiv("Authorization") && header.value.as_str() == format!("Bearer {}", self.token))
+    }
+
+    fn route(&self, method: &Method, url: &str, body: &str) -> (u16, Value) {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            (Method::Get, ["motors"]) => (200, self.list()),
+            (Method::Post, ["motors", "start"]) => self.start_all(),
+            (Method::Post, ["motors", "stop"]) => self.stop_all(),
+            (Method::Get, ["motors", id]) => self.with_motor(id, |tab, motor| (200, status(tab, motor))),
+            (Method::Put, ["motors", id, "protocol"]) => self.with_motor(id, |tab, motor| self.upload_protocol(tab, motor, body)),
+            (Method::Post, ["motors", id, "start"]) => self.start(id),
+            (Method::Post, ["motors", id, "stop"]) => self.with_motor(id, |tab, motor| self.stop(tab, motor)),
+            _ => (404, error(&format!("Unknown endpoint {} {}", method, path))),
+        }
+    }
+
+    fn list(&self) -> Value {
+        let mut tabs: Vec<usize> = self.motors.iter().map(|motor| *motor.key()).collect();
+        tabs.sort_unstable();
+        tabs.into_iter()
+            .filter_map(|tab| self.motors.get(&tab).map(|motor| status(tab, &motor)))
+            .collect()
+    }
+
+    /// The action must not wait for the firmware, the motor being locked meanwhile.
+    fn with_motor(&self, id: &str, action: impl FnOnce(usize, &mut Motor) -> (u16, Value)) -> (u16, Value) {
+        let tab = match parse_tab(id) {
+            Ok(tab) => tab,
+            Err(response) => return response,
+        };
+        match self.motors.get_mut(&tab) {
+            Some(mut motor) => action(tab, &mut motor),
+            None => unknown_motor(tab),
+        }
+    }
+
+    fn upload_protocol(&self, tab: usize, motor: &mut Motor, body: &str) -> (u16, Value) {
+        if motor.get_is_running() {
+            return (409, error("The motor is running. Stop it before uploading a protocol."));
+        }
+        let document = match ProtocolDocument::from_json(body) {
+            Ok(document) => document,
+            Err(err) => return (400, error(&err.to_string())),
+        };
+        match motor.import_document(&document) {
+            Ok(_) => {
+                motor.generate_graph_rotation();
+                motor.generate_graph_agitation();
+                motor.calculate_expected_end_date();
+                motor.is_protocol_updated = true;
+                self.send(Message::new(MessageKind::Info, "Protocol uploaded through the HTTP API", None, Some(motor.name.clone()), 3, false));
+                (200, status(tab, motor))
+            }
+            Err(MotorError::InvalidProtocol(issues)) => {
+                let issues: Vec<String> = issues.iter().map(|issue| issue.to_string()).collect();
+                (422, json!({ "error": "Invalid protocol", "issues": issues }))
+            }
+            Err(err) => (500, error(&err.to_string())),
+        }
+    }
+
+    /// The motor is released while the firmware acknowledges the protocol.
+    fn start(&self, id: &str) -> (u16, Value) {
+        let tab = match parse_tab(id) {
+            Ok(tab) => tab,
+            Err(response) => return response,
+        };
+        match self.motors.get(&tab) {
+            None => return unknown_motor(tab),
+            Some(motor) if !motor.get_is_connected() => return (409, error("The motor is not connected")),
+            Some(motor) if motor.get_is_running() => return (409, error("The motor is already running")),
+            Some(_) => {}
+        }
+        // The messages are sent to the app since the listener of the run keeps the channel.
+        motor::start_motor_in(&self.motors, tab, self.message_tx.clone());
+        self.with_motor(id, |tab, motor| {
+            if motor.get_is_running() {
+                return (200, status(tab, motor));
+            }
+            let issues = if motor.is_step_protocol { motor.step_protocol.validate() } else { motor.protocol.validate_run() };
+            let issues: Vec<String> = issues.into_iter().filter(|issue| issue.is_error()).map(|issue| issue.to_string()).collect();
+            if issues.is_empty() {
+                (502, error("The protocol was not started"))
+            } else {
+                (422, json!({ "error": "Invalid protocol", "issues": issues }))
+            }
+        })
+    }
+
+    fn stop(&self, tab: usize, motor: &mut Motor) -> (u16, Value) {
+        if !motor.get_is_running() {
+            return (409, error("The motor is not running"));
+        }
+        motor.stop_motor(self.message_tx.clone());
+        (200, status(tab, motor))
+    }
+
+    /// Same as the right click on the Run button of the app. The motors are started one after the other, none being held while the firmware acknowledges its protocol.
+    fn start_all(&self) -> (u16, Value) {
+        let (mut started, mut failed) = (vec![], vec![]);
+        let mut tabs: Vec<usize> = self.motors.iter().filter(|motor| motor.get_is_connected() && !motor.get_is_running()).map(|motor| *motor.key()).collect();
+        tabs.sort_unstable();
+        for tab in tabs {
+            motor::start_motor_in(&self.motors, tab, self.message_tx.clone());
+            if self.motors.get(&tab).map_or(false, |motor| motor.get_is_running()) {
+                started.push(tab);
+            } else {
+                failed.push(tab);
+            }
+        }
+        let status_code = if failed.is_empty() { 200 } else { 502 };
+        (status_code, json!({ "started": started, "failed": failed }))
+    }
+
+    fn stop_all(&self) -> (u16, Value) {
+        let mut stopped = vec![];
+        self.motors.iter().for_each(|motor| {
+            if motor.get_is_running() {
+                motor.stop_motor(self.message_tx.clone());
+                stopped.push(*motor.key());
+            }
+        });
+        stopped.sort_unstable();
+        (200, json!({ "stopped": stopped }))
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(message_tx) = &self.message_tx {
+            message_tx.send(message).ok();
+        }
+    }
+}
+
+fn status(tab: usize, motor: &Motor) -> Value {
+    let mut status = motor.status_json();
+    status["id"] = json!(tab);
+    status
+}
+
+fn error(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+fn parse_tab(id: &str) -> Result<usize, (u16, Value)> {
+    id.parse::<usize>().map_err(|_| (404, error(&format!("Unknown motor {:?}", id))))
+}
+
+fn unknown_motor(tab: usize) -> (u16, Value) {
+    (404, error(&format!("Unknown motor {}", tab)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_not_saved() {
+        let settings = ApiSettings { is_enabled: true, token: "secret".into(), ..Default::default() };
+        let saved = settings.without_secrets();
+        assert!(saved.token.is_empty());
+        assert!(!saved.is_enabled);
+        let open = ApiSettings { is_enabled: true, ..Default::default() };
+        assert_eq!(open.without_secrets(), open);
+    }
+}
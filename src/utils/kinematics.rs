@@ -0,0 +1,152 @@
+use fugit::TimerInstantU64;
+
+use crate::utils::constants::MAX_ACCELERATION;
+use crate::utils::protocols::Rotation;
+
+/// Share of a cycle spent accelerating and decelerating above which the cycle is flagged.
+pub const MAX_RAMP_SHARE: f64 = 0.5;
+/// The target speed counts as reached within 1 %, the step delays being rounded to the microsecond.
+const CRUISE_TOLERANCE: f64 = 0.99;
+
+/// Speed profile of one direction cycle, as generated by `Rotation::create_stepgen`. The motor decelerates at the end
+/// of the cycle as it accelerated at its start: trapezoidal when the target speed is reached, triangular otherwise.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Kinematics {
+    pub target_rpm: u32,
+    /// Highest speed reached during the cycle.
+    pub peak_rpm: f64,
+    /// Time spent accelerating before reaching `peak_rpm`, the same time being spent decelerating at the end of the cycle.
+    pub ramp_ms: u64,
+    /// Time spent at the target speed.
+    pub cruise_ms: u64,
+    pub cycle_ms: u64,
+}
+
+impl Kinematics {
+    pub fn is_target_reached(&self) -> bool {
+        self.peak_rpm >= self.target_rpm as f64 * CRUISE_TOLERANCE
+    }
+
+    /// Share of the cycle spent accelerating and decelerating, between 0 and 1.
+    pub fn ramp_share(&self) -> f64 {
+        (2 * self.ramp_ms) as f64 / self.cycle_ms as f64
+    }
+
+    /// The motor never turns at the speed typed by the user, or only for a short part of the cycle.
+    pub fn is_flagged(&self) -> bool {
+        !self.is_target_reached() || self.ramp_share() > MAX_RAMP_SHARE
+    }
+}
+
+impl Rotation {
+    /// Run the step generator until the target speed is reached or half of the cycle is spent accelerating.
+    /// `None` when there is no cycle to analyze.
+    pub fn kinematics(&self) -> Option<Kinematics> {
+        let cycle_ms = self.duration_of_one_direction_cycle_ms;
+        if cycle_ms == 0 || self.rpm == 0 || self.acceleration == 0 {
+            return None;
+        }
+        let multiplier = self.step_mode.get_multiplier() as f64;
+        let target_rpm = self.rpm as f64;
+        // The deceleration takes the second half of a cycle too short to reach the target speed.
+        let half_cycle_us = cycle_ms * 1000 / 2;
+        let mut stepgen = self.create_stepgen();
+        let mut elapsed_us = 0;
+        let mut peak_rpm: f64 = 0.0;
+        while elapsed_us < half_cycle_us {
+            let Some(delay_us) = stepgen.next_delay(Some(TimerInstantU64::from_ticks(elapsed_us / 1000))) else {
+                break;
+            };
+            peak_rpm = peak_rpm.max(300_000.0 / multiplier / delay_us.max(1) as f64);
+            if peak_rpm >= target_rpm * CRUISE_TOLERANCE {
+                let ramp_ms = elapsed_us / 1000;
+                return Some(Kinematics { target_rpm: self.rpm, peak_rpm: peak_rpm.min(target_rpm), ramp_ms, cruise_ms: cycle_ms - 2 * ramp_ms, cycle_ms });
+            }
+            elapsed_us += delay_us;
+        }
+        Some(Kinematics { target_rpm: self.rpm, peak_rpm, ramp_ms: cycle_ms / 2, cruise_ms: 0, cycle_ms })
+    }
+
+    /// Same rotation with the lowest acceleration that is not flagged by `Kinematics::is_flagged`.
+    /// The RPM is lowered when even `MAX_ACCELERATION` is not enough.
+    pub fn with_feasible_kinematics(&self) -> Rotation {
+        let is_feasible = |rotation: &Rotation| rotation.kinematics().map_or(true, |kinematics| !kinematics.is_flagged());
+        if is_feasible(self) {
+            return *self;
+        }
+        let mut rotation = *self;
+        rotation.acceleration = MAX_ACCELERATION;
+        if is_feasible(&rotation) {
+            rotation.acceleration = lowest_feasible(self.acceleration, MAX_ACCELERATION, |acceleration| is_feasible(&Rotation { acceleration, ..*self }));
+        } else {
+            // Highest RPM that is feasible, found as the lowest infeasible one minus one.
+            let rpm = lowest_feasible(1, self.rpm, |rpm| !is_feasible(&Rotation { rpm, ..rotation }));
+            rotation.rpm = rpm.saturating_sub(1).max(1);
+        }
+        rotation
+    }
+}
+
+/// Binary search of the lowest value of `low..=high` for which `is_feasible` holds, assuming it holds for `high`
+/// and that it keeps holding above the lowest feasible value.
+fn lowest_feasible(mut low: u32, mut high: u32, is_feasible: impl Fn(u32) -> bool) -> u32 {
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if is_feasible(middle) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    high
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(rpm: u32, acceleration: u32, duration_of_one_direction_cycle_ms: u64) -> Rotation {
+        Rotation { rpm, acceleration, duration_of_one_direction_cycle_ms, ..Default::default() }
+    }
+
+    #[test]
+    fn reached_target_gives_a_trapezoidal_profile() {
+        let kinematics = rotation(60, 60, 10_000).kinematics().unwrap();
+        assert!(kinematics.is_target_reached());
+        assert!(kinematics.peak_rpm <= 60.0);
+        assert!((900..=1_100).contains(&kinematics.ramp_ms), "{:?}", kinematics);
+        assert_eq!(kinematics.cruise_ms, kinematics.cycle_ms - 2 * kinematics.ramp_ms);
+        assert!((kinematics.ramp_share() - 0.2).abs() < 0.02, "{:?}", kinematics);
+        assert!(!kinematics.is_flagged());
+    }
+
+    #[test]
+    fn unreached_target_gives_a_triangular_profile() {
+        let kinematics = rotation(600, 60, 4_000).kinematics().unwrap();
+        assert!(!kinematics.is_target_reached());
+        assert!((108.0..=132.0).contains(&kinematics.peak_rpm), "{:?}", kinematics);
+        assert_eq!(kinematics.ramp_ms, 2_000);
+        assert_eq!(kinematics.cruise_ms, 0);
+        assert_eq!(kinematics.ramp_share(), 1.0);
+        assert!(kinematics.is_flagged());
+        assert_eq!(rotation(600, 60, 0).kinematics(), None);
+    }
+
+    #[test]
+    fn feasible_kinematics_are_the_closest_to_the_settings() {
+        let is_flagged = |rotation: Rotation| rotation.kinematics().unwrap().is_flagged();
+        // Acceleration raised to the lowest one that is not flagged.
+        let feasible = rotation(600, 60, 4_000).with_feasible_kinematics();
+        assert_eq!(feasible.rpm, 600);
+        assert!(!is_flagged(feasible));
+        assert!(is_flagged(Rotation { acceleration: feasible.acceleration - 1, ..feasible }));
+        // RPM lowered to the highest one that is not flagged, even the maximum acceleration being too low.
+        let feasible = rotation(5_000, 100, 200).with_feasible_kinematics();
+        assert_eq!(feasible.acceleration, MAX_ACCELERATION);
+        assert!(feasible.rpm < 5_000);
+        assert!(!is_flagged(feasible));
+        assert!(is_flagged(Rotation { rpm: feasible.rpm + 1, ..feasible }));
+        let rotation = rotation(60, 60, 10_000);
+        assert_eq!(rotation.with_feasible_kinematics(), rotation);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+
+use crate::utils::enums::StepperState;
+use crate::utils::motor::Motor;
+
+/// States counted as faults of a run, `Invalid` standing for the lost connections and the unknown states.
+pub const FAULTS: [StepperState; 7] = [
+    StepperState::EmergencyStop,
+    StepperState::OpenLoad,
+    StepperState::OverCurrent,
+    StepperState::OverHeat,
+    StepperState::StepgenRotationError,
+    StepperState::StepgenAgitationError,
+    StepperState::Invalid,
+];
+/// Upper bounds of the buckets of the handshake durations, in seconds. A handshake with retries takes seconds.
+const HANDSHAKE_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations of each bucket only, summed up when rendered.
+    buckets: [u64; HANDSHAKE_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = HANDSHAKE_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    runs_started: AtomicU64,
+    runs_finished: AtomicU64,
+    /// Same order as `FAULTS`.
+    runs_faulted: [AtomicU64; FAULTS.len()],
+    serial_errors: AtomicU64,
+    reconnects: AtomicU64,
+    handshakes: Mutex<Histogram>,
+}
+
+/// Counters of a serial link since it was opened, shared by its clones.
+#[derive(Clone, Default)]
+pub struct SerialMetrics {
+    counters: Arc<Counters>,
+}
+
+impl SerialMetrics {
+    pub fn run_started(&self) {
+        self.counters.runs_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn run_finished(&self) {
+        self.counters.runs_finished.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// States that are not in `FAULTS` are ignored.
+    pub fn run_faulted(&self, state: StepperState) {
+        if let Some(fault) = FAULTS.iter().position(|fault| *fault == state) {
+            self.counters.runs_faulted[fault].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Read and write errors of the port, invalid states and commands that were not acknowledged.
+    pub fn serial_error(&self) {
+        self.counters.serial_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Duration of a successful opening of the port and its `helo` handshake.
+    pub fn handshake(&self, duration: Duration) {
+        self.counters.handshakes.lock().observe(duration.as_secs_f64());
+    }
+}
+
+/// Metrics of every motor in the text format of Prometheus, labelled by the numbers of the tabs.
+/// The counters start again from 0 when a motor is connected again.
+pub fn render(motors: &DashMap<usize, Motor>) -> String {
+    let mut tabs: Vec<usize> = motors.iter().map(|motor| *motor.key()).collect();
+    tabs.sort_unstable();
+    let motors: Vec<_> = tabs.into_iter().filter_map(|tab| motors.get(&tab).map(|motor| (tab, motor))).collect();
+    let mut text = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: &dyn Fn(&mut String, usize, &Motor)| {
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+        for (tab, motor) in motors.iter() {
+            samples(&mut text, *tab, motor);
+        }
+    };
+    family("cell_spinner_motor_info", "gauge", "Name and serial port of the motor.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_motor_info{{motor=\"{}\",name=\"{}\",port=\"{}\"}} 1", tab, escape(&motor.name), escape(&motor.serial.port_name)).unwrap();
+    });
+    family("cell_spinner_connected", "gauge", "1 if the serial port of the motor is open.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_connected{{motor=\"{}\"}} {}", tab, u8::from(motor.get_is_connected())).unwrap();
+    });
+    family("cell_spinner_running", "gauge", "1 if the motor is running a protocol.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_running{{motor=\"{}\"}} {}", tab, u8::from(motor.get_is_running())).unwrap();
+    });
+    family("cell_spinner_rpm", "gauge", "Current speed of the motor according to its protocol.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_rpm{{motor=\"{}\"}} {}", tab, motor.current_rpm()).unwrap();
+    });
+    family("cell_spinner_phase", "gauge", "Current main phase and sub phase of the motor, always 1.", &|text, tab, motor| {
+        let (main_phase, sub_phase) = {
+            let lock = motor.timers_and_phases.lock();
+            (lock.main_phase, lock.sub_phase)
+        };
+        writeln!(text, "cell_spinner_phase{{motor=\"{}\",main_phase=\"{:?}\",sub_phase=\"{:?}\"}} 1", tab, main_phase, sub_phase).unwrap();
+    });
+    family("cell_spinner_runs_started_total", "counter", "Runs acknowledged by the firmware.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_runs_started_total{{motor=\"{}\"}} {}", tab, counters(motor).runs_started.load(Ordering::Relaxed)).unwrap();
+    });
+    family("cell_spinner_runs_finished_total", "counter", "Runs that reached the end of their protocol.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_runs_finished_total{{motor=\"{}\"}} {}", tab, counters(motor).runs_finished.load(Ordering::Relaxed)).unwrap();
+    });
+    family("cell_spinner_runs_faulted_total", "counter", "Runs stopped by a fault, by fault.", &|text, tab, motor| {
+        for (fault, count) in FAULTS.iter().zip(counters(motor).runs_faulted.iter()) {
+            writeln!(text, "cell_spinner_runs_faulted_total{{motor=\"{}\",fault=\"{:?}\"}} {}", tab, fault, count.load(Ordering::Relaxed)).unwrap();
+        }
+    });
+    family("cell_spinner_serial_errors_total", "counter", "Errors of the serial port, invalid states and commands that were not acknowledged.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_serial_errors_total{{motor=\"{}\"}} {}", tab, counters(motor).serial_errors.load(Ordering::Relaxed)).unwrap();
+    });
+    family("cell_spinner_reconnects_total", "counter", "Serial ports reopened after being lost during a run.", &|text, tab, motor| {
+        writeln!(text, "cell_spinner_reconnects_total{{motor=\"{}\"}} {}", tab, counters(motor).reconnects.load(Ordering::Relaxed)).unwrap();
+    });
+    family("cell_spinner_handshake_duration_seconds", "histogram", "Time taken to open the serial port and pass the handshake.", &|text, tab, motor| {
+        let histogram = counters(motor).handshakes.lock();
+        let mut cumulative = 0;
+        for (bound, count) in HANDSHAKE_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            writeln!(text, "cell_spinner_handshake_duration_seconds_bucket{{motor=\"{}\",le=\"{}\"}} {}", tab, bound, cumulative).unwrap();
+        }
+        writeln!(text, "cell_spinner_handshake_duration_seconds_bucket{{motor=\"{}\",le=\"+Inf\"}} {}", tab, histogram.count).unwrap();
+        writeln!(text, "cell_spinner_handshake_duration_seconds_sum{{motor=\"{}\"}} {}", tab, histogram.sum).unwrap();
+        writeln!(text, "cell_spinner_handshake_duration_seconds_count{{motor=\"{}\"}} {}", tab, histogram.count).unwrap();
+    });
+    text
+}
+
+fn counters(motor: &Motor) -> &Counters {
+    &motor.serial.metrics.counters
+}
+
+/// Label values are quoted, the names of the motors being typed by the users.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_of_a_motor() {
+        let mut motor = Motor { name: "Spinner \"A\"\\1\nB".into(), ..Default::default() };
+        motor.serial.port_name = "/dev/ttyACM0".into();
+        {
+            let mut timers_and_phases = motor.timers_and_phases.lock();
+            timers_and_phases.main_phase = StepperState::StartAgitation;
+            timers_and_phases.sub_phase = StepperState::StartPauseAgitation;
+        }
+        let metrics = &motor.serial.metrics;
+        (0..3).for_each(|_| metrics.run_started());
+        metrics.run_finished();
+        metrics.run_faulted(StepperState::OverHeat);
+        metrics.run_faulted(StepperState::Invalid);
+        metrics.run_faulted(StepperState::Finished);
+        metrics.serial_error();
+        metrics.serial_error();
+        metrics.reconnected();
+        // Exact in binary, so is their sum. The last one is beyond the buckets.
+        metrics.handshake(Duration::from_micros(15_625));
+        metrics.handshake(Duration::from_millis(250));
+        metrics.handshake(Duration::from_secs(20));
+        let motors = DashMap::new();
+        motors.insert(2, motor);
+        let expected = [
+            r#"# HELP cell_spinner_motor_info Name and serial port of the motor."#,
+            r#"# TYPE cell_spinner_motor_info gauge"#,
+            r#"cell_spinner_motor_info{motor="2",name="Spinner \"A\"\\1\nB",port="/dev/ttyACM0"} 1"#,
+            r#"# HELP cell_spinner_connected 1 if the serial port of the motor is open."#,
+            r#"# TYPE cell_spinner_connected gauge"#,
+            r#"cell_spinner_connected{motor="2"} 0"#,
+            r#"# HELP cell_spinner_running 1 if the motor is running a protocol."#,
+            r#"# TYPE cell_spinner_running gauge"#,
+            r#"cell_spinner_running{motor="2"} 0"#,
+            r#"# HELP cell_spinner_rpm Current speed of the motor according to its protocol."#,
+            r#"# TYPE cell_spinner_rpm gauge"#,
+            r#"cell_spinner_rpm{motor="2"} 0"#,
+            r#"# HELP cell_spinner_phase Current main phase and sub phase of the motor, always 1."#,
+            r#"# TYPE cell_spinner_phase gauge"#,
+            r#"cell_spinner_phase{motor="2",main_phase="StartAgitation",sub_phase="StartPauseAgitation"} 1"#,
+            r#"# HELP cell_spinner_runs_started_total Runs acknowledged by the firmware."#,
+            r#"# TYPE cell_spinner_runs_started_total counter"#,
+            r#"cell_spinner_runs_started_total{motor="2"} 3"#,
+            r#"# HELP cell_spinner_runs_finished_total Runs that reached the end of their protocol."#,
+            r#"# TYPE cell_spinner_runs_finished_total counter"#,
+            r#"cell_spinner_runs_finished_total{motor="2"} 1"#,
+            r#"# HELP cell_spinner_runs_faulted_total Runs stopped by a fault, by fault."#,
+            r#"# TYPE cell_spinner_runs_faulted_total counter"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="EmergencyStop"} 0"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="OpenLoad"} 0"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="OverCurrent"} 0"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="OverHeat"} 1"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="StepgenRotationError"} 0"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="StepgenAgitationError"} 0"#,
+            r#"cell_spinner_runs_faulted_total{motor="2",fault="Invalid"} 1"#,
+            r#"# HELP cell_spinner_serial_errors_total Errors of the serial port, invalid states and commands that were not acknowledged."#,
+            r#"# TYPE cell_spinner_serial_errors_total counter"#,
+            r#"cell_spinner_serial_errors_total{motor="2"} 2"#,
+            r#"# HELP cell_spinner_reconnects_total Serial ports reopened after being lost during a run."#,
+            r#"# TYPE cell_spinner_reconnects_total counter"#,
+            r#"cell_spinner_reconnects_total{motor="2"} 1"#,
+            r#"# HELP cell_spinner_handshake_duration_seconds Time taken to open the serial port and pass the handshake."#,
+            r#"# TYPE cell_spinner_handshake_duration_seconds histogram"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.01"} 0"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.025"} 1"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.05"} 1"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.1"} 1"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.25"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="0.5"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="1"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="2.5"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="5"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="10"} 2"#,
+            r#"cell_spinner_handshake_duration_seconds_bucket{motor="2",le="+Inf"} 3"#,
+            r#"cell_spinner_handshake_duration_seconds_sum{motor="2"} 20.265625"#,
+            r#"cell_spinner_handshake_duration_seconds_count{motor="2"} 3"#,
+        ];
+        assert_eq!(render(&motors), expected.iter().map(|line| format!("{}\n", line)).collect::<String>());
+    }
+
+    #[test]
+    fn motors_are_rendered_by_tab() {
+        let motors = DashMap::new();
+        for tab in [3, 1, 2] {
+            motors.insert(tab, Motor::default());
+        }
+        let text = render(&motors);
+        let tabs: Vec<&str> = text.lines().filter(|line| line.starts_with("cell_spinner_connected{")).collect();
+        assert_eq!(tabs, [r#"cell_spinner_connected{motor="1"} 0"#, r#"cell_spinner_connected{motor="2"} 0"#, r#"cell_spinner_connected{motor="3"} 0"#]);
+    }
+}
@@ -0,0 +1,454 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::enums::{Direction, StepMode128, StepperState};
+use crate::utils::errors::ApiError;
+use crate::utils::motor::{self, Motor};
+use crate::utils::protocols::{Protocol, Rotation};
+use crate::utils::structs::Message;
+
+/// Time between two checks of the stop flag while waiting for connections and requests.
+const POLL_INTERVAL_MS: u64 = 100;
+/// Registers, coils and inputs of motor `n` start at `(n - 1) * BLOCK_SIZE`.
+pub const BLOCK_SIZE: u16 = 100;
+/// Registers of a `Rotation` in the holding registers, the agitation following the rotation.
+const ROTATION_REGISTERS: usize = 12;
+const HOLDING_REGISTERS: usize = 2 * ROTATION_REGISTERS + 5 * 4;
+const INPUT_REGISTERS: usize = 7;
+/// Coil 0: the motor runs.
+const COILS: usize = 1;
+/// Discrete input 0: the motor is connected.
+const DISCRETE_INPUTS: usize = 1;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_READ_BITS: u16 = 2000;
+
+/// Settings of the Modbus TCP server, saved with the app.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModbusSettings {
+    pub is_enabled: bool,
+    /// `0.0.0.0` to accept the PLCs of the network. Modbus has no access control.
+    pub address: String,
+    /// 5020 by default, the standard port 502 needing administrator rights on Linux and macOS.
+    pub port: u16,
+}
+
+impl Default for ModbusSettings {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 5020,
+        }
+    }
+}
+
+/// Exception codes of the Modbus application protocol.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+    ServerDeviceBusy = 6,
+}
+
+/// Modbus TCP server mapping each motor to a block of `BLOCK_SIZE` addresses. Offsets in the block of a motor:
+/// - holding registers 0 to 11: rotation, as RPM, acceleration, step mode (0 = full step to 7 = 1/128),
+///   direction (0 = forward, 1 = backward), duration of one direction cycle and pause before a direction change
+/// - holding registers 12 to 23: agitation, same layout
+/// - holding registers 24 to 43: rotation, pause pre-agitation, agitation, pause post-agitation and global durations
+/// - input registers 0 and 1: main phase and sub-phase, as codes of `state_code`
+/// - input registers 2 to 5: elapsed time of the run
+/// - input register 6: fault code, the state code of the fault or 0
+/// - coil 0: start (1) or stop (0) the motor
+/// - discrete input 0: the motor is connected
+///
+/// Durations are in milliseconds, on 4 registers with the most significant first. The holding registers can only be
+/// written while the motor is stopped, and each write is validated like an imported protocol: the fields that depend on
+/// each other, e.g. a new protocol, have to be written at once with function 16. The server stops when dropped.
+pub struct ModbusServer {
+    pub address: String,
+    is_stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ModbusServer {
+    /// Listen in a thread of its own, each connection being served by another thread.
+    pub fn start(settings: &ModbusSettings, motors: Arc<DashMap<usize, Motor>>, message_tx: Option<Sender<Message>>) -> Result<Self, ApiError> {
+        let address = format!("{}:{}", settings.address, settings.port);
+        let bind_error = |err: io::Error| {
+            let reason = if err.kind() == io::ErrorKind::PermissionDenied && settings.port < 1024 {
+                format!("{} (the ports below 1024 need administrator rights, use 5020 or redirect 502 to it)", err)
+            } else {
+                err.to_string()
+            };
+            ApiError::Bind { address: address.clone(), reason }
+        };
+        let listener = TcpListener::bind(&address).map_err(bind_error)?;
+        listener.set_nonblocking(true).map_err(bind_error)?;
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let is_stopped = is_stopped.clone();
+            thread::spawn(move || {
+                while !is_stopped.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, peer)) => {
+                            tracing::info!("Modbus - Connection from {}", peer);
+                            let connection = Connection { motors: motors.clone(), message_tx: message_tx.clone(), is_stopped: is_stopped.clone() };
+                            thread::spawn(move || connection.serve(stream));
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(POLL_INTERVAL_MS)),
+                        Err(err) => {
+                            tracing::error!("Modbus server stopped: {}", err);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+        tracing::info!("Modbus server listening on {}", address);
+        Ok(Self { address, is_stopped, handle: Some(handle) })
+    }
+}
+
+impl Drop for ModbusServer {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+        tracing::info!("Modbus server stopped.");
+    }
+}
+
+struct Connection {
+    motors: Arc<DashMap<usize, Motor>>,
+    message_tx: Option<Sender<Message>>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn serve(self, mut stream: TcpStream) {
+        if let Err(err) = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))) {
+            tracing::warn!("Modbus - Unable to configure the connection: {}", err);
+            return;
+        }
+        loop {
+            // MBAP header: transaction id, protocol id (0), length of the unit id and the PDU, unit id.
+            let mut header = [0; 7];
+            if self.read_exact(&mut stream, &mut header).is_err() {
+                break;
+            }
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+                tracing::warn!("Modbus - Invalid header {:?}, closing the connection.", header);
+                break;
+            }
+            let mut request = vec![0; length - 1];
+            if self.read_exact(&mut stream, &mut request).is_err() {
+                break;
+            }
+            let response = self.process(&request).unwrap_or_else(|exception| vec![request[0] | 0x80, exception as u8]);
+            let mut frame = Vec::with_capacity(7 + response.len());
+            frame.extend_from_slice(&header[0..4]);
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            if stream.write_all(&frame).is_err() {
+                break;
+            }
+        }
+        tracing::info!("Modbus - Connection closed.");
+    }
+
+    /// Same as `Read::read_exact`, giving up when the server stops.
+    fn read_exact(&self, stream: &mut TcpStream, buffer: &mut [u8]) -> io::Result<()> {
+        let mut count = 0;
+        while count < buffer.len() {
+            if self.is_stopped.load(Ordering::SeqCst) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            match stream.read(&mut buffer[count..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => count += read,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Response PDU of a request PDU.
+    fn process(&self, request: &[u8]) -> Result<Vec<u8>, Exception> {
+        let (&function, data) = request.split_first().ok_or(Exception::IllegalFunction)?;
+        match function {
+            0x01 => self.read_bits(function, data, COILS, |motor| vec![motor.get_is_running()]),
+            0x02 => self.read_bits(function, data, DISCRETE_INPUTS, |motor| vec![motor.get_is_connected()]),
+            0x03 => self.read_registers(function, data, HOLDING_REGISTERS, |motor| holding_registers(&motor.protocol)),
+            0x04 => self.read_registers(function, data, INPUT_REGISTERS, input_registers),
+            0x05 => {
+                let is_running = match word(data, 2)? {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                };
+                self.write_coils(word(data, 0)?, &[is_running])?;
+                Ok(request.to_vec())
+            }
+            0x06 => {
+                self.write_registers(word(data, 0)?, &[word(data, 2)?])?;
+                Ok(request.to_vec())
+            }
+            0x0F => {
+                let (start, quantity) = (word(data, 0)?, word(data, 2)?);
+                let bytes = data.get(5..5 + (quantity as usize + 7) / 8).ok_or(Exception::IllegalDataValue)?;
+                let values: Vec<bool> = (0..quantity as usize).map(|index| bytes[index / 8] & (1 << (index % 8)) != 0).collect();
+                self.write_coils(start, &values)?;
+                Ok(request[..5].to_vec())
+            }
+            0x10 => {
+                let (start, quantity) = (word(data, 0)?, word(data, 2)?);
+                let values = (0..quantity as usize).map(|index| word(data, 5 + index * 2)).collect::<Result<Vec<u16>, Exception>>()?;
+                self.write_registers(start, &values)?;
+                Ok(request[..5].to_vec())
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    fn read_bits(&self, function: u8, data: &[u8], count: usize, bits: impl Fn(&Motor) -> Vec<bool>) -> Result<Vec<u8>, Exception> {
+        let (start, quantity) = (word(data, 0)?, word(data, 2)?);
+        if quantity == 0 || quantity > MAX_READ_BITS {
+            return Err(Exception::IllegalDataValue);
+        }
+        let (tab, offset) = block(start, quantity, count)?;
+        let motor = self.motors.get(&tab).ok_or(Exception::IllegalDataAddress)?;
+        let bits = bits(&motor);
+        let mut response = vec![function, ((quantity + 7) / 8) as u8];
+        for (index, bit) in bits[offset..offset + quantity as usize].iter().enumerate() {
+            if index % 8 == 0 {
+                response.push(0);
+            }
+            *response.last_mut().unwrap() |= (*bit as u8) << (index % 8);
+        }
+        Ok(response)
+    }
+
+    fn read_registers(&self, function: u8, data: &[u8], count: usize, registers: impl Fn(&Motor) -> Vec<u16>) -> Result<Vec<u8>, Exception> {
+        let (start, quantity) = (word(data, 0)?, word(data, 2)?);
+        if quantity == 0 || quantity > MAX_READ_REGISTERS {
+            return Err(Exception::IllegalDataValue);
+        }
+        let (tab, offset) = block(start, quantity, count)?;
+        let motor = self.motors.get(&tab).ok_or(Exception::IllegalDataAddress)?;
+        let mut response = vec![function, (quantity * 2) as u8];
+        registers(&motor)[offset..offset + quantity as usize].iter().for_each(|register| response.extend_from_slice(&register.to_be_bytes()));
+        Ok(response)
+    }
+
+    /// Same as the Run and STOP MOTOR buttons of the tabs.
+    fn write_coils(&self, start: u16, values: &[bool]) -> Result<(), Exception> {
+        let (tab, _) = block(start, values.len() as u16, COILS)?;
+        {
+            let motor = self.motors.get(&tab).ok_or(Exception::IllegalDataAddress)?;
+            let is_running = values[0];
+            if is_running == motor.get_is_running() {
+                return Ok(());
+            }
+            if !is_running {
+                motor.stop_motor(self.message_tx.clone());
+                return Ok(());
+            }
+            if !motor.get_is_connected() {
+                return Err(Exception::ServerDeviceFailure);
+            }
+        }
+        // The motor is released while the firmware acknowledges the protocol.
+        motor::start_motor_in(&self.motors, tab, self.message_tx.clone());
+        if self.motors.get(&tab).map_or(false, |motor| motor.get_is_running()) { Ok(()) } else { Err(Exception::ServerDeviceFailure) }
+    }
+
+    fn write_registers(&self, start: u16, values: &[u16]) -> Result<(), Exception> {
+        if values.is_empty() {
+            return Err(Exception::IllegalDataValue);
+        }
+        let (tab, offset) = block(start, values.len() as u16, HOLDING_REGISTERS)?;
+        let mut motor = self.motors.get_mut(&tab).ok_or(Exception::IllegalDataAddress)?;
+        if motor.get_is_running() {
+            return Err(Exception::ServerDeviceBusy);
+        }
+        let mut registers = holding_registers(&motor.protocol);
+        registers[offset..offset + values.len()].copy_from_slice(values);
+        let protocol = protocol_from_holding_registers(&registers, motor.protocol)?;
+        if protocol == motor.protocol && !motor.is_step_protocol {
+            return Ok(());
+        }
+        if let Err(err) = motor.import_protocol(protocol) {
+            tracing::warn!("Modbus - {} - {}", motor.name, err);
+            return Err(Exception::IllegalDataValue);
+        }
+        // The PLC runs the protocol of the registers, not the step protocol of the tab.
+        motor.is_step_protocol = false;
+        motor.generate_graph_rotation();
+        motor.generate_graph_agitation();
+        motor.calculate_expected_end_date();
+        motor.is_protocol_updated = true;
+        Ok(())
+    }
+}
+
+/// Tab and offset of a range of `quantity` addresses, which has to fit in the first `count` addresses of a block.
+fn block(start: u16, quantity: u16, count: usize) -> Result<(usize, usize), Exception> {
+    let offset = (start % BLOCK_SIZE) as usize;
+    if quantity == 0 || offset + quantity as usize > count {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(((start / BLOCK_SIZE) as usize + 1, offset))
+}
+
+/// Big-endian word at `index` of the data of a request.
+fn word(data: &[u8], index: usize) -> Result<u16, Exception> {
+    match data.get(index..index + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(Exception::IllegalDataValue),
+    }
+}
+
+fn push_u64(registers: &mut Vec<u16>, value: u64) {
+    registers.extend((0..4).rev().map(|index| (value >> (index * 16)) as u16));
+}
+
+fn u64_at(registers: &[u16], index: usize) -> u64 {
+    registers[index..index + 4].iter().fold(0, |value, register| value << 16 | *register as u64)
+}
+
+fn holding_registers(protocol: &Protocol) -> Vec<u16> {
+    let mut registers = Vec::with_capacity(HOLDING_REGISTERS);
+    for rotation in [&protocol.rotation, &protocol.agitation] {
+        registers.push(rotation.rpm.min(u16::MAX as u32) as u16);
+        registers.push(rotation.acceleration.min(u16::MAX as u32) as u16);
+        registers.push(rotation.step_mode.convert_to_bytes_slice()[0] as u16);
+        registers.push(rotation.direction.convert_to_byte_slice()[0] as u16);
+        push_u64(&mut registers, rotation.duration_of_one_direction_cycle_ms);
+        push_u64(&mut registers, rotation.pause_before_direction_change_ms);
+    }
+    for duration_ms in [protocol.rotation_duration_ms, protocol.pause_pre_agitation_ms, protocol.agitation_duration_ms, protocol.pause_post_agitation_ms, protocol.global_duration_ms] {
+        push_u64(&mut registers, duration_ms);
+    }
+    registers
+}
+
+/// `protocol` with the values of the holding registers. The fields that are not mapped are kept.
+fn protocol_from_holding_registers(registers: &[u16], mut protocol: Protocol) -> Result<Protocol, Exception> {
+    let rotation_from_registers = |registers: &[u16], rotation: Rotation| -> Result<Rotation, Exception> {
+        let byte = |register: u16| u8::try_from(register).map_err(|_| Exception::IllegalDataValue);
+        Ok(Rotation {
+            rpm: registers[0] as u32,
+            acceleration: registers[1] as u32,
+            step_mode: StepMode128::from_bytes(&[byte(registers[2])?]).map_err(|_| Exception::IllegalDataValue)?,
+            direction: Direction::from_bytes(&[byte(registers[3])?]).map_err(|_| Exception::IllegalDataValue)?,
+            duration_of_one_direction_cycle_ms: u64_at(registers, 4),
+            pause_before_direction_change_ms: u64_at(registers, 8),
+            ..rotation
+        })
+    };
+    protocol.rotation = rotation_from_registers(&registers[..ROTATION_REGISTERS], protocol.rotation)?;
+    protocol.agitation = rotation_from_registers(&registers[ROTATION_REGISTERS..], protocol.agitation)?;
+    let durations = &registers[2 * ROTATION_REGISTERS..];
+    protocol.rotation_duration_ms = u64_at(durations, 0);
+    protocol.pause_pre_agitation_ms = u64_at(durations, 4);
+    protocol.agitation_duration_ms = u64_at(durations, 8);
+    protocol.pause_post_agitation_ms = u64_at(durations, 12);
+    protocol.global_duration_ms = u64_at(durations, 16);
+    Ok(protocol)
+}
+
+fn input_registers(motor: &Motor) -> Vec<u16> {
+    let timers_and_phases = motor.timers_and_phases.lock();
+    let main_phase = timers_and_phases.main_phase;
+    let mut registers = Vec::with_capacity(INPUT_REGISTERS);
+    registers.push(state_code(main_phase));
+    registers.push(state_code(timers_and_phases.sub_phase));
+    push_u64(&mut registers, timers_and_phases.get_run_elapsed_ms().unwrap_or(0));
+    registers.push(if main_phase.is_fault() { state_code(main_phase) } else { 0 });
+    registers
+}
+
+/// Code of a state in the input registers. 0 is idle, the steps of a step protocol are `0x100 + index`.
+pub fn state_code(state: StepperState) -> u16 {
+    match state {
+        StepperState::Finished => 0,
+        StepperState::EmergencyStop => 1,
+        StepperState::OpenLoad => 2,
+        StepperState::OverCurrent => 3,
+        StepperState::OverHeat => 4,
+        StepperState::StepgenRotationError => 5,
+        StepperState::StepgenAgitationError => 6,
+        StepperState::Invalid => 7,
+        StepperState::StartRotation => 10,
+        StepperState::StartPauseRotation => 11,
+        StepperState::StartPausePreAgitation => 12,
+        StepperState::StartAgitation => 13,
+        StepperState::StartPauseAgitation => 14,
+        StepperState::StartPausePostAgitation => 15,
+        StepperState::OscillationRotation => 16,
+        StepperState::OscillationAgitation => 17,
+        StepperState::CommandReceived => 20,
+        StepperState::Acknowledged(_) => 21,
+        StepperState::NotAcknowledged(_) => 22,
+        StepperState::StartStep(index) => 0x100 + index as u16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::constants::MAX_RPM;
+
+    fn connection() -> Connection {
+        let mut motor = Motor::default();
+        motor.protocol.rotation = Rotation { rpm: 60, acceleration: 100, duration_of_one_direction_cycle_ms: 1_000, ..Default::default() };
+        motor.protocol.rotation_duration_ms = 10_000;
+        motor.protocol.global_duration_ms = 10_000;
+        motor.is_step_protocol = true;
+        let motors = Arc::new(DashMap::new());
+        motors.insert(1, motor);
+        Connection { motors, message_tx: None, is_stopped: Arc::new(AtomicBool::new(false)) }
+    }
+
+    #[test]
+    fn holding_registers_round_trip() {
+        let protocol = connection().motors.get(&1).unwrap().protocol;
+        assert_eq!(protocol_from_holding_registers(&holding_registers(&protocol), Protocol::default()), Ok(protocol));
+    }
+
+    #[test]
+    fn written_protocol_replaces_the_step_protocol() {
+        let connection = connection();
+        connection.write_registers(0, &[60]).unwrap();
+        assert!(!connection.motors.get(&1).unwrap().is_step_protocol);
+        connection.motors.get_mut(&1).unwrap().is_step_protocol = true;
+        connection.write_registers(0, &[120]).unwrap();
+        let motor = connection.motors.get(&1).unwrap();
+        assert!(!motor.is_step_protocol);
+        assert_eq!(motor.protocol.rotation.rpm, 120);
+    }
+
+    #[test]
+    fn invalid_protocol_is_rejected() {
+        let connection = connection();
+        assert_eq!(connection.write_registers(0, &[MAX_RPM as u16 + 1]), Err(Exception::IllegalDataValue));
+        let motor = connection.motors.get(&1).unwrap();
+        assert!(motor.is_step_protocol);
+        assert_eq!(motor.protocol.rotation.rpm, 60);
+    }
+}
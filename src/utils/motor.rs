@@ -1,34 +1,52 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::thread;
-use std::time::Instant;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, Error};
+use anyhow::anyhow;
 use chrono::Local;
-use egui_toast::ToastKind;
+use dashmap::DashMap;
 use fugit::TimerInstantU64;
 use parking_lot::Mutex;
+use serde_json::{json, Value};
 
-use crate::app::{MAX_ACCELERATION, MAX_DURATION_MS, MAX_POINTS_GRAPHS};
-use crate::utils::enums::StepperState;
+use crate::utils::constants::{MAX_POINTS_GRAPHS, STATUS_QUERY_TIMEOUT_MS};
+use crate::utils::enums::{FrameFormat, MessageKind, StepperState};
+use crate::utils::errors::{MotorError, SerialError};
+#[cfg(feature = "gui")]
 use crate::utils::frame_history::FrameHistory;
 use crate::utils::graph::Graph;
+use crate::utils::protocol_file::{ProtocolDocument, ProtocolMetadata};
 use crate::utils::protocols::Protocol;
-use crate::utils::serial::Serial;
-use crate::utils::structs::{Message, StepsCycle, TimersAndPhases};
+use crate::utils::serial::{Acknowledgement, Serial};
+use crate::utils::status::MotorStatus;
+use crate::utils::step_protocol::StepProtocol;
+use crate::utils::structs::{Message, SerialSettings, StepsCycle, TimersAndPhases};
+use crate::utils::timeline::ProtocolTimeline;
+use crate::utils::validation::ValidationIssue;
 
 pub struct Motor {
     pub name: String,
     pub is_running: Arc<AtomicBool>,
     pub protocol: Protocol,
+    /// Name, author and description saved with the protocol.
+    pub protocol_metadata: ProtocolMetadata,
+    /// Run instead of `protocol` when `is_step_protocol` is set.
+    pub step_protocol: StepProtocol,
+    pub is_step_protocol: bool,
     pub serial: Serial,
     pub graph: Graph,
     pub timers_and_phases: Arc<Mutex<TimersAndPhases>>,
     pub steps_per_cycle: StepsCycle,
+    #[cfg(feature = "gui")]
     pub frame_hisory: FrameHistory,
     pub angle_rotation: f32,
     pub angle_agitation: f32,
+    /// Set when the motor was reattached to a run started before the connection, until the tab refreshes its durations.
+    pub is_reattached: bool,
+    /// Set when the protocol was changed outside of the tab, e.g. through the HTTP API, until the tab refreshes its durations.
+    pub is_protocol_updated: bool,
 }
 
 impl Default for Motor {
@@ -37,43 +55,112 @@ impl Default for Motor {
             name: String::from(""),
             is_running: Arc::new(AtomicBool::new(false)),
             protocol: Protocol::default(),
+            protocol_metadata: ProtocolMetadata::default(),
+            step_protocol: StepProtocol::default(),
+            is_step_protocol: false,
             serial: Serial::default(),
             graph: Graph::default(),
             timers_and_phases: Arc::new(Mutex::new(TimersAndPhases::default())),
             steps_per_cycle: StepsCycle::default(),
+            #[cfg(feature = "gui")]
             frame_hisory: FrameHistory::default(),
             angle_rotation: 0.0,
             angle_agitation: 0.0,
+            is_reattached: false,
+            is_protocol_updated: false,
         }
     }
 }
 
 impl Motor {
-    pub fn new(serial_port: String, motor_name: String, already_connected_ports: Arc<Mutex<Vec<String>>>) -> Result<Self, Error> {
-        let serial = Serial::new(&serial_port, already_connected_ports)?;
-        Ok(Self {
+    pub fn new(serial_port: String, motor_name: String, settings: SerialSettings, already_connected_ports: Arc<Mutex<Vec<String>>>) -> Result<Self, MotorError> {
+        let serial = Serial::new(&serial_port, settings, already_connected_ports)?;
+        // Only the framed firmware answers the status query, the others would delay every connection by the timeout.
+        let status = if serial.settings.frame_format == FrameFormat::Framed {
+            serial.query_status(STATUS_QUERY_TIMEOUT_MS.min(serial.settings.timeout_ms)).unwrap_or_else(|err| {
+                tracing::warn!("{} - Unable to read the status of the motor: {}", motor_name, err);
+                None
+            })
+        } else {
+            None
+        };
+        let mut motor = Self {
             name: motor_name,
             is_running: Arc::new(AtomicBool::new(false)),
             protocol: Protocol::default(),
+            protocol_metadata: ProtocolMetadata::default(),
+            step_protocol: StepProtocol::default(),
+            is_step_protocol: false,
             serial,
             graph: Graph::default(),
             timers_and_phases: Arc::new(Mutex::new(TimersAndPhases::default())),
             steps_per_cycle: StepsCycle::default(),
+            #[cfg(feature = "gui")]
             frame_hisory: FrameHistory::default(),
             angle_rotation: 0.0,
             angle_agitation: 0.0,
-        })
+            is_reattached: false,
+            is_protocol_updated: false,
+        };
+        if let Some(status) = status.filter(|status| status.is_running) {
+            motor.reattach(status);
+        }
+        Ok(motor)
     }
 
-    pub fn new_with_already_loaded_protocol(serial_port: String, motor_name: String, already_connected_ports: Arc<Mutex<Vec<String>>>, protocol: Protocol, graph: Graph, steps_per_cycle: StepsCycle) -> Result<Self, Error> {
-        let mut motor = Self::new(serial_port, motor_name, already_connected_ports)?;
-        motor.protocol = protocol;
+    pub fn new_with_already_loaded_protocol(serial_port: String, motor_name: String, settings: SerialSettings, already_connected_ports: Arc<Mutex<Vec<String>>>, protocol: Protocol, graph: Graph, steps_per_cycle: StepsCycle) -> Result<Self, MotorError> {
+        let mut motor = Self::new(serial_port, motor_name, settings, already_connected_ports)?;
         motor.graph = graph;
         motor.steps_per_cycle = steps_per_cycle;
-        motor.calculate_expected_end_date();
+        if motor.is_reattached {
+            // Keep the protocol of the ongoing run.
+            motor.generate_graph_rotation();
+            motor.generate_graph_agitation();
+        } else {
+            motor.protocol = protocol;
+            motor.calculate_expected_end_date();
+        }
         Ok(motor)
     }
 
+    /// Rebuild the timers, the expected end date and the graphs of a run started before the connection.
+    /// The serial port still has to be listened to.
+    pub fn reattach(&mut self, status: MotorStatus) {
+        let now = Instant::now();
+        let instant_before = |elapsed_ms: u64| now.checked_sub(Duration::from_millis(elapsed_ms)).unwrap_or(now);
+        self.protocol = status.protocol;
+        // A step protocol is known by its phases. The status carries it only if a fixed protocol can express it,
+        // the duration of the others being unknown.
+        self.is_step_protocol = matches!(status.main_phase, StepperState::StartStep(_));
+        if self.is_step_protocol {
+            self.step_protocol = if status.protocol == Protocol::default() { StepProtocol::default() } else { StepProtocol::from(status.protocol) };
+        }
+        let duration_ms = self.get_global_duration_ms();
+        self.is_running.store(true, Ordering::SeqCst);
+        {
+            let mut lock = self.timers_and_phases.lock();
+            lock.global_start_time = Some(instant_before(status.elapsed_global_ms));
+            lock.global_stop_time_ms = None;
+            lock.main_phase = status.main_phase;
+            lock.main_phase_start_time = Some(instant_before(status.elapsed_main_phase_ms));
+            lock.sub_phase = status.sub_phase;
+            lock.sub_phase_start_time = Some(instant_before(status.elapsed_sub_phase_ms));
+            lock.rotation_direction = status.rotation_direction;
+            lock.agitation_direction = status.agitation_direction;
+            let remaining_ms = duration_ms.saturating_sub(status.elapsed_global_ms);
+            lock.expected_end_date = (duration_ms != 0).then(|| Local::now() + chrono::Duration::milliseconds(remaining_ms as i64));
+        }
+        self.generate_graph_rotation();
+        self.generate_graph_agitation();
+        self.is_reattached = true;
+        tracing::info!("Motor {} reattached to an ongoing run.", self.name);
+        if self.is_step_protocol {
+            tracing::info!("{} - {}", self.name, self.step_protocol);
+        } else {
+            tracing::info!("{} - {}", self.name, self.protocol);
+        }
+    }
+
     pub fn get_is_connected(&self) -> bool {
         self.serial.get_is_connected()
     }
@@ -82,8 +169,55 @@ impl Motor {
         self.is_running.load(Ordering::SeqCst)
     }
 
+    /// Duration of the protocol that runs at the next start.
+    pub fn get_global_duration_ms(&self) -> u64 {
+        if self.is_step_protocol {
+            self.step_protocol.duration_ms()
+        } else {
+            ProtocolTimeline::new(&self.protocol).duration_ms
+        }
+    }
+
+    /// Phases, elapsed time, expected end date and fault of the motor, for the programs controlling it.
+    pub fn status_json(&self) -> Value {
+        let is_running = self.get_is_running();
+        let timers_and_phases = self.timers_and_phases.lock();
+        let main_phase = timers_and_phases.main_phase;
+        let fault = main_phase.is_fault().then(|| json!({ "state": format!("{:?}", main_phase), "description": main_phase.to_string() }));
+        json!({
+            "name": self.name,
+            "port": self.serial.port_name,
+            "is_connected": self.get_is_connected(),
+            "is_running": is_running,
+            "main_phase": format!("{:?}", main_phase),
+            "sub_phase": format!("{:?}", timers_and_phases.sub_phase),
+            "elapsed_ms": timers_and_phases.get_run_elapsed_ms(),
+            // Unknown for a step protocol reattached without its steps.
+            "duration_ms": Some(self.get_global_duration_ms()).filter(|duration_ms| *duration_ms != 0),
+            "expected_end_date": timers_and_phases.expected_end_date.filter(|_| is_running).map(|date| date.to_rfc3339()),
+            "fault": fault,
+        })
+    }
+
+    /// Speed read on the graph of the ongoing phase, as shown by the rotating tubes of the tab. 0 during the pauses.
+    pub fn current_rpm(&self) -> u32 {
+        if !self.get_is_running() {
+            return 0;
+        }
+        let (main_phase, sub_phase, elapsed_ms) = {
+            let lock = self.timers_and_phases.lock();
+            (lock.main_phase, lock.sub_phase, lock.get_elapsed_time_since_sub_phase_start_as_millis())
+        };
+        let points = match main_phase {
+            StepperState::StartRotation if sub_phase != StepperState::StartPausePreAgitation && sub_phase != StepperState::StartPauseRotation => &self.graph.rotation_points_sec_rpm,
+            StepperState::StartAgitation if sub_phase != StepperState::StartPausePostAgitation && sub_phase != StepperState::StartPauseAgitation => &self.graph.agitation_points_sec_rpm,
+            _ => return 0,
+        };
+        points.lock().iter().find(|point| point[0] * 1000.0 >= elapsed_ms as f64).map_or(0, |point| point[1].round() as u32)
+    }
+
     pub fn calculate_expected_end_date(&self) {
-        let global_duration = self.protocol.global_duration_ms;
+        let global_duration = self.get_global_duration_ms();
         if global_duration == 0 {
             self.timers_and_phases.lock().expected_end_date = None;
             return;
@@ -95,17 +229,45 @@ impl Motor {
 
     pub fn disconnect(&self, message_tx: Option<Sender<Message>>) {
         if self.is_running.load(Ordering::SeqCst) {
-            self.stop_motor(message_tx.clone());
+            self.stop_motor(message_tx.clone()).join().ok();
         }
         self.serial.disconnect();
-        let message = Message::new(ToastKind::Info, &format!("Disconnected from {}", self.serial.port_name), None, Some(self.name.clone()), 3, false);
+        let message = Message::new(MessageKind::Info, &format!("Disconnected from {}", self.serial.port_name), None, Some(self.name.clone()), 3, false);
         if let Some(message_tx) = message_tx {
             message_tx.send(message).unwrap();
         }
         // self.serial = Serial::default();
     }
 
+    /// Send `stop` right away and disconnect once the firmware acknowledged it, without waiting.
+    /// Stopping every motor this way sends all the `stop` before any acknowledgement is awaited.
+    pub fn emergency_stop(&self, message_tx: Option<Sender<Message>>) -> JoinHandle<()> {
+        let stop = self.stop_motor(message_tx.clone());
+        let serial = self.serial.clone();
+        let motor_name = self.name.clone();
+        thread::spawn(move || {
+            stop.join().ok();
+            serial.disconnect();
+            let message = Message::new(MessageKind::Info, &format!("Disconnected from {}", serial.port_name), None, Some(motor_name), 3, false);
+            if let Some(message_tx) = message_tx {
+                message_tx.send(message).ok();
+            }
+        })
+    }
+
     pub fn start_motor(&mut self, message_tx: Option<Sender<Message>>) {
+        if let Some(pending) = self.prepare_start(message_tx.clone()) {
+            let result = pending.send();
+            self.finish_start(result, message_tx);
+        }
+    }
+
+    /// Check the protocol to run and drop the events of the previous run. Returns None, after sending the reason, if the protocol cannot run.
+    /// The returned protocol is sent without borrowing the motor, see `start_motor_in`.
+    pub fn prepare_start(&mut self, message_tx: Option<Sender<Message>>) -> Option<PendingStart> {
+        if self.is_step_protocol {
+            return self.prepare_step_protocol(message_tx);
+        }
         let min_rotation_duration = self.protocol.rotation.get_min_duration();
         let min_agitation_duration = self.protocol.agitation.get_min_duration();
         if min_rotation_duration == 0 {
@@ -120,16 +282,69 @@ impl Motor {
         if self.protocol.agitation_duration_ms == 0 {
             self.protocol.pause_post_agitation_ms = 0;
         }
-        let duration_without_pause = self.protocol.get_duration_without_pause();
-        if duration_without_pause == 0 {
-            self.protocol.global_duration_ms = 0;
-            let message = Message::new(ToastKind::Error, "The duration of the protocol is 0. Please check the durations.", Some(anyhow!("0 duration")), Some(self.name.clone()), 3, false);
+        let (errors, warnings): (Vec<ValidationIssue>, Vec<ValidationIssue>) = self.protocol.validate_run().into_iter().partition(|issue| issue.is_error());
+        if !errors.is_empty() {
+            let message = Message::new(MessageKind::Error, "The protocol is invalid. Please check the highlighted fields.", Some(anyhow!(MotorError::InvalidProtocol(errors))), Some(self.name.clone()), 5, false);
+            if let Some(message_tx) = message_tx {
+                message_tx.send(message).unwrap();
+            }
+            return None;
+        }
+        if let Some(message_tx) = message_tx.as_ref() {
+            for warning in warnings {
+                message_tx.send(Message::new(MessageKind::Warning, &warning.to_string(), None, Some(self.name.clone()), 5, false)).unwrap();
+            }
+        }
+        self.serial.clear_events();
+        Some(PendingStart { serial: self.serial.clone(), protocol: StartedProtocol::Protocol(self.protocol) })
+    }
+
+    fn prepare_step_protocol(&mut self, message_tx: Option<Sender<Message>>) -> Option<PendingStart> {
+        let (errors, warnings): (Vec<ValidationIssue>, Vec<ValidationIssue>) = self.step_protocol.validate().into_iter().partition(|issue| issue.is_error());
+        let error = if !errors.is_empty() {
+            Some(("The step protocol is invalid. Please check the steps and the loops.", anyhow!(MotorError::InvalidProtocol(errors))))
+        } else if self.serial.settings.frame_format != FrameFormat::Framed {
+            Some(("Step protocols need the framed format. Please change it in the serial settings.", anyhow!("Invalid step protocol")))
+        } else {
+            None
+        };
+        if let Some((error, reason)) = error {
+            let message = Message::new(MessageKind::Error, error, Some(reason), Some(self.name.clone()), 3, false);
+            if let Some(message_tx) = message_tx {
+                message_tx.send(message).unwrap();
+            }
+            return None;
+        }
+        if let Some(message_tx) = message_tx.as_ref() {
+            for warning in warnings {
+                message_tx.send(Message::new(MessageKind::Warning, &warning.to_string(), None, Some(self.name.clone()), 5, false)).unwrap();
+            }
+        }
+        self.serial.clear_events();
+        Some(PendingStart { serial: self.serial.clone(), protocol: StartedProtocol::StepProtocol(self.step_protocol.clone()) })
+    }
+
+    /// The motor is running only once the firmware acknowledged the protocol.
+    pub fn finish_start(&mut self, result: Result<(), SerialError>, message_tx: Option<Sender<Message>>) {
+        if let Err(err) = result {
+            let message = Message::new(MessageKind::Error, "The protocol was not started. Please run it again.", Some(anyhow!(err)), Some(self.name.clone()), 5, false);
             if let Some(message_tx) = message_tx {
                 message_tx.send(message).unwrap();
             }
             return;
         }
+        self.set_started(message_tx);
+        if self.is_step_protocol {
+            tracing::info!("{} - {}", self.name, self.step_protocol);
+        } else {
+            tracing::info!("{} - {}", self.name, self.protocol);
+        }
+    }
+
+    /// Timers and listener of a run acknowledged by the firmware.
+    fn set_started(&mut self, message_tx: Option<Sender<Message>>) {
         self.is_running.store(true, Ordering::SeqCst);
+        self.serial.metrics.run_started();
         {
             let mut lock = self.timers_and_phases.lock();
             lock.global_start_time = Some(Instant::now());
@@ -140,15 +355,25 @@ impl Motor {
         self.angle_rotation = 0.0;
         self.angle_agitation = 0.0;
         self.serial.listen_to_serial_port(self.name.clone(), &self.is_running, &self.timers_and_phases, message_tx);
-        self.serial.send_bytes(&self.protocol.protocol_as_bytes());
         self.calculate_expected_end_date();
         tracing::info!("Motor {} started.", self.name);
-        tracing::info!("{} - {}",self.name, self.protocol);
     }
 
-    pub fn stop_motor(&self, message_tx: Option<Sender<Message>>) {
+    /// Stop the timers at once and send `stop`. The acknowledgement is awaited by the returned thread,
+    /// which reports a motor that may not have stopped.
+    pub fn stop_motor(&self, message_tx: Option<Sender<Message>>) -> JoinHandle<()> {
         self.is_running.store(false, Ordering::SeqCst);
-        self.serial.send_bytes(b"stop");
+        let serial = self.serial.clone();
+        let motor_name = self.name.clone();
+        let ack_message_tx = message_tx.clone();
+        let stop = thread::spawn(move || {
+            if let Err(err) = serial.send_command(b"stop", Acknowledgement::CommandReceived) {
+                let message = Message::new(MessageKind::Error, &format!("{} may not have stopped.", motor_name), Some(anyhow!(err)), Some(motor_name.clone()), 5, false);
+                if let Some(message_tx) = ack_message_tx {
+                    message_tx.send(message).ok();
+                }
+            }
+        });
         {
             let mut lock = self.timers_and_phases.lock();
             lock.set_global_stop_time_stopped();
@@ -157,10 +382,11 @@ impl Motor {
             lock.sub_phase = StepperState::default();
             lock.main_phase = StepperState::default();
         }
-        let message = Message::new(ToastKind::Info, &format!("{} has been manually stopped.", self.name), None, None, 3, false);
+        let message = Message::new(MessageKind::Info, &format!("{} has been manually stopped.", self.name), None, None, 3, false);
         if let Some(message_tx) = message_tx {
             message_tx.send(message).unwrap();
         }
+        stop
     }
 
     pub fn get_revolutions_per_rotation_cycle(&self) -> f64 {
@@ -171,32 +397,48 @@ impl Motor {
         self.steps_per_cycle.steps_per_direction_cycle_agitation.load(Ordering::SeqCst) as f64 / (self.protocol.agitation.step_mode.get_multiplier() as f64 * 200.0)
     }
 
-    pub fn import_protocol(&mut self, protocol: Protocol) -> Result<(), Error> {
-        // Check if the protocol is valid
-        if protocol.rotation.acceleration == 0 || protocol.agitation.acceleration == 0 {
-            bail!("The acceleration of the rotation or agitation is 0");
-        }
-        if protocol.rotation.acceleration > MAX_ACCELERATION || protocol.agitation.acceleration > MAX_ACCELERATION {
-            bail!("The acceleration of the rotation or agitation is too high");
-        }
-        if protocol.rotation.rpm > protocol.rotation.max_rpm_for_stepmode() || protocol.agitation.rpm > protocol.agitation.max_rpm_for_stepmode() {
-            bail!("The rpm of the rotation or agitation is higher than the max rpm");
-        }
-        if protocol.get_duration_without_pause() == 0 {
-            self.protocol.global_duration_ms = 0;
-        }
-        if protocol.rotation.duration_of_one_direction_cycle_ms > MAX_DURATION_MS || protocol.agitation.duration_of_one_direction_cycle_ms > MAX_DURATION_MS
-            || protocol.rotation.pause_before_direction_change_ms > MAX_DURATION_MS || protocol.agitation.pause_before_direction_change_ms > MAX_DURATION_MS
-            || protocol.global_duration_ms > MAX_DURATION_MS || protocol.rotation_duration_ms > MAX_DURATION_MS || protocol.agitation_duration_ms > MAX_DURATION_MS
-            || protocol.pause_pre_agitation_ms > MAX_DURATION_MS || protocol.pause_post_agitation_ms > MAX_DURATION_MS
-        {
-            bail!("Some duration is too high");
+    /// Import the protocol if it has no error. Warnings are only logged.
+    pub fn import_protocol(&mut self, protocol: Protocol) -> Result<(), MotorError> {
+        let (errors, warnings): (Vec<ValidationIssue>, Vec<ValidationIssue>) = protocol.validate().into_iter().partition(|issue| issue.is_error());
+        if !errors.is_empty() {
+            return Err(MotorError::InvalidProtocol(errors));
         }
-
+        warnings.iter().for_each(|warning| tracing::warn!("{} - {}", self.name, warning));
         self.protocol = protocol;
         Ok(())
     }
 
+    /// Protocol, metadata and step protocol to save in a file. Sets the creation date on the first save.
+    pub fn protocol_document(&mut self) -> ProtocolDocument {
+        self.protocol_metadata.created_at.get_or_insert_with(Local::now);
+        let step_protocol = self.is_step_protocol.then(|| self.step_protocol.clone());
+        ProtocolDocument::new(self.protocol_metadata.clone(), self.protocol, step_protocol)
+    }
+
+    /// Import the protocol of a file with its metadata and step protocol, if none of them has an error.
+    pub fn import_document(&mut self, document: &ProtocolDocument) -> Result<(), MotorError> {
+        if let Some(step_protocol) = &document.step_protocol {
+            let errors: Vec<ValidationIssue> = step_protocol.validate().into_iter().filter(|issue| issue.is_error()).collect();
+            if !errors.is_empty() {
+                return Err(MotorError::InvalidProtocol(errors));
+            }
+        }
+        self.import_protocol(document.protocol)?;
+        self.restore_document(document);
+        Ok(())
+    }
+
+    /// Put back the protocol of a file with its metadata and step protocol as they were saved, even if they have errors.
+    /// They are validated when the motor is started.
+    pub fn restore_document(&mut self, document: &ProtocolDocument) {
+        self.protocol = document.protocol;
+        self.protocol_metadata = document.metadata.clone();
+        self.is_step_protocol = document.step_protocol.is_some();
+        if let Some(step_protocol) = &document.step_protocol {
+            self.step_protocol = step_protocol.clone();
+        }
+    }
+
     pub fn generate_graph_rotation(&self) {
         let points_rotation = self.graph.rotation_points_sec_rpm.clone();
         let rotation = self.protocol.rotation;
@@ -205,10 +447,16 @@ impl Motor {
         let index_thead_initial = index_thread.load(Ordering::SeqCst);
         let steps_rotation = self.steps_per_cycle.steps_per_direction_cycle_rotation.clone();
         let is_generating_rotation_graph = self.graph.is_generating_rotation_graph.clone();
+        let kinematics_rotation = self.graph.rotation_kinematics.clone();
         // Rotation
         thread::spawn(move || {
             is_generating_rotation_graph.store(true, Ordering::SeqCst);
             points_rotation.lock().clear();
+            let kinematics = rotation.kinematics();
+            if index_thead_initial != index_thread.load(Ordering::SeqCst) {
+                return;
+            }
+            *kinematics_rotation.lock() = kinematics;
             let mut stepgen = rotation.create_stepgen();
             let duration_ms = rotation.duration_of_one_direction_cycle_ms;
             let point_threshold_us = duration_ms * 1000 / 100; // 100 points per cycle while rpm is constant
@@ -248,10 +496,16 @@ impl Motor {
         let index_thead_initial = index_thread.load(Ordering::SeqCst);
         let steps_agitation = self.steps_per_cycle.steps_per_direction_cycle_agitation.clone();
         let is_generating_agitation_graph = self.graph.is_generating_agitation_graph.clone();
+        let kinematics_agitation = self.graph.agitation_kinematics.clone();
         // Agitation
         thread::spawn(move || {
             is_generating_agitation_graph.store(true, Ordering::SeqCst);
             points_agitation.lock().clear();
+            let kinematics = agitation.kinematics();
+            if index_thead_initial != index_thread.load(Ordering::SeqCst) {
+                return;
+            }
+            *kinematics_agitation.lock() = kinematics;
             let mut stepgen = agitation.create_stepgen();
             let duration_ms = agitation.duration_of_one_direction_cycle_ms;
             let point_threshold_us = duration_ms * 1000 / 100; // 100 points per cycle while rpm is constant
@@ -282,4 +536,106 @@ impl Motor {
             is_generating_agitation_graph.store(false, Ordering::SeqCst);
         });
     }
-}
\ No newline at end of file
+}
+
+/// Protocol checked by `Motor::prepare_start`, to send to the firmware.
+enum StartedProtocol {
+    Protocol(Protocol),
+    StepProtocol(StepProtocol),
+}
+
+/// Protocol of a motor about to start, holding its own handle of the serial port.
+pub struct PendingStart {
+    serial: Serial,
+    protocol: StartedProtocol,
+}
+
+impl PendingStart {
+    /// Send the protocol and wait for the firmware to acknowledge it.
+    pub fn send(&self) -> Result<(), SerialError> {
+        match &self.protocol {
+            StartedProtocol::Protocol(protocol) => self.serial.send_protocol(protocol),
+            StartedProtocol::StepProtocol(step_protocol) => self.serial.send_step_protocol(step_protocol),
+        }
+    }
+}
+
+/// Start the motor of a tab without holding it while the firmware acknowledges the protocol,
+/// so that the app and the other clients of the map are not blocked meanwhile.
+/// The durations zeroed by the start are flagged for the tab.
+pub fn start_motor_in(motors: &DashMap<usize, Motor>, tab: usize, message_tx: Option<Sender<Message>>) {
+    let Some(pending) = motors.get_mut(&tab).and_then(|mut motor| {
+        motor.is_protocol_updated = true;
+        motor.prepare_start(message_tx.clone())
+    }) else {
+        return;
+    };
+    let result = pending.send();
+    if let Some(mut motor) = motors.get_mut(&tab) {
+        motor.finish_start(result, message_tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::constants::MAX_RPM;
+    use crate::utils::protocols::Rotation;
+    use crate::utils::step_protocol::{Step, StepBlock};
+
+    #[test]
+    fn default_protocol_is_imported_but_not_started() {
+        let mut motor = Motor::default();
+        let protocol = Protocol { global_duration_ms: 60_000, ..Default::default() };
+        motor.import_protocol(protocol).unwrap();
+        assert_eq!(motor.protocol, protocol);
+        motor.import_document(&ProtocolDocument::new(ProtocolMetadata::default(), Protocol::default(), None)).unwrap();
+        assert_eq!(motor.protocol, Protocol::default());
+        let (message_tx, message_rx) = std::sync::mpsc::channel();
+        assert!(motor.prepare_start(Some(message_tx)).is_none());
+        assert_eq!(message_rx.try_recv().unwrap().kind, MessageKind::Error);
+    }
+
+    #[test]
+    fn restored_document_is_not_validated() {
+        let mut protocol = Protocol::default();
+        protocol.rotation.rpm = MAX_RPM + 1;
+        protocol.global_duration_ms = 10_000;
+        let document = ProtocolDocument::new(ProtocolMetadata::default(), protocol, Some(StepProtocol::default()));
+        let mut motor = Motor::default();
+        assert!(matches!(motor.import_document(&document), Err(MotorError::InvalidProtocol(_))));
+        assert_eq!(motor.protocol, Protocol::default());
+        assert!(!motor.is_step_protocol);
+        motor.restore_document(&document);
+        assert_eq!(motor.protocol, protocol);
+        assert!(motor.is_step_protocol);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn step_run_is_reattached_with_its_duration_if_known() {
+        let mut simulator = crate::utils::simulator::FirmwareSimulator::new(None).unwrap();
+        let port_name = simulator.slave_path().to_string_lossy().to_string();
+        thread::spawn(move || simulator.run());
+        let settings = SerialSettings { frame_format: FrameFormat::Framed, ..Default::default() };
+        let rotation = Rotation { rpm: 60, acceleration: 100, duration_of_one_direction_cycle_ms: 1_000, ..Default::default() };
+        let steps = vec![Step::Rotation { rotation, duration_ms: 10_000 }, Step::Pause { duration_ms: 1_000 }];
+        let step_protocol = StepProtocol { blocks: vec![StepBlock { steps, loop_count: 2 }], loop_count: 1, global_duration_ms: 0 };
+        let protocol = Protocol { rotation, rotation_duration_ms: 10_000, agitation: rotation, agitation_duration_ms: 5_000, global_duration_ms: 60_000, ..Default::default() };
+        // The status carries the second one only, a fixed protocol expressing it.
+        for (step_protocol, reattached_step_protocol, duration_ms) in [(step_protocol, StepProtocol::default(), None), (protocol.into(), protocol.into(), Some(60_000))] {
+            let serial = Serial::new(&port_name, settings, Arc::new(Mutex::new(vec![]))).unwrap();
+            serial.send_step_protocol(&step_protocol).unwrap();
+            serial.close_io();
+            let motor = Motor::new(port_name.clone(), "Motor".into(), settings, Arc::new(Mutex::new(vec![]))).unwrap();
+            assert!(motor.is_reattached);
+            assert!(motor.get_is_running());
+            assert!(motor.is_step_protocol);
+            assert_eq!(motor.step_protocol, reattached_step_protocol);
+            assert_eq!(motor.timers_and_phases.lock().main_phase, StepperState::StartStep(0));
+            assert_eq!(motor.status_json()["duration_ms"], json!(duration_ms));
+            assert_eq!(motor.timers_and_phases.lock().expected_end_date.is_some(), duration_ms.is_some());
+            motor.disconnect(None);
+        }
+    }
+}
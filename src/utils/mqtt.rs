@@ -0,0 +1,379 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::enums::MessageKind;
+use crate::utils::events::Subscriptions;
+use crate::utils::motor::{self, Motor};
+use crate::utils::structs::Message;
+
+/// Requests queued for the broker. Publications are dropped when the queue is full, e.g. while the broker is down.
+const REQUEST_CAPACITY: usize = 64;
+/// Time between two checks of the events of the motors.
+const POLL_INTERVAL_MS: u64 = 250;
+const STATUS_INTERVAL_MS: u64 = 1000;
+/// Wait before connecting again to a broker that refused or lost the connection.
+const RETRY_DELAY_MS: u64 = 2000;
+const KEEP_ALIVE_S: u64 = 10;
+
+/// Settings of the MQTT client, saved with the app.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub is_enabled: bool,
+    /// `localhost` for a broker running on the same computer, e.g. Mosquitto.
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// No authentication when empty.
+    pub username: String,
+    pub password: String,
+    pub topic_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "cell_spinner".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "cell_spinner".to_string(),
+        }
+    }
+}
+
+impl MqttSettings {
+    /// Settings to save, without the password. A client needing one is saved disabled.
+    pub fn without_secrets(&self) -> Self {
+        Self {
+            is_enabled: self.is_enabled && self.password.is_empty(),
+            password: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Client publishing the states of the motors to an MQTT broker and running the commands received from it.
+/// `{prefix}` is `MqttSettings::topic_prefix` and `{id}` the number of the tab of the motor:
+/// - `{prefix}/online`: `true` while connected, set to `false` by the broker when the app is gone (retained)
+/// - `{prefix}/motors/{id}/state`: every state reported by the firmware, as printed by the command-line runner (retained)
+/// - `{prefix}/motors/{id}/status`: phases, elapsed time, expected end date and fault, every second (retained)
+/// - `{prefix}/motors/{id}/fault`: the fault states only
+/// - `{prefix}/motors/{id}/command` and `{prefix}/command`: `start` or `stop` one motor or every connected motor
+///
+/// The client disconnects when dropped.
+pub struct MqttBridge {
+    client: Client,
+    topics: Topics,
+    is_stopped: Arc<AtomicBool>,
+    publisher: Option<JoinHandle<()>>,
+}
+
+impl MqttBridge {
+    /// Connect in the background, the connection errors being sent to `message_tx` like the ones of the motors.
+    pub fn start(settings: &MqttSettings, motors: Arc<DashMap<usize, Motor>>, message_tx: Option<Sender<Message>>) -> Self {
+        let topics = Topics { prefix: settings.topic_prefix.trim_end_matches('/').to_string() };
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options.set_keep_alive(Duration::from_secs(KEEP_ALIVE_S));
+        options.set_last_will(LastWill::new(topics.online(), "false", QoS::AtLeastOnce, true));
+        if !settings.username.is_empty() {
+            options.set_credentials(&settings.username, &settings.password);
+        }
+        let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let listener = Listener {
+            client: client.clone(),
+            topics: topics.clone(),
+            broker: format!("{}:{}", settings.host, settings.port),
+            motors: motors.clone(),
+            message_tx,
+            is_stopped: is_stopped.clone(),
+        };
+        // Not joined on drop: connecting to an unreachable broker can block it for several seconds. It ends on its own.
+        thread::spawn(move || listener.run(connection));
+        let publisher = Publisher { client: client.clone(), topics: topics.clone(), motors, is_stopped: is_stopped.clone() };
+        let publisher = thread::spawn(move || publisher.run());
+        tracing::info!("MQTT client started for {}:{}", settings.host, settings.port);
+        Self { client, topics, is_stopped, publisher: Some(publisher) }
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        self.client.try_publish(self.topics.online(), QoS::AtLeastOnce, true, "false").ok();
+        self.client.try_disconnect().ok();
+        if let Some(publisher) = self.publisher.take() {
+            publisher.join().ok();
+        }
+        tracing::info!("MQTT client stopped.");
+    }
+}
+
+#[derive(Clone)]
+struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    fn online(&self) -> String {
+        format!("{}/online", self.prefix)
+    }
+
+    fn command(&self) -> String {
+        format!("{}/command", self.prefix)
+    }
+
+    fn motor(&self, tab: usize, topic: &str) -> String {
+        format!("{}/motors/{}/{}", self.prefix, tab, topic)
+    }
+
+    /// Commands of every motor.
+    fn motor_commands(&self) -> String {
+        format!("{}/motors/+/command", self.prefix)
+    }
+
+    /// Tab of a `{prefix}/motors/{id}/command` topic.
+    fn tab_of_command(&self, topic: &str) -> Option<usize> {
+        topic.strip_prefix(&format!("{}/motors/", self.prefix))?.strip_suffix("/command")?.parse().ok()
+    }
+}
+
+/// Connection to the broker and commands received from it.
+struct Listener {
+    client: Client,
+    topics: Topics,
+    broker: String,
+    motors: Arc<DashMap<usize, Motor>>,
+    message_tx: Option<Sender<Message>>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl Listener {
+    fn run(mut self, mut connection: Connection) {
+        // Only the first error of a series is shown, the client retrying until the broker is back.
+        let mut is_failing = false;
+        for notification in connection.iter() {
+            // Once stopped, the connection is only driven until the last publication and the disconnection requested on drop are sent.
+            if self.is_stopped.load(Ordering::SeqCst) {
+                match notification {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    _ => continue,
+                }
+            }
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    // The subscriptions do not survive a reconnection.
+                    self.client.try_subscribe(self.topics.command(), QoS::AtLeastOnce).ok();
+                    self.client.try_subscribe(self.topics.motor_commands(), QoS::AtLeastOnce).ok();
+                    self.client.try_publish(self.topics.online(), QoS::AtLeastOnce, true, "true").ok();
+                    self.send(Message::new(MessageKind::Success, &format!("Connected to the MQTT broker {}", self.broker), None, None, 3, false));
+                    is_failing = false;
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => self.run_command(&publish.topic, &String::from_utf8_lossy(&publish.payload)),
+                Ok(_) => {}
+                Err(err) => {
+                    if !is_failing {
+                        let message = Message::new(MessageKind::Error, &format!("Unable to reach the MQTT broker {}", self.broker), Some(anyhow!(err.to_string())), None, 5, false);
+                        self.send(message);
+                        is_failing = true;
+                    }
+                    tracing::warn!("MQTT - {}", err);
+                    thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                }
+            }
+        }
+    }
+
+    /// Same as the Run and STOP MOTOR buttons of the tabs.
+    fn run_command(&self, topic: &str, command: &str) {
+        let tabs: Vec<usize> = if topic == self.topics.command() {
+            self.motors.iter().map(|motor| *motor.key()).collect()
+        } else if let Some(tab) = self.topics.tab_of_command(topic) {
+            vec![tab]
+        } else {
+            return;
+        };
+        tracing::info!("MQTT - Command {:?} received on {}", command, topic);
+        match command.trim() {
+            "start" => tabs.iter().for_each(|tab| {
+                if self.motors.get(tab).map_or(false, |motor| motor.get_is_connected() && !motor.get_is_running()) {
+                    motor::start_motor_in(&self.motors, *tab, self.message_tx.clone());
+                }
+            }),
+            "stop" => tabs.iter().for_each(|tab| {
+                let Some(motor) = self.motors.get(tab) else { return };
+                if motor.get_is_running() {
+                    motor.stop_motor(self.message_tx.clone());
+                }
+            }),
+            _ => self.send(Message::new(MessageKind::Warning, &format!("Unknown MQTT command {:?} on {}", command, topic), None, None, 3, false)),
+        }
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(message_tx) = &self.message_tx {
+            message_tx.send(message).ok();
+        }
+    }
+}
+
+/// States and status of the motors sent to the broker.
+struct Publisher {
+    client: Client,
+    topics: Topics,
+    motors: Arc<DashMap<usize, Motor>>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl Publisher {
+    fn run(mut self) {
+        let mut subscriptions = Subscriptions::default();
+        let mut last_status: Option<Instant> = None;
+        while !self.is_stopped.load(Ordering::SeqCst) {
+            subscriptions.refresh(&self.motors);
+            for (tab, event) in subscriptions.try_iter() {
+                let payload = event.to_json().to_string();
+                if event.state.is_fault() {
+                    self.publish(self.topics.motor(tab, "fault"), false, payload.clone());
+                }
+                self.publish(self.topics.motor(tab, "state"), true, payload);
+            }
+            if last_status.map_or(true, |last_status| last_status.elapsed() >= Duration::from_millis(STATUS_INTERVAL_MS)) {
+                let statuses: Vec<(usize, String)> = self.motors.iter().map(|motor| (*motor.key(), motor.status_json().to_string())).collect();
+                for (tab, status) in statuses {
+                    self.publish(self.topics.motor(tab, "status"), true, status);
+                }
+                last_status = Some(Instant::now());
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    fn publish(&mut self, topic: String, retain: bool, payload: String) {
+        if let Err(err) = self.client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            tracing::debug!("MQTT - Publication dropped: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use parking_lot::Mutex;
+    use rumqttd::local::{LinkRx, LinkTx};
+    use rumqttd::{Broker, Config, Notification};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::utils::protocols::Rotation;
+    use crate::utils::structs::SerialSettings;
+
+    /// Broker on a free port of the loopback, with a link to publish the commands and read what the client publishes.
+    fn broker() -> (u16, LinkTx, LinkRx) {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config: Config = serde_json::from_value(json!({
+            "id": 0,
+            "router": { "max_connections": 10, "max_outgoing_packet_count": 200, "max_segment_size": 1024 * 1024, "max_segment_count": 10 },
+            "v4": {
+                "1": {
+                    "name": "v4-1",
+                    "listen": format!("127.0.0.1:{}", port),
+                    "next_connection_delay_ms": 1,
+                    "connections": { "connection_timeout_ms": 5000, "max_payload_size": 20480, "max_inflight_count": 100, "dynamic_filters": true },
+                },
+            },
+            // The broker always serves its console, on a free port too.
+            "console": { "listen": "127.0.0.1:0" },
+        })).unwrap();
+        let mut broker = Broker::new(config);
+        let (mut link_tx, link_rx) = broker.link("observer").unwrap();
+        thread::spawn(move || broker.start().unwrap());
+        link_tx.subscribe("#").unwrap();
+        (port, link_tx, link_rx)
+    }
+
+    /// Payload of the next publication on `topic` accepted by `predicate`.
+    fn wait_for(link_rx: &mut LinkRx, topic: &str, predicate: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(Some(Notification::Forward(forward))) = link_rx.recv_deadline(deadline) {
+                let payload = String::from_utf8_lossy(&forward.publish.payload).to_string();
+                if forward.publish.topic == topic.as_bytes() && predicate(&payload) {
+                    return payload;
+                }
+            }
+        }
+        panic!("Nothing published on {}", topic);
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn states_are_published_and_commands_are_run() {
+        use crate::utils::simulator::FirmwareSimulator;
+
+        let (port, mut link_tx, mut link_rx) = broker();
+        let mut simulator = FirmwareSimulator::new(None).unwrap();
+        let port_name = simulator.slave_path().to_string_lossy().to_string();
+        thread::spawn(move || simulator.run());
+        let mut motor = Motor::new(port_name.clone(), "Motor".into(), SerialSettings::default(), Arc::new(Mutex::new(vec![]))).unwrap();
+        motor.protocol.rotation = Rotation { rpm: 60, acceleration: 100, duration_of_one_direction_cycle_ms: 1_000, ..Default::default() };
+        motor.protocol.rotation_duration_ms = 60_000;
+        motor.protocol.global_duration_ms = 60_000;
+        let motors = Arc::new(DashMap::new());
+        motors.insert(1, motor);
+        let settings = MqttSettings { is_enabled: true, host: "127.0.0.1".into(), port, client_id: "cell_spinner_test".into(), ..Default::default() };
+        let bridge = MqttBridge::start(&settings, motors.clone(), None);
+        wait_for(&mut link_rx, "cell_spinner/online", |payload| payload == "true");
+        let status: Value = serde_json::from_str(&wait_for(&mut link_rx, "cell_spinner/motors/1/status", |_| true)).unwrap();
+        assert_eq!(status["name"], "Motor");
+        assert_eq!(status["is_running"], false);
+
+        link_tx.publish("cell_spinner/motors/1/command", "start").unwrap();
+        let state: Value = serde_json::from_str(&wait_for(&mut link_rx, "cell_spinner/motors/1/state", |_| true)).unwrap();
+        assert_eq!(state["motor"], "Motor");
+        assert_eq!(state["port"], port_name.as_str());
+        assert_eq!(state["state"], "StartRotation");
+        assert!(motors.get(&1).unwrap().get_is_running());
+        wait_for(&mut link_rx, "cell_spinner/motors/1/status", |payload| payload.contains(r#""is_running":true"#));
+
+        link_tx.publish("cell_spinner/command", "stop").unwrap();
+        wait_until(|| !motors.get(&1).unwrap().get_is_running());
+        link_tx.publish("cell_spinner/motors/1/command", "jump").unwrap();
+        link_tx.publish("cell_spinner/motors/2/command", "start").unwrap();
+        drop(bridge);
+        wait_for(&mut link_rx, "cell_spinner/online", |payload| payload == "false");
+        assert!(!motors.get(&1).unwrap().get_is_running());
+        motors.get(&1).unwrap().disconnect(None);
+    }
+
+    #[test]
+    fn password_is_not_saved() {
+        let settings = MqttSettings { is_enabled: true, username: "spinner".into(), password: "secret".into(), ..Default::default() };
+        let saved = settings.without_secrets();
+        assert!(saved.password.is_empty());
+        assert!(!saved.is_enabled);
+        assert_eq!(saved.username, "spinner");
+        let anonymous = MqttSettings { is_enabled: true, ..Default::default() };
+        assert_eq!(anonymous.without_secrets(), anonymous);
+    }
+}
@@ -0,0 +1,592 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use dashmap::DashMap;
+use lettre::{SmtpTransport, Transport};
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::utils::enums::{MessageKind, StepperState};
+use crate::utils::errors::NotificationError;
+use crate::utils::events::{MotorEvent, Subscriptions};
+use crate::utils::motor::Motor;
+use crate::utils::structs::Message;
+
+/// Time between two checks of the events of the motors.
+const POLL_INTERVAL_MS: u64 = 250;
+/// Bounds the wait for a webhook or a mail server that does not answer.
+const TIMEOUT_S: u64 = 10;
+
+/// What a notification is about.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NotificationKind {
+    Finished,
+    Fault,
+    ConnectionLost,
+    /// Sent from the settings through every enabled channel, whatever its filter.
+    Test,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Finished => "finished",
+            NotificationKind::Fault => "fault",
+            NotificationKind::ConnectionLost => "connection_lost",
+            NotificationKind::Test => "test",
+        }
+    }
+}
+
+/// Notifications sent through a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationFilter {
+    pub is_finished: bool,
+    pub is_fault: bool,
+    pub is_connection_lost: bool,
+}
+
+impl Default for NotificationFilter {
+    fn default() -> Self {
+        Self {
+            is_finished: true,
+            is_fault: true,
+            is_connection_lost: true,
+        }
+    }
+}
+
+impl NotificationFilter {
+    pub fn accepts(&self, kind: NotificationKind) -> bool {
+        match kind {
+            NotificationKind::Finished => self.is_finished,
+            NotificationKind::Fault => self.is_fault,
+            NotificationKind::ConnectionLost => self.is_connection_lost,
+            NotificationKind::Test => true,
+        }
+    }
+}
+
+/// `POST` of the notifications as JSON to a URL, e.g. a Slack or Teams workflow or a LIMS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub is_enabled: bool,
+    pub url: String,
+    pub filter: NotificationFilter,
+}
+
+impl WebhookSettings {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(TIMEOUT_S))
+            .build()
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&notification.to_json().to_string())
+            .map_err(|err| NotificationError::Webhook { reason: err.to_string() })?;
+        Ok(())
+    }
+}
+
+/// Encryption of the connection to the mail server.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SmtpSecurity {
+    /// Plain text, only for a relay of the local network.
+    None,
+    StartTls,
+    Tls,
+}
+
+impl Display for SmtpSecurity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmtpSecurity::None => write!(f, "None"),
+            SmtpSecurity::StartTls => write!(f, "STARTTLS"),
+            SmtpSecurity::Tls => write!(f, "TLS"),
+        }
+    }
+}
+
+/// E-mails sent through an SMTP server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailSettings {
+    pub is_enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    /// No authentication when empty.
+    pub username: String,
+    pub password: String,
+    pub from: String,
+    /// Addresses separated by commas.
+    pub to: String,
+    pub filter: NotificationFilter,
+}
+
+impl Default for EmailSettings {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            host: "localhost".to_string(),
+            port: 587,
+            security: SmtpSecurity::StartTls,
+            username: String::new(),
+            password: String::new(),
+            from: String::new(),
+            to: String::new(),
+            filter: NotificationFilter::default(),
+        }
+    }
+}
+
+impl EmailSettings {
+    fn send(&self, notification: &Notification) -> Result<(), NotificationError> {
+        let mailbox = |address: &str| address.trim().parse::<Mailbox>()
+            .map_err(|err| NotificationError::InvalidAddress { address: address.trim().into(), reason: err.to_string() });
+        let email_error = |reason: String| NotificationError::Email { host: self.host.clone(), reason };
+        let mut builder = lettre::Message::builder()
+            .from(mailbox(&self.from)?)
+            .subject(notification.subject())
+            .header(ContentType::TEXT_PLAIN);
+        for address in self.to.split(',').filter(|address| !address.trim().is_empty()) {
+            builder = builder.to(mailbox(address)?);
+        }
+        let email = builder.body(notification.to_text()).map_err(|err| email_error(err.to_string()))?;
+        let transport = match self.security {
+            SmtpSecurity::None => SmtpTransport::builder_dangerous(&self.host),
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&self.host).map_err(|err| email_error(err.to_string()))?,
+            SmtpSecurity::Tls => SmtpTransport::relay(&self.host).map_err(|err| email_error(err.to_string()))?,
+        };
+        let mut transport = transport.port(self.port).timeout(Some(Duration::from_secs(TIMEOUT_S)));
+        if !self.username.is_empty() {
+            transport = transport.credentials(Credentials::new(self.username.clone(), self.password.clone()));
+        }
+        transport.build().send(&email).map_err(|err| email_error(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Settings of the notifications, saved with the app.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub webhook: WebhookSettings,
+    pub email: EmailSettings,
+}
+
+impl NotificationSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.webhook.is_enabled || self.email.is_enabled
+    }
+
+    /// Settings to save, without the password of the mail server. The e-mails are saved disabled if they needed one.
+    pub fn without_secrets(&self) -> Self {
+        let email = EmailSettings {
+            is_enabled: self.email.is_enabled && self.email.password.is_empty(),
+            password: String::new(),
+            ..self.email.clone()
+        };
+        Self { email, ..self.clone() }
+    }
+}
+
+/// End of a run, fault or lost port of a motor, with what is needed to find the samples concerned.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub motor_name: String,
+    pub port_name: String,
+    pub description: String,
+    pub protocol_name: String,
+    pub protocol_summary: String,
+    pub duration_ms: u64,
+    pub run_started_at: Option<DateTime<Local>>,
+    pub timestamp: DateTime<Local>,
+}
+
+impl Notification {
+    /// None for the states that are not notified.
+    pub fn from_event(event: &MotorEvent, motor: &Motor) -> Option<Self> {
+        let kind = if event.is_connection_lost {
+            NotificationKind::ConnectionLost
+        } else if event.state == StepperState::Finished {
+            NotificationKind::Finished
+        } else if event.state.is_fault() {
+            NotificationKind::Fault
+        } else {
+            return None;
+        };
+        let description = match kind {
+            NotificationKind::ConnectionLost => format!("Connection lost with serial port {}", event.port_name),
+            _ => event.state.to_string(),
+        };
+        let protocol_summary = if motor.is_step_protocol { motor.step_protocol.to_string() } else { motor.protocol.to_string() };
+        let run_elapsed_ms = motor.timers_and_phases.lock().get_run_elapsed_ms();
+        Some(Self {
+            kind,
+            motor_name: event.motor_name.clone(),
+            port_name: event.port_name.clone(),
+            description,
+            protocol_name: motor.protocol_metadata.name.clone(),
+            protocol_summary,
+            duration_ms: motor.get_global_duration_ms(),
+            run_started_at: run_elapsed_ms.map(|elapsed_ms| event.timestamp - chrono::Duration::milliseconds(elapsed_ms as i64)),
+            timestamp: event.timestamp,
+        })
+    }
+
+    pub fn test() -> Self {
+        Self {
+            kind: NotificationKind::Test,
+            motor_name: String::new(),
+            port_name: String::new(),
+            description: "Test notification".to_string(),
+            protocol_name: String::new(),
+            protocol_summary: String::new(),
+            duration_ms: 0,
+            run_started_at: None,
+            timestamp: Local::now(),
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        if self.motor_name.is_empty() {
+            return format!("Cell Spinner - {}", self.description);
+        }
+        format!("Cell Spinner - {}: {}", self.motor_name, self.description)
+    }
+
+    /// Body of the webhooks.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind.as_str(),
+            "title": self.subject(),
+            "motor": self.motor_name,
+            "port": self.port_name,
+            "description": self.description,
+            "protocol": {
+                "name": self.protocol_name,
+                "summary": self.protocol_summary,
+                "duration_ms": self.duration_ms,
+            },
+            "run_started_at": self.run_started_at.map(|date| date.to_rfc3339()),
+            "timestamp": self.timestamp.to_rfc3339(),
+        })
+    }
+
+    /// Body of the e-mails.
+    pub fn to_text(&self) -> String {
+        let date = |date: DateTime<Local>| date.format("%Y/%m/%d %H:%M:%S").to_string();
+        let mut text = format!("{}\n\n", self.description);
+        if !self.motor_name.is_empty() {
+            text.push_str(&format!("Motor: {}\nSerial port: {}\n", self.motor_name, self.port_name));
+        }
+        text.push_str(&format!("Time: {}\n", date(self.timestamp)));
+        if let Some(run_started_at) = self.run_started_at {
+            text.push_str(&format!("Run started: {}\n", date(run_started_at)));
+        }
+        if !self.protocol_name.is_empty() {
+            text.push_str(&format!("\nProtocol name: {}\n", self.protocol_name));
+        }
+        if !self.protocol_summary.is_empty() {
+            text.push_str(&format!("\n{}\n", self.protocol_summary));
+        }
+        text
+    }
+}
+
+/// Send the notification through the enabled channels whose filter accepts it, in a thread of its own.
+/// The failures are sent to `message_tx`, as are the successes of the test notifications.
+pub fn notify(settings: &NotificationSettings, notification: Notification, message_tx: Option<Sender<Message>>) -> JoinHandle<()> {
+    let settings = settings.clone();
+    thread::spawn(move || {
+        let send = |message: Message| {
+            if let Some(message_tx) = &message_tx {
+                message_tx.send(message).ok();
+            }
+        };
+        let is_test = notification.kind == NotificationKind::Test;
+        let mut results = vec![];
+        if settings.webhook.is_enabled && settings.webhook.filter.accepts(notification.kind) {
+            results.push(("Webhook", settings.webhook.send(&notification)));
+        }
+        if settings.email.is_enabled && settings.email.filter.accepts(notification.kind) {
+            results.push(("E-mail", settings.email.send(&notification)));
+        }
+        if is_test && results.is_empty() {
+            send(Message::new(MessageKind::Warning, "No notification channel is enabled", None, None, 3, false));
+        }
+        let origin = (!is_test).then(|| notification.motor_name.clone());
+        for (channel, result) in results {
+            match result {
+                Ok(_) if is_test => send(Message::new(MessageKind::Success, &format!("{} test notification sent", channel), None, None, 3, false)),
+                Ok(_) => tracing::info!("{} notification sent: {}", channel, notification.subject()),
+                Err(err) => send(Message::new(MessageKind::Error, &format!("{} notification failed", channel), Some(anyhow!(err)), origin.clone(), 5, false)),
+            }
+        }
+    })
+}
+
+/// Sends a notification when a run finishes, when a motor faults and when a serial port is lost during a run,
+/// from the states the listeners of the ports publish. Stops when dropped.
+pub struct Notifier {
+    is_stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    /// The failures are sent to `message_tx` like the messages of the motors.
+    pub fn start(settings: &NotificationSettings, motors: Arc<DashMap<usize, Motor>>, message_tx: Option<Sender<Message>>) -> Self {
+        let settings = settings.clone();
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let is_stopped = is_stopped.clone();
+            thread::spawn(move || {
+                let mut subscriptions = Subscriptions::default();
+                while !is_stopped.load(Ordering::SeqCst) {
+                    subscriptions.refresh(&motors);
+                    for (tab, event) in subscriptions.try_iter() {
+                        let notification = motors.get(&tab).and_then(|motor| Notification::from_event(&event, &motor));
+                        if let Some(notification) = notification {
+                            notify(&settings, notification, message_tx.clone());
+                        }
+                    }
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                }
+            })
+        };
+        Self { is_stopped, handle: Some(handle) }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Web server answering every request with a `200`, the JSON bodies being sent to the receiver.
+    fn webhook_server() -> (String, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (body_tx, body_rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                body_tx.send(serde_json::from_slice(&body).unwrap()).ok();
+                reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+        });
+        (url, body_rx)
+    }
+
+    /// Mail server accepting every command without authentication, the messages being sent to the receiver.
+    fn mail_server() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (message_tx, message_rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                reader.get_mut().write_all(b"220 localhost ESMTP\r\n").unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 {
+                    let command = line.get(..4).unwrap_or_default().to_ascii_uppercase();
+                    let reply: &[u8] = match command.as_str() {
+                        "DATA" => {
+                            reader.get_mut().write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").unwrap();
+                            let mut message = String::new();
+                            line.clear();
+                            while reader.read_line(&mut line).unwrap() > 0 && line != ".\r\n" {
+                                message.push_str(&line);
+                                line.clear();
+                            }
+                            message_tx.send(message).ok();
+                            b"250 OK\r\n"
+                        }
+                        "QUIT" => b"221 Bye\r\n",
+                        _ => b"250 OK\r\n",
+                    };
+                    reader.get_mut().write_all(reply).unwrap();
+                    if command == "QUIT" {
+                        break;
+                    }
+                    line.clear();
+                }
+            }
+        });
+        (port, message_rx)
+    }
+
+    fn settings(url: &str, port: u16) -> NotificationSettings {
+        NotificationSettings {
+            webhook: WebhookSettings { is_enabled: true, url: url.into(), filter: NotificationFilter::default() },
+            email: EmailSettings {
+                is_enabled: true,
+                host: "127.0.0.1".into(),
+                port,
+                security: SmtpSecurity::None,
+                from: "spinner@lab.example".into(),
+                to: "alice@lab.example, bob@lab.example".into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn motor() -> Motor {
+        let mut motor = Motor { name: "Spinner 1".into(), ..Default::default() };
+        motor.protocol_metadata.name = "Neurons".into();
+        motor.protocol.global_duration_ms = 3_600_000;
+        {
+            let mut timers_and_phases = motor.timers_and_phases.lock();
+            timers_and_phases.global_start_time = Some(Instant::now() - Duration::from_secs(60));
+            timers_and_phases.global_stop_time_ms = Some(60_000);
+        }
+        motor
+    }
+
+    #[test]
+    fn webhook_body_describes_the_run() {
+        let (url, body_rx) = webhook_server();
+        let mut settings = settings(&url, 0);
+        settings.email.is_enabled = false;
+        let motor = motor();
+        let event = MotorEvent::new(&motor.name, "/dev/ttyACM0", StepperState::Finished);
+        let notification = Notification::from_event(&event, &motor).unwrap();
+        let (message_tx, message_rx) = channel();
+        notify(&settings, notification, Some(message_tx)).join().unwrap();
+        let body = body_rx.try_recv().unwrap();
+        assert_eq!(body["kind"], "finished");
+        assert_eq!(body["title"], "Cell Spinner - Spinner 1: Idle - Finished");
+        assert_eq!(body["motor"], "Spinner 1");
+        assert_eq!(body["port"], "/dev/ttyACM0");
+        assert_eq!(body["description"], "Idle - Finished");
+        assert_eq!(body["protocol"]["name"], "Neurons");
+        assert_eq!(body["protocol"]["summary"], motor.protocol.to_string());
+        assert_eq!(body["protocol"]["duration_ms"], motor.get_global_duration_ms());
+        assert_eq!(body["run_started_at"], (event.timestamp - chrono::Duration::seconds(60)).to_rfc3339());
+        assert_eq!(body["timestamp"], event.timestamp.to_rfc3339());
+        assert!(message_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn email_is_sent_to_every_recipient() {
+        let (port, email_rx) = mail_server();
+        let mut settings = settings("", port);
+        settings.webhook.is_enabled = false;
+        let motor = motor();
+        let event = MotorEvent::connection_lost(&motor.name, "/dev/ttyACM0");
+        let notification = Notification::from_event(&event, &motor).unwrap();
+        let (message_tx, message_rx) = channel();
+        notify(&settings, notification, Some(message_tx)).join().unwrap();
+        let email = email_rx.try_recv().unwrap();
+        assert!(email.contains("From: spinner@lab.example"));
+        assert!(email.contains("To: alice@lab.example, bob@lab.example"));
+        assert!(email.contains("Subject: Cell Spinner - Spinner 1: Connection lost"));
+        assert!(email.contains("Motor: Spinner 1\r\nSerial port: /dev/ttyACM0"));
+        assert!(email.contains("Run started: "));
+        assert!(email.contains("Protocol name: Neurons"));
+        assert!(message_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn each_channel_sends_what_its_filter_accepts() {
+        let (url, body_rx) = webhook_server();
+        let (port, email_rx) = mail_server();
+        let mut settings = settings(&url, port);
+        settings.webhook.filter = NotificationFilter { is_finished: false, ..Default::default() };
+        settings.email.filter = NotificationFilter { is_fault: false, is_connection_lost: false, ..Default::default() };
+        let motor = motor();
+        let cases = [
+            (MotorEvent::new(&motor.name, "/dev/ttyACM0", StepperState::Finished), false, true),
+            (MotorEvent::new(&motor.name, "/dev/ttyACM0", StepperState::OverCurrent), true, false),
+            (MotorEvent::connection_lost(&motor.name, "/dev/ttyACM0"), true, false),
+        ];
+        for (event, is_webhook, is_email) in cases {
+            let notification = Notification::from_event(&event, &motor).unwrap();
+            notify(&settings, notification, None).join().unwrap();
+            assert_eq!(body_rx.try_recv().is_ok(), is_webhook, "webhook of {:?}", event);
+            assert_eq!(email_rx.try_recv().is_ok(), is_email, "e-mail of {:?}", event);
+        }
+        let event = MotorEvent::new(&motor.name, "/dev/ttyACM0", StepperState::OscillationRotation);
+        assert!(Notification::from_event(&event, &motor).is_none());
+        settings.webhook.filter = NotificationFilter { is_finished: false, is_fault: false, is_connection_lost: false };
+        settings.email.filter = settings.webhook.filter.clone();
+        notify(&settings, Notification::test(), None).join().unwrap();
+        assert_eq!(body_rx.try_recv().unwrap()["kind"], "test");
+        assert!(email_rx.try_recv().unwrap().contains("Subject: Cell Spinner - Test notification"));
+    }
+
+    #[test]
+    fn test_notification_reports_each_channel() {
+        let (url, _body_rx) = webhook_server();
+        let (port, _email_rx) = mail_server();
+        let messages = |settings: &NotificationSettings| {
+            let (message_tx, message_rx) = channel();
+            notify(settings, Notification::test(), Some(message_tx)).join().unwrap();
+            message_rx.try_iter().map(|message| (message.kind, message.message)).collect::<Vec<_>>()
+        };
+        let mut settings = settings(&url, port);
+        assert_eq!(messages(&settings), [
+            (MessageKind::Success, "Webhook test notification sent".to_string()),
+            (MessageKind::Success, "E-mail test notification sent".to_string()),
+        ]);
+        settings.webhook.url = format!("http://{}/hook", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        assert_eq!(messages(&settings), [
+            (MessageKind::Error, "Webhook notification failed".to_string()),
+            (MessageKind::Success, "E-mail test notification sent".to_string()),
+        ]);
+        settings.webhook.is_enabled = false;
+        settings.email.is_enabled = false;
+        assert_eq!(messages(&settings), [(MessageKind::Warning, "No notification channel is enabled".to_string())]);
+    }
+
+    #[test]
+    fn email_password_is_not_saved() {
+        let mut settings = NotificationSettings::default();
+        settings.webhook.is_enabled = true;
+        settings.email = EmailSettings { is_enabled: true, username: "spinner".into(), password: "secret".into(), ..Default::default() };
+        let saved = settings.without_secrets();
+        assert!(saved.email.password.is_empty());
+        assert!(!saved.email.is_enabled);
+        assert!(saved.webhook.is_enabled);
+        settings.email.password.clear();
+        assert_eq!(settings.without_secrets(), settings);
+    }
+}
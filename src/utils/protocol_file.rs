@@ -0,0 +1,110 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::errors::ProtocolFileError;
+use crate::utils::protocols::Protocol;
+use crate::utils::step_protocol::StepProtocol;
+
+/// Schema version written by `export_configuration`. Bump it and add a step to `migrate` when the document changes.
+pub const PROTOCOL_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtocolMetadata {
+    pub name: String,
+    pub author: String,
+    pub description: String,
+    pub created_at: Option<DateTime<Local>>,
+}
+
+/// Protocol file with its schema version and metadata.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolDocument {
+    pub schema_version: u32,
+    pub metadata: ProtocolMetadata,
+    pub protocol: Protocol,
+    /// Run instead of `protocol` when present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step_protocol: Option<StepProtocol>,
+}
+
+impl ProtocolDocument {
+    pub fn new(metadata: ProtocolMetadata, protocol: Protocol, step_protocol: Option<StepProtocol>) -> Self {
+        Self {
+            schema_version: PROTOCOL_SCHEMA_VERSION,
+            metadata,
+            protocol,
+            step_protocol,
+        }
+    }
+
+    /// Read a document of any schema up to `PROTOCOL_SCHEMA_VERSION`, including the bare `Protocol` of the first versions of the app.
+    pub fn from_json(json: &str) -> Result<Self, ProtocolFileError> {
+        Self::from_value(serde_json::from_str(json)?)
+    }
+
+    /// Same as `from_json`, for a document embedded in another file.
+    pub fn from_value(value: Value) -> Result<Self, ProtocolFileError> {
+        // Version 0 only stands for the bare protocols, which have no version field.
+        let schema_version = match value.get("schema_version") {
+            Some(version) => version.as_u64().filter(|version| *version > 0).ok_or(ProtocolFileError::InvalidSchemaVersion)?,
+            None if value.get("rotation").is_some() => 0,
+            None => return Err(ProtocolFileError::UnknownFormat),
+        };
+        if schema_version > PROTOCOL_SCHEMA_VERSION as u64 {
+            return Err(ProtocolFileError::NewerSchema { found: schema_version, supported: PROTOCOL_SCHEMA_VERSION });
+        }
+        let value = migrate(schema_version as u32, value)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, ProtocolFileError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Upgrade a document to `PROTOCOL_SCHEMA_VERSION`, one version at a time.
+fn migrate(mut schema_version: u32, mut value: Value) -> Result<Value, ProtocolFileError> {
+    while schema_version < PROTOCOL_SCHEMA_VERSION {
+        value = match schema_version {
+            // Bare protocol, without metadata.
+            0 => serde_json::json!({
+                "schema_version": 1,
+                "metadata": serde_json::to_value(ProtocolMetadata::default())?,
+                "protocol": value,
+            }),
+            _ => unreachable!("No migration from schema version {}", schema_version),
+        };
+        schema_version += 1;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_protocol_is_migrated() {
+        let protocol = Protocol { global_duration_ms: 10_000, ..Default::default() };
+        let document = ProtocolDocument::from_value(serde_json::to_value(protocol).unwrap()).unwrap();
+        assert_eq!(document, ProtocolDocument::new(ProtocolMetadata::default(), protocol, None));
+    }
+
+    #[test]
+    fn document_round_trip() {
+        let metadata = ProtocolMetadata { name: "Spin".into(), author: "Lab".into(), description: "Overnight".into(), created_at: None };
+        let document = ProtocolDocument::new(metadata, Protocol::default(), Some(StepProtocol::from(Protocol::default())));
+        assert_eq!(ProtocolDocument::from_json(&document.to_json().unwrap()).unwrap(), document);
+    }
+
+    #[test]
+    fn explicit_schema_version_0_is_rejected() {
+        let mut value = serde_json::to_value(ProtocolDocument::new(ProtocolMetadata::default(), Protocol::default(), None)).unwrap();
+        value["schema_version"] = 0.into();
+        assert!(matches!(ProtocolDocument::from_value(value.clone()), Err(ProtocolFileError::InvalidSchemaVersion)));
+        value["schema_version"] = (PROTOCOL_SCHEMA_VERSION + 1).into();
+        assert!(matches!(ProtocolDocument::from_value(value), Err(ProtocolFileError::NewerSchema { .. })));
+    }
+}
@@ -1,13 +1,19 @@
 use std::fmt::{Display, Formatter};
 
+use anyhow::Error;
 use serde::{Deserialize, Serialize};
 use stepgen_new::x64::Stepgen;
 
-use crate::app::{BYTES, MAX_RPM};
+use crate::utils::constants::{BYTES, MAX_ACCELERATION, MAX_DURATION_MS, MAX_RPM};
 use crate::utils::enums::{Direction, StepMode128};
+use crate::utils::errors::DecodeError;
+use crate::utils::frame::{CommandFrame, FrameCommand};
 use crate::utils::structs::DurationHelper;
+use crate::utils::validation::{ProtocolField, ValidationIssue};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub const ROTATION_BYTES: usize = 34;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Rotation {
     pub rpm: u32,
     pub acceleration: u32,
@@ -80,9 +86,25 @@ impl Rotation {
         bytes[26..34].copy_from_slice(&self.pause_before_direction_change_ms.to_le_bytes());
         bytes
     }
+
+    /// Rotation from the bytes produced by `convert_to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < ROTATION_BYTES {
+            return Err(DecodeError::Truncated { expected: ROTATION_BYTES, found: bytes.len() });
+        }
+        Ok(Self {
+            rpm: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            acceleration: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            step_mode: StepMode128::from_bytes(&bytes[8..9])?,
+            duration_of_one_direction_cycle_ms: u64::from_le_bytes(bytes[9..17].try_into().unwrap()),
+            steps_for_one_direction_cycle: u64::from_le_bytes(bytes[17..25].try_into().unwrap()),
+            direction: Direction::from_bytes(&bytes[25..26])?,
+            pause_before_direction_change_ms: u64::from_le_bytes(bytes[26..34].try_into().unwrap()),
+        })
+    }
 }
 
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Protocol {
     pub rotation: Rotation,
     pub rotation_duration_ms: u64,
@@ -99,6 +121,50 @@ impl Protocol {
         self.rotation_duration_ms + self.agitation_duration_ms
     }
 
+    /// Every issue of the protocol to import. A protocol still being filled in, with nothing to run or without the speed of a phase,
+    /// is only warned about, see `validate_run`. The protocol should not be imported if one of them is an error.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        self.issues(false)
+    }
+
+    /// Every issue of the protocol to start, the protocol with nothing to run or without the speed of a phase being refused.
+    /// The protocol should not be sent to the motor if one of them is an error.
+    pub fn validate_run(&self) -> Vec<ValidationIssue> {
+        self.issues(true)
+    }
+
+    fn issues(&self, is_run: bool) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+        validate_rotation(&mut issues, "rotation", &self.rotation, self.rotation_duration_ms, is_run,
+                          [ProtocolField::RotationRpm, ProtocolField::RotationAcceleration, ProtocolField::RotationCycleDuration, ProtocolField::RotationPause, ProtocolField::RotationDuration]);
+        validate_rotation(&mut issues, "agitation", &self.agitation, self.agitation_duration_ms, is_run,
+                          [ProtocolField::AgitationRpm, ProtocolField::AgitationAcceleration, ProtocolField::AgitationCycleDuration, ProtocolField::AgitationPause, ProtocolField::AgitationDuration]);
+        for (field, duration_ms) in [(ProtocolField::PausePreAgitation, self.pause_pre_agitation_ms), (ProtocolField::PausePostAgitation, self.pause_post_agitation_ms), (ProtocolField::GlobalDuration, self.global_duration_ms)] {
+            if duration_ms > MAX_DURATION_MS {
+                issues.push(ValidationIssue::error(field, "Longer than one year."));
+            }
+        }
+        if self.get_duration_without_pause() == 0 {
+            let message = "The rotation and agitation durations are both 0, there is nothing to run.";
+            let issue = if is_run { ValidationIssue::error } else { ValidationIssue::warning };
+            issues.push(issue(ProtocolField::RotationDuration, message));
+            issues.push(issue(ProtocolField::AgitationDuration, message));
+        }
+        if self.rotation_duration_ms == 0 && self.pause_pre_agitation_ms != 0 {
+            issues.push(ValidationIssue::warning(ProtocolField::PausePreAgitation, "Ignored because the rotation duration is 0."));
+        }
+        if self.agitation_duration_ms == 0 && self.pause_post_agitation_ms != 0 {
+            issues.push(ValidationIssue::warning(ProtocolField::PausePostAgitation, "Ignored because the agitation duration is 0."));
+        }
+        let loop_duration_ms = self.get_duration_without_pause() + self.pause_pre_agitation_ms + self.pause_post_agitation_ms;
+        if self.global_duration_ms == 0 {
+            issues.push(ValidationIssue::warning(ProtocolField::GlobalDuration, "The global duration is 0."));
+        } else if self.global_duration_ms < loop_duration_ms {
+            issues.push(ValidationIssue::warning(ProtocolField::GlobalDuration, "Shorter than one rotation and agitation loop, the protocol stops before the end of the first loop."));
+        }
+        issues
+    }
+
     /// Protocol to bytes for serial communication
     pub fn protocol_as_bytes(&self) -> [u8; BYTES] {
         let mut bytes = [0u8; BYTES];
@@ -113,6 +179,43 @@ impl Protocol {
         bytes[109] = b'z';
         bytes
     }
+
+    /// Protocol from the bytes produced by `protocol_as_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < BYTES {
+            return Err(DecodeError::Truncated { expected: BYTES, found: bytes.len() });
+        }
+        if bytes[0] != b'a' {
+            return Err(DecodeError::InvalidMarker { index: 0, expected: b'a', found: bytes[0] });
+        }
+        if bytes[BYTES - 1] != b'z' {
+            return Err(DecodeError::InvalidMarker { index: BYTES - 1, expected: b'z', found: bytes[BYTES - 1] });
+        }
+        Self::from_payload_bytes(&bytes[1..BYTES - 1])
+    }
+
+    /// Protocol from the bytes of `protocol_as_bytes` without the `a` and `z` markers, as carried by a framed command
+    pub fn from_payload_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < BYTES - 2 {
+            return Err(DecodeError::Truncated { expected: BYTES - 2, found: bytes.len() });
+        }
+        let u64_at = |index: usize| u64::from_le_bytes(bytes[index..index + 8].try_into().unwrap());
+        Ok(Self {
+            rotation: Rotation::from_bytes(&bytes[0..34])?,
+            rotation_duration_ms: u64_at(34),
+            pause_pre_agitation_ms: u64_at(42),
+            agitation: Rotation::from_bytes(&bytes[50..84])?,
+            agitation_duration_ms: u64_at(84),
+            pause_post_agitation_ms: u64_at(92),
+            global_duration_ms: u64_at(100),
+        })
+    }
+
+    /// Protocol to a framed command for serial communication. The payload is the legacy frame without its markers.
+    pub fn protocol_as_frame(&self, sequence_id: u8) -> Result<Vec<u8>, Error> {
+        let payload = self.protocol_as_bytes()[1..BYTES - 1].to_vec();
+        CommandFrame::new(sequence_id, FrameCommand::StartProtocol, payload).as_bytes()
+    }
 }
 
 impl Display for Protocol {
@@ -132,4 +235,112 @@ impl Display for Protocol {
         write!(f, "Global duration: {}", duration_global)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Issues of the rotation or the agitation. `fields` are the rpm, acceleration, cycle duration, pause and phase duration fields.
+/// An rpm or an acceleration of 0 is an error only for a phase that runs and when the protocol is started.
+pub(crate) fn validate_rotation(issues: &mut Vec<ValidationIssue>, name: &str, rotation: &Rotation, phase_duration_ms: u64, is_run: bool, fields: [ProtocolField; 5]) {
+    let [rpm, acceleration, cycle_duration, pause, phase_duration] = fields;
+    let is_phase_run = phase_duration_ms != 0 && rotation.get_min_duration() != 0;
+    let missing = if is_run && is_phase_run { ValidationIssue::error } else { ValidationIssue::warning };
+    if rotation.acceleration == 0 {
+        issues.push(missing(acceleration, format!("The acceleration of the {} is 0.", name)));
+    } else if rotation.acceleration > MAX_ACCELERATION {
+        issues.push(ValidationIssue::error(acceleration, format!("The acceleration of the {} is higher than {}.", name, MAX_ACCELERATION)));
+    }
+    if rotation.rpm == 0 {
+        issues.push(missing(rpm, format!("The RPM of the {} is 0.", name)));
+    } else if rotation.rpm > rotation.max_rpm_for_stepmode() {
+        issues.push(ValidationIssue::error(rpm, format!("The RPM of the {} is higher than {}, the maximum for the step mode {}.", name, rotation.max_rpm_for_stepmode(), rotation.step_mode)));
+    }
+    for (field, duration_ms) in [(cycle_duration, rotation.duration_of_one_direction_cycle_ms), (pause, rotation.pause_before_direction_change_ms), (phase_duration, phase_duration_ms)] {
+        if duration_ms > MAX_DURATION_MS {
+            issues.push(ValidationIssue::error(field, "Longer than one year."));
+        }
+    }
+    if phase_duration_ms != 0 && rotation.get_min_duration() == 0 {
+        issues.push(ValidationIssue::warning(cycle_duration, format!("The cycle duration of the {} is 0, the {} phase is skipped.", name, name)));
+    } else if phase_duration_ms != 0 && phase_duration_ms < rotation.duration_of_one_direction_cycle_ms {
+        issues.push(ValidationIssue::warning(phase_duration, format!("The {} phase is shorter than one cycle.", name)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol() -> Protocol {
+        Protocol {
+            rotation: Rotation { rpm: 123, acceleration: 456, step_mode: StepMode128::M32, duration_of_one_direction_cycle_ms: 7890, steps_for_one_direction_cycle: 11, direction: Direction::Backward, pause_before_direction_change_ms: 12 },
+            rotation_duration_ms: 60_000,
+            pause_pre_agitation_ms: 1_000,
+            agitation: Rotation { rpm: 30, acceleration: 100, step_mode: StepMode128::M128, duration_of_one_direction_cycle_ms: 500, steps_for_one_direction_cycle: 0, direction: Direction::Forward, pause_before_direction_change_ms: 0 },
+            agitation_duration_ms: 30_000,
+            pause_post_agitation_ms: 2_000,
+            global_duration_ms: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn protocol_being_filled_in_is_only_refused_at_run() {
+        let errors = |issues: Vec<ValidationIssue>| issues.into_iter().filter(|issue| issue.is_error()).map(|issue| issue.field).collect::<Vec<_>>();
+        let mut protocol = Protocol::default();
+        assert_eq!(errors(protocol.validate()), []);
+        assert_eq!(errors(protocol.validate_run()), [ProtocolField::RotationDuration, ProtocolField::AgitationDuration]);
+        // Speed of the rotation missing, the agitation being unused.
+        protocol.rotation = Rotation { rpm: 0, acceleration: 0, duration_of_one_direction_cycle_ms: 1_000, ..Default::default() };
+        protocol.agitation = Rotation { rpm: 0, acceleration: 0, ..Default::default() };
+        protocol.rotation_duration_ms = 10_000;
+        assert_eq!(errors(protocol.validate()), []);
+        assert_eq!(errors(protocol.validate_run()), [ProtocolField::RotationAcceleration, ProtocolField::RotationRpm]);
+        protocol.rotation.rpm = 60;
+        protocol.rotation.acceleration = 100;
+        assert_eq!(errors(protocol.validate_run()), []);
+        assert!(protocol.validate_run().iter().any(|issue| issue.field == ProtocolField::AgitationRpm && !issue.is_error()));
+        protocol.rotation.rpm = MAX_RPM + 1;
+        assert_eq!(errors(protocol.validate()), [ProtocolField::RotationRpm]);
+    }
+
+    #[test]
+    fn rotation_round_trip() {
+        let rotation = protocol().rotation;
+        assert_eq!(Rotation::from_bytes(&rotation.convert_to_bytes()), Ok(rotation));
+    }
+
+    #[test]
+    fn protocol_round_trip() {
+        let protocol = protocol();
+        assert_eq!(Protocol::from_bytes(&protocol.protocol_as_bytes()), Ok(protocol));
+        assert_eq!(Protocol::from_payload_bytes(&protocol.protocol_as_bytes()[1..BYTES - 1]), Ok(protocol));
+    }
+
+    #[test]
+    fn truncated_bytes_are_rejected() {
+        let bytes = protocol().protocol_as_bytes();
+        assert_eq!(Protocol::from_bytes(&bytes[..BYTES - 1]), Err(DecodeError::Truncated { expected: BYTES, found: BYTES - 1 }));
+        assert_eq!(Protocol::from_payload_bytes(&bytes[1..BYTES - 2]), Err(DecodeError::Truncated { expected: BYTES - 2, found: BYTES - 3 }));
+        assert_eq!(Rotation::from_bytes(&bytes[1..ROTATION_BYTES]), Err(DecodeError::Truncated { expected: ROTATION_BYTES, found: ROTATION_BYTES - 1 }));
+    }
+
+    #[test]
+    fn bad_markers_are_rejected() {
+        let mut bytes = protocol().protocol_as_bytes();
+        bytes[0] = b'b';
+        assert_eq!(Protocol::from_bytes(&bytes), Err(DecodeError::InvalidMarker { index: 0, expected: b'a', found: b'b' }));
+        bytes[0] = b'a';
+        bytes[BYTES - 1] = b'y';
+        assert_eq!(Protocol::from_bytes(&bytes), Err(DecodeError::InvalidMarker { index: BYTES - 1, expected: b'z', found: b'y' }));
+    }
+
+    #[test]
+    fn unknown_enum_bytes_of_a_protocol_are_rejected() {
+        let mut bytes = protocol().protocol_as_bytes();
+        // Step mode of the rotation, after the marker, the rpm and the acceleration.
+        bytes[9] = 9;
+        assert_eq!(Protocol::from_bytes(&bytes), Err(DecodeError::UnknownEnumByte { field: "step mode", byte: 9 }));
+        let mut bytes = protocol().protocol_as_bytes();
+        // Direction of the agitation.
+        bytes[51 + 25] = 3;
+        assert_eq!(Protocol::from_bytes(&bytes), Err(DecodeError::UnknownEnumByte { field: "direction", byte: 3 }));
+    }
+}
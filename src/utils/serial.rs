@@ -1,90 +1,341 @@
 use std::io::Read;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Sender;
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, Error};
-use egui_toast::ToastKind;
-use parking_lot::Mutex;
-use serialport::{DataBits, FlowControl, Parity, SerialPort, StopBits};
+use anyhow::anyhow;
+use parking_lot::{Condvar, Mutex};
+use serialport::SerialPort;
 
-use crate::app::THREAD_SLEEP;
-use crate::utils::enums::StepperState;
-use crate::utils::structs::{Message, TimersAndPhases};
+use crate::utils::constants::THREAD_SLEEP;
+use crate::utils::enums::{FrameFormat, MessageKind, StepperState};
+use crate::utils::errors::{DecodeError, SerialError};
+use crate::utils::events::{EventBus, MotorEvent};
+use crate::utils::metrics::SerialMetrics;
+use crate::utils::protocols::Protocol;
+use crate::utils::status::{MotorStatus, STATUS_BYTES, STATUS_QUERY, STATUS_REPLY};
+use crate::utils::step_protocol::StepProtocol;
+use crate::utils::structs::{Message, SerialSettings, TimersAndPhases};
 
-#[derive(Default)]
+/// What the reader and writer threads of a port report to the listener.
+pub enum SerialEvent {
+    Received([u8; 3]),
+    ReadError(std::io::Error),
+    WriteError(std::io::Error),
+}
+
+/// Reply confirming that the firmware received a command.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Acknowledgement {
+    /// `ok!`, for the commands of 4 bytes and the legacy protocol upload.
+    CommandReceived,
+    /// `ak<sequence id>`, for the framed commands.
+    Frame(u8),
+}
+
+/// Last reply received, handed from the reader thread to the command waiting for it.
+struct ReplySlot<T> {
+    last: Mutex<Option<T>>,
+    received: Condvar,
+}
+
+impl<T> Default for ReplySlot<T> {
+    fn default() -> Self {
+        Self {
+            last: Mutex::new(None),
+            received: Condvar::new(),
+        }
+    }
+}
+
+/// Writer queue of an open port. Dropping the queue lets the writer thread flush it and exit.
+struct PortIo {
+    writer_tx: Sender<Vec<u8>>,
+    is_open: Arc<AtomicBool>,
+    threads: [JoinHandle<()>; 2],
+}
+
+/// Serial link to a Raspberry. Every open port has a reader thread and a writer thread:
+/// the reader forwards what the firmware sends as `SerialEvent`s, the writer sends the queued commands in order.
+#[derive(Clone)]
 pub struct Serial {
     pub port_name: String,
-    pub port: Arc<Mutex<Option<Box<dyn SerialPort>>>>,
+    pub settings: SerialSettings,
+    io: Arc<Mutex<Option<PortIo>>>,
+    /// Sender of the channel of the current listener, replaced to hand the events over to the next one.
+    events_tx: Arc<Mutex<Sender<SerialEvent>>>,
+    /// Receiver of the channel, until a listener takes it. The events received in between are kept for that listener.
+    events_rx: Arc<Mutex<Option<Receiver<SerialEvent>>>>,
+    /// Incremented by each new listener so that the previous one stops.
+    listener_generation: Arc<AtomicUsize>,
+    pub sequence_id: Arc<AtomicU8>,
+    acks: Arc<ReplySlot<StepperState>>,
+    statuses: Arc<ReplySlot<Result<MotorStatus, DecodeError>>>,
+    /// Only one command waits for its acknowledgement at a time.
+    command_lock: Arc<Mutex<()>>,
+    /// The port is closed while the listener tries to reopen it.
+    pub is_reconnecting: Arc<AtomicBool>,
+    /// States received by the listener, for the consumers other than the tab.
+    pub events: EventBus,
+    pub metrics: SerialMetrics,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        let (events_tx, events_rx) = channel();
+        Self {
+            port_name: String::new(),
+            settings: SerialSettings::default(),
+            io: Arc::new(Mutex::new(None)),
+            events_tx: Arc::new(Mutex::new(events_tx)),
+            events_rx: Arc::new(Mutex::new(Some(events_rx))),
+            listener_generation: Arc::new(AtomicUsize::new(0)),
+            sequence_id: Arc::new(AtomicU8::new(0)),
+            acks: Arc::new(ReplySlot::default()),
+            statuses: Arc::new(ReplySlot::default()),
+            command_lock: Arc::new(Mutex::new(())),
+            is_reconnecting: Arc::new(AtomicBool::new(false)),
+            events: EventBus::default(),
+            metrics: SerialMetrics::default(),
+        }
+    }
 }
 
 impl Serial {
-    pub fn new(port_name: &str, already_connected_ports: Arc<Mutex<Vec<String>>>) -> Result<Self, Error> {
-        let port = Self::connect_to_serial_port(port_name)?;
-        let port = Arc::new(port);
-        already_connected_ports.lock().push(port_name.into());
-        Ok(Self {
+    pub fn new(port_name: &str, settings: SerialSettings, already_connected_ports: Arc<Mutex<Vec<String>>>) -> Result<Self, SerialError> {
+        if already_connected_ports.lock().iter().any(|port| port == port_name) {
+            return Err(SerialError::PortInUse { port_name: port_name.into() });
+        }
+        let handshake_start = Instant::now();
+        let port = Self::connect_to_serial_port(port_name, &settings)?;
+        let serial = Self {
             port_name: port_name.into(),
-            port,
-        })
-    }
-
-    fn connect_to_serial_port(port_name: &str) -> Result<Mutex<Option<Box<dyn SerialPort>>>, Error> {
-        let mut system_port_unwrapped = serialport::new(port_name, 500000)
-            .parity(Parity::None)
-            .data_bits(DataBits::Eight)
-            .stop_bits(StopBits::One)
-            .flow_control(FlowControl::None)
-            .timeout(Duration::from_millis(2000))
-            .open()?;
+            settings,
+            ..Default::default()
+        };
+        serial.metrics.handshake(handshake_start.elapsed());
+        serial.start_io(port)?;
+        already_connected_ports.lock().push(port_name.into());
+        Ok(serial)
+    }
+
+    fn connect_to_serial_port(port_name: &str, settings: &SerialSettings) -> Result<Box<dyn SerialPort>, SerialError> {
+        let mut system_port_unwrapped = serialport::new(port_name, settings.baud_rate)
+            .parity(settings.parity)
+            .data_bits(settings.data_bits)
+            .stop_bits(settings.stop_bits)
+            .flow_control(settings.flow_control)
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .open()
+            .map_err(|source| SerialError::PortOpen { port_name: port_name.into(), source })?;
+        let handshake_error = |err: std::io::Error| SerialError::Handshake { port_name: port_name.into(), reason: err.to_string() };
         let mut buf = [0u8; 3];
         let mut counter = 0;
         // Write "helo" to serial port
         loop {
-            system_port_unwrapped.write_all(b"helo")?;
-            system_port_unwrapped.read_exact(&mut buf)?;
+            system_port_unwrapped.write_all(b"helo").map_err(handshake_error)?;
+            system_port_unwrapped.read_exact(&mut buf).map_err(handshake_error)?;
             if buf == [b'o', b'k', b'!'] {
                 break;
             } else {
                 counter += 1;
                 tracing::info!("Raspberry connection failed, retrying... ({})", counter);
-                if counter >= 15 {
-                    bail!("Raspberry connection failed after 15 retries");
+                if counter >= settings.handshake_retries {
+                    return Err(SerialError::Handshake { port_name: port_name.into(), reason: format!("no answer after {} retries", counter) });
+                }
+                thread::sleep(Duration::from_millis(settings.retry_delay_ms));
+            }
+        }
+        Ok(system_port_unwrapped)
+    }
+
+    /// Spawn the reader and writer threads of a port that passed the handshake.
+    fn start_io(&self, port: Box<dyn SerialPort>) -> Result<(), SerialError> {
+        let mut reader = port.try_clone().map_err(|source| SerialError::PortOpen { port_name: self.port_name.clone(), source })?;
+        // A short read timeout lets the reader notice quickly that the port is closed. Reads return as soon as bytes arrive.
+        let reader_timeout_ms = self.settings.timeout_ms.min(THREAD_SLEEP * 10);
+        reader.set_timeout(Duration::from_millis(reader_timeout_ms)).map_err(|source| SerialError::PortOpen { port_name: self.port_name.clone(), source })?;
+        let mut writer = port;
+        let (writer_tx, writer_rx) = channel::<Vec<u8>>();
+        let is_open = Arc::new(AtomicBool::new(true));
+        // Reader
+        let events_tx = self.events_tx.clone();
+        let acks = self.acks.clone();
+        let statuses = self.statuses.clone();
+        let is_reader_open = is_open.clone();
+        let reader_thread = thread::spawn(move || {
+            let mut buffer: Vec<u8> = Vec::new();
+            let mut read_buffer = [0u8; 256];
+            while is_reader_open.load(Ordering::SeqCst) {
+                match reader.read(&mut read_buffer) {
+                    // End of file, the device is gone.
+                    Ok(0) => {
+                        if is_reader_open.load(Ordering::SeqCst) {
+                            events_tx.lock().send(SerialEvent::ReadError(std::io::ErrorKind::UnexpectedEof.into())).ok();
+                        }
+                        return;
+                    }
+                    Ok(n) => buffer.extend_from_slice(&read_buffer[..n]),
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(err) => {
+                        if is_reader_open.load(Ordering::SeqCst) {
+                            events_tx.lock().send(SerialEvent::ReadError(err)).ok();
+                        }
+                        return;
+                    }
+                }
+                // Split what was received into 3 bytes states and status replies.
+                loop {
+                    let length = if buffer.starts_with(&STATUS_REPLY) { STATUS_BYTES } else { 3 };
+                    if buffer.len() < length {
+                        break;
+                    }
+                    let bytes: Vec<u8> = buffer.drain(..length).collect();
+                    if length == STATUS_BYTES {
+                        *statuses.last.lock() = Some(MotorStatus::from_bytes(&bytes));
+                        statuses.received.notify_all();
+                        continue;
+                    }
+                    let buf = [bytes[0], bytes[1], bytes[2]];
+                    match StepperState::from(&buf) {
+                        state @ (StepperState::CommandReceived | StepperState::Acknowledged(_) | StepperState::NotAcknowledged(_)) => {
+                            *acks.last.lock() = Some(state);
+                            acks.received.notify_all();
+                        }
+                        // Nobody listens between two runs, what is received then is dropped with the channel.
+                        _ => {
+                            events_tx.lock().send(SerialEvent::Received(buf)).ok();
+                        }
+                    }
                 }
-                thread::sleep(Duration::from_millis(500));
             }
+        });
+        // Writer
+        let events_tx = self.events_tx.clone();
+        let writer_thread = thread::spawn(move || {
+            for bytes in writer_rx {
+                if let Err(err) = writer.write_all(&bytes) {
+                    events_tx.lock().send(SerialEvent::WriteError(err)).ok();
+                    return;
+                }
+            }
+        });
+        *self.io.lock() = Some(PortIo { writer_tx, is_open, threads: [reader_thread, writer_thread] });
+        Ok(())
+    }
+
+    /// Stop the reader thread, let the writer thread flush its queue and wait for both to release the port.
+    /// Unlike `disconnect`, the firmware is not told and keeps running, as when the app is killed.
+    pub(crate) fn close_io(&self) {
+        let Some(io) = self.io.lock().take() else {
+            return;
+        };
+        io.is_open.store(false, Ordering::SeqCst);
+        drop(io.writer_tx);
+        for thread in io.threads {
+            thread.join().ok();
         }
-        Ok(Mutex::new(Some(system_port_unwrapped)))
     }
 
     pub fn get_is_connected(&self) -> bool {
-        self.port.lock().is_some()
+        self.io.lock().is_some() || self.is_reconnecting.load(Ordering::SeqCst)
+    }
+
+    /// Ask the firmware for its current state. Returns None if the firmware does not answer the query within `timeout_ms`.
+    /// The reply is handed over by the reader like the acknowledgements, the listener of a run keeps its events.
+    pub fn query_status(&self, timeout_ms: u64) -> Result<Option<MotorStatus>, SerialError> {
+        let _command = self.command_lock.lock();
+        let mut last = self.statuses.last.lock();
+        last.take();
+        self.send_bytes(&STATUS_QUERY)?;
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        while last.is_none() {
+            if self.statuses.received.wait_until(&mut last, deadline).timed_out() {
+                tracing::info!("{} - No answer to the status query, the firmware is considered idle", self.port_name);
+                return Ok(None);
+            }
+        }
+        last.take().unwrap().map(Some).map_err(|source| SerialError::InvalidStatus { port_name: self.port_name.clone(), source })
     }
 
+    /// Reopen the port and redo the handshake according to the policy. Returns true once the port is reopened.
+    /// Gives up as soon as the motor is stopped.
+    fn reconnect(&self, is_running: &AtomicBool, motor_name: &str, message_tx: &Option<Sender<Message>>) -> bool {
+        let policy = self.settings.reconnect_policy;
+        self.is_reconnecting.store(true, Ordering::SeqCst);
+        // Drop the broken handle so that the OS can give the same port back.
+        self.close_io();
+        let send = |message: Message| {
+            if let Some(message_tx) = message_tx {
+                message_tx.send(message).ok();
+            }
+        };
+        for attempt in 1..=policy.max_retries {
+            let deadline = Instant::now() + Duration::from_millis(policy.delay_ms(attempt));
+            send(Message::new(MessageKind::Warning, &format!("Serial port {} lost, reconnecting... ({}/{})", self.port_name, attempt, policy.max_retries), None, Some(motor_name.into()), 3, false));
+            while Instant::now() < deadline {
+                if !is_running.load(Ordering::SeqCst) {
+                    self.is_reconnecting.store(false, Ordering::SeqCst);
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(THREAD_SLEEP));
+            }
+            let handshake_start = Instant::now();
+            match Self::connect_to_serial_port(&self.port_name, &self.settings).and_then(|port| self.start_io(port)) {
+                Ok(_) => {
+                    self.metrics.handshake(handshake_start.elapsed());
+                    self.metrics.reconnected();
+                    self.is_reconnecting.store(false, Ordering::SeqCst);
+                    send(Message::new(MessageKind::Success, &format!("Reconnected to serial port {}", self.port_name), None, Some(motor_name.into()), 3, false));
+                    return true;
+                }
+                Err(err) => tracing::warn!("{} - Reconnection attempt {} failed: {}", motor_name, attempt, err),
+            }
+        }
+        self.is_reconnecting.store(false, Ordering::SeqCst);
+        false
+    }
+
+    /// The firmware does not acknowledge `bye!`, the writer thread sends it before releasing the port.
     pub fn disconnect(&self) {
-        if let Some(mut port) = self.port.lock().take() {
-            port.write_all(b"bye!").ok();
+        if let Err(err) = self.send_bytes(b"bye!") {
+            tracing::warn!("{}", err);
         }
+        self.close_io();
     }
 
     pub fn listen_to_serial_port(&self, motor_name: String, is_running: &Arc<AtomicBool>, timers_and_phases: &Arc<Mutex<TimersAndPhases>>, message_tx: Option<Sender<Message>>) {
-        let port = self.port.clone();
+        let serial = self.clone();
         let is_running = is_running.clone();
         let timers_and_phases = timers_and_phases.clone();
         let port_name = self.port_name.clone();
+        let generation = self.listener_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        // The listener owns its receiver, a previous listener stops when it is handed a new one.
+        let events_rx = self.events_rx.lock().take().unwrap_or_else(|| self.replace_events_channel());
         thread::spawn(move || {
-            while is_running.load(Ordering::SeqCst) {
-                if port.lock().is_none() {
-                    return;
-                }
-                let mut buf: [u8; 3];
-                // Check if there is a byte to read
-                let result = port.lock().as_ref().unwrap().bytes_to_read();
-                let is_byte = match result {
-                    Ok(n) => n,
-                    Err(err) => {
+            while is_running.load(Ordering::SeqCst) && serial.listener_generation.load(Ordering::SeqCst) == generation {
+                // The timeout only bounds the time needed to notice that the run is over.
+                let event = match events_rx.recv_timeout(Duration::from_millis(THREAD_SLEEP * 10)) {
+                    Ok(_) if serial.listener_generation.load(Ordering::SeqCst) != generation => return,
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                let received = match event {
+                    SerialEvent::Received(buf) => Ok(buf),
+                    SerialEvent::ReadError(source) => Err(SerialError::Read { port_name: port_name.clone(), source }),
+                    SerialEvent::WriteError(source) if source.kind() == std::io::ErrorKind::TimedOut => Err(SerialError::WriteTimeout { port_name: port_name.clone() }),
+                    SerialEvent::WriteError(source) => Err(SerialError::Write { port_name: port_name.clone(), source }),
+                };
+                let buf = match received {
+                    Ok(buf) => buf,
+                    Err(error) => {
+                        serial.metrics.serial_error();
+                        if serial.settings.reconnect_policy.enabled && serial.reconnect(&is_running, &motor_name, &message_tx) {
+                            continue;
+                        }
                         is_running.store(false, Ordering::SeqCst);
                         {
                             let mut lock = timers_and_phases.lock();
@@ -94,123 +345,223 @@ impl Serial {
                             lock.main_phase = StepperState::Invalid;
                             lock.main_phase_start_time = None;
                         }
-                        // port.lock().take();
-                        let error = Some(anyhow!(err));
-                        let message: Message = Message::new(ToastKind::Error, &format!("Error while reading serial port {} - ⚠️YOU SHOULD RECONNECT⚠️", port_name), error, Some(motor_name.clone()), 5, false);
-                        message_tx.as_ref().unwrap().send(message).unwrap();
+                        serial.events.publish(MotorEvent::connection_lost(&motor_name, &port_name));
+                        serial.metrics.run_faulted(StepperState::Invalid);
+                        let message: Message = Message::new(MessageKind::Error, &format!("Connection lost with serial port {}", port_name), Some(anyhow!(error)), Some(motor_name), 5, false);
+                        if let Some(message_tx) = &message_tx {
+                            message_tx.send(message).ok();
+                        }
                         return;
                     }
                 };
-                if is_byte != 0 {
-                    buf = [0u8; 3];
-                    let read_exact_result = port.lock().as_mut().unwrap().read_exact(&mut buf);
-                    match read_exact_result {
-                        Ok(_) => {
-                            let state: StepperState = StepperState::from(&buf);
-                            let origin = Some(motor_name.clone());
-                            let message = state.to_string();
-                            match state {
-                                StepperState::Invalid => {
-                                    is_running.store(false, Ordering::SeqCst);
-                                    {
-                                        let mut lock = timers_and_phases.lock();
-                                        lock.set_global_stop_time_stopped();
-                                        lock.sub_phase = StepperState::Invalid;
-                                        lock.sub_phase_start_time = None;
-                                        lock.main_phase = StepperState::Invalid;
-                                        lock.main_phase_start_time = None;
-                                    }
-                                    // port.lock().take();
-                                    let error = Some(anyhow!("Invalid state received. Disconnecting..."));
-                                    let message: Message = Message::new(ToastKind::Error, &format!("Error while reading serial port {} - ⚠️YOU SHOULD RECONNECT⚠️", port_name), error, Some(motor_name), 5, false);
-                                    message_tx.as_ref().unwrap().send(message).unwrap();
-                                    return;
-                                }
-                                StepperState::CommandReceived => {}
-                                StepperState::StepgenAgitationError | StepperState::StepgenRotationError | StepperState::EmergencyStop | StepperState::OpenLoad
-                                | StepperState::OverHeat | StepperState::OverCurrent => {
-                                    is_running.store(false, Ordering::SeqCst);
-                                    timers_and_phases.lock().set_global_stop_time_stopped();
-                                    {
-                                        let mut lock = timers_and_phases.lock();
-                                        lock.sub_phase = state;
-                                        lock.sub_phase_start_time = None;
-                                        lock.main_phase = state;
-                                        lock.main_phase_start_time = None;
-                                    }
-                                    let error = Some(anyhow!("Motor stopped !"));
-                                    let message: Message = Message::new(ToastKind::Error, &message, error, origin, 5, false);
-                                    message_tx.as_ref().unwrap().send(message).unwrap();
-                                }
-                                StepperState::Finished => {
-                                    is_running.store(false, Ordering::SeqCst);
-                                    {
-                                        let mut lock = timers_and_phases.lock();
-                                        lock.set_global_stop_time_stopped();
-                                        lock.sub_phase = state;
-                                        lock.sub_phase_start_time = None;
-                                        lock.main_phase = state;
-                                        lock.main_phase_start_time = None;
-                                    }
-                                    let message: Message = Message::new(ToastKind::Success, &message, None, origin, 5, false);
-                                    message_tx.as_ref().unwrap().send(message).unwrap();
-                                }
-                                StepperState::StartRotation | StepperState::StartAgitation => {
-                                    let mut lock = timers_and_phases.lock();
-                                    lock.main_phase = state;
-                                    lock.main_phase_start_time = Some(Instant::now());
-                                }
-                                StepperState::OscillationRotation => {
-                                    let mut lock = timers_and_phases.lock();
-                                    let direction = lock.rotation_direction.reverse();
-                                    lock.rotation_direction = direction;
-                                    lock.sub_phase = state;
-                                    lock.sub_phase_start_time = Some(Instant::now());
-                                }
-                                StepperState::OscillationAgitation => {
-                                    let mut lock = timers_and_phases.lock();
-                                    let direction = lock.agitation_direction.reverse();
-                                    lock.agitation_direction = direction;
-                                    lock.sub_phase = state;
-                                    lock.sub_phase_start_time = Some(Instant::now());
-                                }
-                                _ => {
-                                    let mut lock = timers_and_phases.lock();
-                                    lock.sub_phase = state;
-                                    lock.sub_phase_start_time = Some(Instant::now());
-                                }
-                            }
+                let state: StepperState = StepperState::from(&buf);
+                if !matches!(state, StepperState::CommandReceived | StepperState::Acknowledged(_) | StepperState::NotAcknowledged(_)) {
+                    serial.events.publish(MotorEvent::new(&motor_name, &port_name, state));
+                }
+                let origin = Some(motor_name.clone());
+                let message = state.to_string();
+                match state {
+                    StepperState::Invalid => {
+                        is_running.store(false, Ordering::SeqCst);
+                        serial.metrics.serial_error();
+                        serial.metrics.run_faulted(state);
+                        {
+                            let mut lock = timers_and_phases.lock();
+                            lock.set_global_stop_time_stopped();
+                            lock.sub_phase = StepperState::Invalid;
+                            lock.sub_phase_start_time = None;
+                            lock.main_phase = StepperState::Invalid;
+                            lock.main_phase_start_time = None;
+                        }
+                        let error = Some(anyhow!(SerialError::InvalidState { port_name: port_name.clone(), bytes: buf }));
+                        let message: Message = Message::new(MessageKind::Error, &format!("Error while reading serial port {}", port_name), error, Some(motor_name), 5, false);
+                        if let Some(message_tx) = &message_tx {
+                            message_tx.send(message).ok();
+                        }
+                        return;
+                    }
+                    // Handed by the reader to the command waiting for it.
+                    StepperState::CommandReceived | StepperState::Acknowledged(_) | StepperState::NotAcknowledged(_) => {}
+                    StepperState::StepgenAgitationError | StepperState::StepgenRotationError | StepperState::EmergencyStop | StepperState::OpenLoad
+                    | StepperState::OverHeat | StepperState::OverCurrent => {
+                        is_running.store(false, Ordering::SeqCst);
+                        serial.metrics.run_faulted(state);
+                        timers_and_phases.lock().set_global_stop_time_stopped();
+                        {
+                            let mut lock = timers_and_phases.lock();
+                            lock.sub_phase = state;
+                            lock.sub_phase_start_time = None;
+                            lock.main_phase = state;
+                            lock.main_phase_start_time = None;
+                        }
+                        let error = Some(anyhow!("Motor stopped !"));
+                        let message: Message = Message::new(MessageKind::Error, &message, error, origin, 5, false);
+                        if let Some(message_tx) = &message_tx {
+                            message_tx.send(message).ok();
+                        }
+                    }
+                    StepperState::Finished => {
+                        is_running.store(false, Ordering::SeqCst);
+                        serial.metrics.run_finished();
+                        {
+                            let mut lock = timers_and_phases.lock();
+                            lock.set_global_stop_time_stopped();
+                            lock.sub_phase = state;
+                            lock.sub_phase_start_time = None;
+                            lock.main_phase = state;
+                            lock.main_phase_start_time = None;
                         }
-                        Err(err) => {
-                            is_running.store(false, Ordering::SeqCst);
-                            {
-                                let mut lock = timers_and_phases.lock();
-                                lock.set_global_stop_time_stopped();
-                                lock.sub_phase = StepperState::Invalid;
-                                lock.sub_phase_start_time = None;
-                                lock.main_phase = StepperState::Invalid;
-                                lock.main_phase_start_time = None;
-                            }
-                            // port.lock().take();
-                            let error = Some(anyhow!(err));
-                            let message: Message = Message::new(ToastKind::Error, &format!("Error while reading serial port {} - ⚠️YOU SHOULD RECONNECT⚠️", port_name), error, Some(motor_name), 5, false);
-                            message_tx.as_ref().unwrap().send(message).unwrap();
-                            return;
+                        let message: Message = Message::new(MessageKind::Success, &message, None, origin, 5, false);
+                        if let Some(message_tx) = &message_tx {
+                            message_tx.send(message).ok();
                         }
                     }
+                    StepperState::StartRotation | StepperState::StartAgitation | StepperState::StartStep(_) => {
+                        let mut lock = timers_and_phases.lock();
+                        lock.main_phase = state;
+                        lock.main_phase_start_time = Some(Instant::now());
+                    }
+                    StepperState::OscillationRotation => {
+                        let mut lock = timers_and_phases.lock();
+                        let direction = lock.rotation_direction.reverse();
+                        lock.rotation_direction = direction;
+                        lock.sub_phase = state;
+                        lock.sub_phase_start_time = Some(Instant::now());
+                    }
+                    StepperState::OscillationAgitation => {
+                        let mut lock = timers_and_phases.lock();
+                        let direction = lock.agitation_direction.reverse();
+                        lock.agitation_direction = direction;
+                        lock.sub_phase = state;
+                        lock.sub_phase_start_time = Some(Instant::now());
+                    }
+                    _ => {
+                        let mut lock = timers_and_phases.lock();
+                        lock.sub_phase = state;
+                        lock.sub_phase_start_time = Some(Instant::now());
+                    }
                 }
-                thread::sleep(Duration::from_millis(THREAD_SLEEP));
             }
         });
     }
 
-    pub fn send_bytes(&self, bytes: &[u8]) {
-        let now = Instant::now();
-        let future = now + Duration::from_millis(THREAD_SLEEP + 5);
-        if let Some(mut lock) = self.port.try_lock_until(future) {
-            if let Some(port) = lock.as_mut() {
-                port.write_all(bytes).ok();
+    /// Send the protocol with the frame format of the port and wait for the firmware to acknowledge it.
+    pub fn send_protocol(&self, protocol: &Protocol) -> Result<(), SerialError> {
+        match self.settings.frame_format {
+            FrameFormat::Legacy => self.send_command(&protocol.protocol_as_bytes(), Acknowledgement::CommandReceived),
+            FrameFormat::Framed => {
+                let sequence_id = self.sequence_id.fetch_add(1, Ordering::SeqCst);
+                let frame = protocol.protocol_as_frame(sequence_id).map_err(|err| SerialError::Encode { port_name: self.port_name.clone(), reason: err.to_string() })?;
+                self.send_command(&frame, Acknowledgement::Frame(sequence_id))
+            }
+        }
+    }
+
+    /// Send a step protocol and wait for the firmware to acknowledge it. Step protocols are only sent framed.
+    pub fn send_step_protocol(&self, step_protocol: &StepProtocol) -> Result<(), SerialError> {
+        let sequence_id = self.sequence_id.fetch_add(1, Ordering::SeqCst);
+        let frame = step_protocol.step_protocol_as_frame(sequence_id).map_err(|err| SerialError::Encode { port_name: self.port_name.clone(), reason: err.to_string() })?;
+        self.send_command(&frame, Acknowledgement::Frame(sequence_id))
+    }
+
+    /// Send a command and wait for its acknowledgement. A frame is sent again up to `command_retries` times,
+    /// its sequence id letting the firmware drop a duplicate. A rejected frame is sent again too, as it was most likely corrupted on the way.
+    /// Unframed commands are sent once, the firmware being unable to tell a repeated legacy upload from a new one.
+    pub fn send_command(&self, bytes: &[u8], acknowledgement: Acknowledgement) -> Result<(), SerialError> {
+        let _command = self.command_lock.lock();
+        let attempts = match acknowledgement {
+            Acknowledgement::CommandReceived => 1,
+            Acknowledgement::Frame(_) => self.settings.command_retries + 1,
+        };
+        let mut rejected_sequence_id = None;
+        for attempt in 1..=attempts {
+            let mut last = self.acks.last.lock();
+            last.take();
+            self.send_bytes(bytes)?;
+            let deadline = Instant::now() + Duration::from_millis(self.settings.ack_timeout_ms);
+            loop {
+                match (*last, acknowledgement) {
+                    (Some(StepperState::CommandReceived), Acknowledgement::CommandReceived) => return Ok(()),
+                    (Some(StepperState::Acknowledged(received)), Acknowledgement::Frame(expected)) if received == expected => return Ok(()),
+                    (Some(StepperState::NotAcknowledged(received)), Acknowledgement::Frame(expected)) if received == expected => {
+                        rejected_sequence_id = Some(received);
+                        break;
+                    }
+                    _ => {}
+                }
+                last.take();
+                if self.acks.received.wait_until(&mut last, deadline).timed_out() {
+                    rejected_sequence_id = None;
+                    break;
+                }
             }
+            tracing::warn!("{} - Command not acknowledged, attempt {}/{}", self.port_name, attempt, attempts);
         }
+        self.metrics.serial_error();
+        if let Some(sequence_id) = rejected_sequence_id {
+            return Err(SerialError::Rejected { port_name: self.port_name.clone(), sequence_id });
+        }
+        Err(SerialError::AckTimeout { port_name: self.port_name.clone(), attempts })
+    }
+
+    /// Stop the listener of the previous run and drop what was received since.
+    /// The events received from now on are kept for the next listener.
+    pub fn clear_events(&self) {
+        self.listener_generation.fetch_add(1, Ordering::SeqCst);
+        let events_rx = self.replace_events_channel();
+        *self.events_rx.lock() = Some(events_rx);
+    }
+
+    /// Send the next events to a new channel, the receiver of the previous one being disconnected.
+    fn replace_events_channel(&self) -> Receiver<SerialEvent> {
+        let (events_tx, events_rx) = channel();
+        *self.events_tx.lock() = events_tx;
+        events_rx
     }
-}
\ No newline at end of file
+
+    /// Queue the bytes for the writer thread. Fails only if the port is not open.
+    pub fn send_bytes(&self, bytes: &[u8]) -> Result<(), SerialError> {
+        let not_connected = || SerialError::NotConnected { port_name: self.port_name.clone() };
+        let io = self.io.lock();
+        let Some(io) = io.as_ref() else {
+            return Err(not_connected());
+        };
+        io.writer_tx.send(bytes.to_vec()).map_err(|_| not_connected())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::utils::simulator::FirmwareSimulator;
+
+    #[test]
+    fn listener_does_not_hold_back_the_status_query_and_the_clearing() {
+        let mut simulator = FirmwareSimulator::new(None).unwrap();
+        let port_name = simulator.slave_path().to_string_lossy().to_string();
+        thread::spawn(move || simulator.run());
+        let settings = SerialSettings { frame_format: FrameFormat::Framed, ..Default::default() };
+        let serial = Serial::new(&port_name, settings, Arc::new(Mutex::new(vec![]))).unwrap();
+        let mut protocol = Protocol::default();
+        protocol.rotation.rpm = 60;
+        protocol.rotation.duration_of_one_direction_cycle_ms = 1_000;
+        protocol.rotation_duration_ms = 10_000;
+        protocol.global_duration_ms = 10_000;
+        serial.clear_events();
+        serial.send_protocol(&protocol).unwrap();
+        let is_running = Arc::new(AtomicBool::new(true));
+        let timers_and_phases = Arc::new(Mutex::new(TimersAndPhases::default()));
+        serial.listen_to_serial_port("Motor".into(), &is_running, &timers_and_phases, None);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(timers_and_phases.lock().main_phase, StepperState::StartRotation);
+
+        let start = Instant::now();
+        let status = serial.query_status(1_000).unwrap().unwrap();
+        assert!(status.is_running);
+        assert_eq!(status.main_phase, StepperState::StartRotation);
+        serial.clear_events();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        serial.send_command(b"stop", Acknowledgement::CommandReceived).unwrap();
+        serial.disconnect();
+    }
+}
@@ -0,0 +1,605 @@
+// Every gRPC call returns a `tonic::Status` as error, which clippy finds too large.
+#![allow(clippy::result_large_err)]
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+
+use crate::utils::enums::{Direction, MessageKind, StepMode128};
+use crate::utils::errors::{ApiError, MotorError};
+use crate::utils::motor::{self, Motor};
+use crate::utils::protocols::{Protocol, Rotation};
+use crate::utils::structs::Message;
+
+use self::proto::sila2::com::gropplero::instruments::cellspinnercontroller::v1 as cellspinnercontroller;
+use self::proto::sila2::org::silastandard as framework;
+use self::proto::sila2::org::silastandard::core::silaservice::v1 as silaservice;
+use cellspinnercontroller::cell_spinner_controller_server::{CellSpinnerController, CellSpinnerControllerServer};
+use cellspinnercontroller::data_type_protocol::ProtocolStruct;
+use cellspinnercontroller::subscribe_phase_responses::PhaseStruct;
+use cellspinnercontroller::subscribe_progress_responses::ProgressStruct;
+use silaservice::si_la_service_server::{SiLaService, SiLaServiceServer};
+
+/// Code generated by `build.rs` from the proto files of `src/resources/sila`.
+#[allow(clippy::all, dead_code, non_camel_case_types)]
+mod proto {
+    pub mod sila2 {
+        pub mod org {
+            pub mod silastandard {
+                tonic::include_proto!("sila2.org.silastandard");
+
+                pub mod core {
+                    pub mod silaservice {
+                        pub mod v1 {
+                            tonic::include_proto!("sila2.org.silastandard.core.silaservice.v1");
+                        }
+                    }
+                }
+            }
+        }
+
+        pub mod com {
+            pub mod gropplero {
+                pub mod instruments {
+                    pub mod cellspinnercontroller {
+                        pub mod v1 {
+                            tonic::include_proto!("sila2.com.gropplero.instruments.cellspinnercontroller.v1");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+const SERVICE_FEATURE: &str = "org.silastandard/core/SiLAService/v1";
+const CONTROLLER_FEATURE: &str = "com.gropplero/instruments/CellSpinnerController/v1";
+/// Fully qualified identifiers and definitions of the implemented features.
+const FEATURES: [(&str, &str); 2] = [
+    (SERVICE_FEATURE, include_str!("../resources/sila/SiLAService.sila.xml")),
+    (CONTROLLER_FEATURE, include_str!("../resources/sila/CellSpinnerController.sila.xml")),
+];
+const SERVER_TYPE: &str = "CellSpinner";
+const SERVER_DESCRIPTION: &str = "Cell Spinner motors, controlled through their Raspberry Pi";
+const SERVER_VENDOR_URL: &str = "https://gropplero.com";
+const MAX_SERVER_NAME_LENGTH: usize = 255;
+/// Time between two checks of the values of the observable properties.
+const POLL_INTERVAL_MS: u64 = 250;
+const WORKER_THREADS: usize = 2;
+
+/// Settings of the SiLA 2 server, saved with the app.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilaSettings {
+    pub is_enabled: bool,
+    /// `0.0.0.0` to accept the schedulers of the network. The connection is not encrypted.
+    pub address: String,
+    pub port: u16,
+    /// Can also be changed by the clients with `SetServerName`.
+    pub server_name: String,
+    /// Generated once, the clients telling the servers apart with it.
+    pub server_uuid: String,
+}
+
+impl Default for SilaSettings {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 50052,
+            server_name: "Cell Spinner".to_string(),
+            server_uuid: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// SiLA 2 server letting the schedulers of lab automation systems control the motors of the app. It implements:
+/// - `org.silastandard/core/SiLAService/v1`, mandatory for every SiLA server
+/// - `com.gropplero/instruments/CellSpinnerController/v1`: the `SetProtocol`, `StartMotor` and `StopMotor` commands,
+///   and the `Phase` and `Progress` observable properties
+///
+/// The ids of the motors are the numbers of the tabs. There is no discovery over mDNS and no TLS: the clients connect
+/// to the address of the settings in plain text. The server stops when dropped.
+pub struct SilaServer {
+    pub address: String,
+    server_name: Arc<Mutex<String>>,
+    is_stopped: Arc<AtomicBool>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SilaServer {
+    /// Listen in a thread of its own running the gRPC services. The messages of the motors are sent to `message_tx`, like the ones of the tabs.
+    pub fn start(settings: &SilaSettings, motors: Arc<DashMap<usize, Motor>>, message_tx: Option<Sender<Message>>) -> Result<Self, ApiError> {
+        let address = format!("{}:{}", settings.address, settings.port);
+        let bind_error = |err: io::Error| ApiError::Bind { address: address.clone(), reason: err.to_string() };
+        let listener = TcpListener::bind(&address).map_err(bind_error)?;
+        listener.set_nonblocking(true).map_err(bind_error)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(WORKER_THREADS).enable_all().build().map_err(bind_error)?;
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let server_name = Arc::new(Mutex::new(settings.server_name.clone()));
+        let service = Service { server_name: server_name.clone(), server_uuid: settings.server_uuid.clone() };
+        let controller = Controller { motors, message_tx: Arc::new(Mutex::new(message_tx)), is_stopped: is_stopped.clone() };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let handle = thread::spawn(move || {
+            let result = runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                Server::builder()
+                    .add_service(SiLaServiceServer::new(service))
+                    .add_service(CellSpinnerControllerServer::new(controller))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async { shutdown_rx.await.ok(); })
+                    .await?;
+                Ok::<(), anyhow::Error>(())
+            });
+            if let Err(err) = result {
+                tracing::error!("SiLA server stopped: {}", err);
+            }
+        });
+        tracing::info!("SiLA server listening on {}", address);
+        Ok(Self { address, server_name, is_stopped, shutdown_tx: Some(shutdown_tx), handle: Some(handle) })
+    }
+
+    /// Name of the server, which the clients may have changed.
+    pub fn server_name(&self) -> String {
+        self.server_name.lock().clone()
+    }
+}
+
+impl Drop for SilaServer {
+    fn drop(&mut self) {
+        // The subscriptions to the observable properties end first, or the server would wait for them.
+        self.is_stopped.store(true, Ordering::SeqCst);
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            shutdown_tx.send(()).ok();
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+        tracing::info!("SiLA server stopped.");
+    }
+}
+
+/// `org.silastandard/core/SiLAService/v1`.
+struct Service {
+    server_name: Arc<Mutex<String>>,
+    server_uuid: String,
+}
+
+#[tonic::async_trait]
+impl SiLaService for Service {
+    async fn get_feature_definition(&self, request: Request<silaservice::GetFeatureDefinitionParameters>) -> Result<Response<silaservice::GetFeatureDefinitionResponses>, Status> {
+        let identifier = request.into_inner().feature_identifier.and_then(|identifier| identifier.feature_identifier).map(|identifier| identifier.value).unwrap_or_default();
+        // The fully qualified identifiers are not case sensitive.
+        let Some((_, definition)) = FEATURES.iter().find(|(feature, _)| feature.eq_ignore_ascii_case(&identifier)) else {
+            return Err(defined_error(SERVICE_FEATURE, "UnimplementedFeature", &format!("The feature {:?} is not implemented", identifier)));
+        };
+        Ok(Response::new(silaservice::GetFeatureDefinitionResponses { feature_definition: string(definition) }))
+    }
+
+    async fn set_server_name(&self, request: Request<silaservice::SetServerNameParameters>) -> Result<Response<silaservice::SetServerNameResponses>, Status> {
+        let server_name = request.into_inner().server_name.map(|server_name| server_name.value).unwrap_or_default();
+        if server_name.chars().count() > MAX_SERVER_NAME_LENGTH {
+            let parameter = format!("{}/Command/SetServerName/Parameter/ServerName", SERVICE_FEATURE);
+            return Err(validation_error(&parameter, &format!("The name is longer than {} characters", MAX_SERVER_NAME_LENGTH)));
+        }
+        *self.server_name.lock() = server_name;
+        Ok(Response::new(silaservice::SetServerNameResponses {}))
+    }
+
+    async fn get_server_name(&self, _request: Request<silaservice::GetServerNameParameters>) -> Result<Response<silaservice::GetServerNameResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerNameResponses { server_name: string(&self.server_name.lock()) }))
+    }
+
+    async fn get_server_type(&self, _request: Request<silaservice::GetServerTypeParameters>) -> Result<Response<silaservice::GetServerTypeResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerTypeResponses { server_type: string(SERVER_TYPE) }))
+    }
+
+    async fn get_server_uuid(&self, _request: Request<silaservice::GetServerUuidParameters>) -> Result<Response<silaservice::GetServerUuidResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerUuidResponses { server_uuid: string(&self.server_uuid) }))
+    }
+
+    async fn get_server_description(&self, _request: Request<silaservice::GetServerDescriptionParameters>) -> Result<Response<silaservice::GetServerDescriptionResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerDescriptionResponses { server_description: string(SERVER_DESCRIPTION) }))
+    }
+
+    async fn get_server_version(&self, _request: Request<silaservice::GetServerVersionParameters>) -> Result<Response<silaservice::GetServerVersionResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerVersionResponses { server_version: string(env!("CARGO_PKG_VERSION")) }))
+    }
+
+    async fn get_server_vendor_url(&self, _request: Request<silaservice::GetServerVendorUrlParameters>) -> Result<Response<silaservice::GetServerVendorUrlResponses>, Status> {
+        Ok(Response::new(silaservice::GetServerVendorUrlResponses { server_vendor_url: string(SERVER_VENDOR_URL) }))
+    }
+
+    async fn get_implemented_features(&self, _request: Request<silaservice::GetImplementedFeaturesParameters>) -> Result<Response<silaservice::GetImplementedFeaturesResponses>, Status> {
+        let implemented_features = FEATURES.iter()
+            .map(|(feature, _)| silaservice::DataTypeFeatureIdentifier { feature_identifier: string(feature) })
+            .collect();
+        Ok(Response::new(silaservice::GetImplementedFeaturesResponses { implemented_features }))
+    }
+}
+
+/// `com.gropplero/instruments/CellSpinnerController/v1`.
+#[derive(Clone)]
+struct Controller {
+    motors: Arc<DashMap<usize, Motor>>,
+    message_tx: Arc<Mutex<Option<Sender<Message>>>>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+#[tonic::async_trait]
+impl CellSpinnerController for Controller {
+    async fn set_protocol(&self, request: Request<cellspinnercontroller::SetProtocolParameters>) -> Result<Response<cellspinnercontroller::SetProtocolResponses>, Status> {
+        let parameters = request.into_inner();
+        let protocol = parameters.protocol.and_then(|protocol| protocol.protocol).ok_or_else(|| invalid_protocol("The protocol is missing".to_string()))?;
+        let controller = self.clone();
+        blocking(move || controller.with_motor(parameters.motor_id, "SetProtocol", |_, motor| controller.upload_protocol(motor, protocol))).await?;
+        Ok(Response::new(cellspinnercontroller::SetProtocolResponses {}))
+    }
+
+    async fn start_motor(&self, request: Request<cellspinnercontroller::StartMotorParameters>) -> Result<Response<cellspinnercontroller::StartMotorResponses>, Status> {
+        let motor_id = request.into_inner().motor_id;
+        let controller = self.clone();
+        blocking(move || controller.start(motor_id)).await?;
+        Ok(Response::new(cellspinnercontroller::StartMotorResponses {}))
+    }
+
+    async fn stop_motor(&self, request: Request<cellspinnercontroller::StopMotorParameters>) -> Result<Response<cellspinnercontroller::StopMotorResponses>, Status> {
+        let motor_id = request.into_inner().motor_id;
+        let controller = self.clone();
+        blocking(move || controller.with_motor(motor_id, "StopMotor", |_, motor| controller.stop(motor))).await?;
+        Ok(Response::new(cellspinnercontroller::StopMotorResponses {}))
+    }
+
+    type Subscribe_PhaseStream = ReceiverStream<Result<cellspinnercontroller::SubscribePhaseResponses, Status>>;
+
+    async fn subscribe_phase(&self, _request: Request<cellspinnercontroller::SubscribePhaseParameters>) -> Result<Response<Self::Subscribe_PhaseStream>, Status> {
+        Ok(Response::new(self.subscribe(Controller::phase)))
+    }
+
+    type Subscribe_ProgressStream = ReceiverStream<Result<cellspinnercontroller::SubscribeProgressResponses, Status>>;
+
+    async fn subscribe_progress(&self, _request: Request<cellspinnercontroller::SubscribeProgressParameters>) -> Result<Response<Self::Subscribe_ProgressStream>, Status> {
+        Ok(Response::new(self.subscribe(Controller::progress)))
+    }
+}
+
+impl Controller {
+    /// The action must not wait for the firmware, the motor being locked meanwhile.
+    fn with_motor<T>(&self, motor_id: Option<framework::Integer>, command: &str, action: impl FnOnce(usize, &mut Motor) -> Result<T, Status>) -> Result<T, Status> {
+        let motor_id = motor_id.map(|motor_id| motor_id.value).unwrap_or_default();
+        let unknown_motor = || validation_error(&format!("{}/Command/{}/Parameter/MotorId", CONTROLLER_FEATURE, command), &format!("Unknown motor {}", motor_id));
+        let tab = usize::try_from(motor_id).map_err(|_| unknown_motor())?;
+        let mut motor = self.motors.get_mut(&tab).ok_or_else(unknown_motor)?;
+        action(tab, &mut motor)
+    }
+
+    fn upload_protocol(&self, motor: &mut Motor, protocol: ProtocolStruct) -> Result<(), Status> {
+        if motor.get_is_running() {
+            return Err(defined_error(CONTROLLER_FEATURE, "MotorRunning", "The motor is running. Stop it before uploading a protocol."));
+        }
+        let protocol = protocol_from_struct(protocol, motor.protocol)?;
+        match motor.import_protocol(protocol) {
+            Ok(_) => {}
+            Err(MotorError::InvalidProtocol(issues)) => return Err(defined_error(CONTROLLER_FEATURE, "InvalidProtocol", &join_issues(issues.iter().map(|issue| issue.to_string())))),
+            Err(err) => return Err(undefined_error(&err.to_string())),
+        }
+        motor.is_step_protocol = false;
+        motor.generate_graph_rotation();
+        motor.generate_graph_agitation();
+        motor.calculate_expected_end_date();
+        motor.is_protocol_updated = true;
+        self.send(Message::new(MessageKind::Info, "Protocol uploaded by a SiLA client", None, Some(motor.name.clone()), 3, false));
+        Ok(())
+    }
+
+    /// The motor is released while the firmware acknowledges the protocol.
+    fn start(&self, motor_id: Option<framework::Integer>) -> Result<(), Status> {
+        let tab = self.with_motor(motor_id.clone(), "StartMotor", |tab, motor| {
+            if !motor.get_is_connected() {
+                return Err(defined_error(CONTROLLER_FEATURE, "MotorNotConnected", "The motor is not connected"));
+            }
+            if motor.get_is_running() {
+                return Err(defined_error(CONTROLLER_FEATURE, "MotorRunning", "The motor is already running"));
+            }
+            Ok(tab)
+        })?;
+        // The messages are sent to the app since the listener of the run keeps the channel.
+        motor::start_motor_in(&self.motors, tab, self.message_tx.lock().clone());
+        self.with_motor(motor_id, "StartMotor", |_, motor| {
+            if motor.get_is_running() {
+                return Ok(());
+            }
+            let issues = if motor.is_step_protocol { motor.step_protocol.validate() } else { motor.protocol.validate_run() };
+            let issues: Vec<String> = issues.into_iter().filter(|issue| issue.is_error()).map(|issue| issue.to_string()).collect();
+            if issues.is_empty() {
+                Err(defined_error(CONTROLLER_FEATURE, "StartFailed", "The protocol was not started"))
+            } else {
+                Err(defined_error(CONTROLLER_FEATURE, "InvalidProtocol", &join_issues(issues.into_iter())))
+            }
+        })
+    }
+
+    fn stop(&self, motor: &mut Motor) -> Result<(), Status> {
+        if !motor.get_is_running() {
+            return Err(defined_error(CONTROLLER_FEATURE, "MotorNotRunning", "The motor is not running"));
+        }
+        motor.stop_motor(self.message_tx.lock().clone());
+        Ok(())
+    }
+
+    /// Stream of the values of an observable property, sent again when they change.
+    /// The values are read on the blocking threads, the motors and their timers being locked by the app and the listeners meanwhile.
+    fn subscribe<T: Clone + PartialEq + Send + 'static>(&self, value: fn(&Controller) -> T) -> ReceiverStream<Result<T, Status>> {
+        let (value_tx, value_rx) = mpsc::channel(1);
+        let controller = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(POLL_INTERVAL_MS));
+            let mut last_value = None;
+            while !controller.is_stopped.load(Ordering::SeqCst) && !value_tx.is_closed() {
+                interval.tick().await;
+                let reader = controller.clone();
+                let Ok(value) = tokio::task::spawn_blocking(move || value(&reader)).await else {
+                    break;
+                };
+                if last_value.as_ref() == Some(&value) {
+                    continue;
+                }
+                last_value = Some(value.clone());
+                if value_tx.send(Ok(value)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        ReceiverStream::new(value_rx)
+    }
+
+    fn phase(&self) -> cellspinnercontroller::SubscribePhaseResponses {
+        let phase = self.sorted_tabs().into_iter()
+            .filter_map(|tab| self.motors.get(&tab).map(|motor| {
+                let timers_and_phases = motor.timers_and_phases.lock();
+                PhaseStruct {
+                    motor_id: integer(tab as i64),
+                    name: string(&motor.name),
+                    is_connected: boolean(motor.get_is_connected()),
+                    is_running: boolean(motor.get_is_running()),
+                    main_phase: string(&format!("{:?}", timers_and_phases.main_phase)),
+                    sub_phase: string(&format!("{:?}", timers_and_phases.sub_phase)),
+                    is_fault: boolean(timers_and_phases.main_phase.is_fault()),
+                }
+            }))
+            .collect();
+        cellspinnercontroller::SubscribePhaseResponses { phase }
+    }
+
+    fn progress(&self) -> cellspinnercontroller::SubscribeProgressResponses {
+        let progress = self.sorted_tabs().into_iter()
+            .filter_map(|tab| self.motors.get(&tab).map(|motor| {
+                let elapsed_ms = motor.timers_and_phases.lock().get_run_elapsed_ms().unwrap_or(0);
+                let duration_ms = motor.get_global_duration_ms();
+                let progress = if duration_ms == 0 { 0.0 } else { (elapsed_ms as f64 / duration_ms as f64).min(1.0) };
+                ProgressStruct {
+                    motor_id: integer(tab as i64),
+                    elapsed_time: integer(elapsed_ms as i64),
+                    duration: integer(duration_ms as i64),
+                    progress: Some(framework::Real { value: progress }),
+                }
+            }))
+            .collect();
+        cellspinnercontroller::SubscribeProgressResponses { progress }
+    }
+
+    fn sorted_tabs(&self) -> Vec<usize> {
+        let mut tabs: Vec<usize> = self.motors.iter().map(|motor| *motor.key()).collect();
+        tabs.sort_unstable();
+        tabs
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(message_tx) = self.message_tx.lock().as_ref() {
+            message_tx.send(message).ok();
+        }
+    }
+}
+
+/// `protocol` with the values of a `Protocol` structure. The fields that are not mapped are kept.
+fn protocol_from_struct(value: ProtocolStruct, protocol: Protocol) -> Result<Protocol, Status> {
+    Ok(Protocol {
+        rotation: rotation_from_struct(value.rotation, "Rotation", protocol.rotation)?,
+        rotation_duration_ms: field(value.rotation_duration, "RotationDuration")?,
+        pause_pre_agitation_ms: field(value.pause_pre_agitation, "PausePreAgitation")?,
+        agitation: rotation_from_struct(value.agitation, "Agitation", protocol.agitation)?,
+        agitation_duration_ms: field(value.agitation_duration, "AgitationDuration")?,
+        pause_post_agitation_ms: field(value.pause_post_agitation, "PausePostAgitation")?,
+        global_duration_ms: field(value.global_duration, "GlobalDuration")?,
+    })
+}
+
+fn rotation_from_struct(value: Option<cellspinnercontroller::DataTypeRotation>, name: &str, rotation: Rotation) -> Result<Rotation, Status> {
+    let value = value.and_then(|value| value.rotation).ok_or_else(|| invalid_protocol(format!("{} is missing", name)))?;
+    let step_mode = value.step_mode.map(|step_mode| step_mode.value).unwrap_or_default();
+    let direction = value.direction.map(|direction| direction.value).unwrap_or_default();
+    Ok(Rotation {
+        rpm: field(value.rpm, &format!("{}.Rpm", name))?,
+        acceleration: field(value.acceleration, &format!("{}.Acceleration", name))?,
+        step_mode: StepMode128::Full.get_modes().into_iter().find(|mode| mode.to_string() == step_mode)
+            .ok_or_else(|| invalid_protocol(format!("Unknown step mode {:?} for {}.StepMode", step_mode, name)))?,
+        direction: [Direction::Forward, Direction::Backward].into_iter().find(|known| known.to_string() == direction)
+            .ok_or_else(|| invalid_protocol(format!("Unknown direction {:?} for {}.Direction", direction, name)))?,
+        duration_of_one_direction_cycle_ms: field(value.cycle_duration, &format!("{}.CycleDuration", name))?,
+        pause_before_direction_change_ms: field(value.pause_before_direction_change, &format!("{}.PauseBeforeDirectionChange", name))?,
+        ..rotation
+    })
+}
+
+/// Value of an `Integer` element of the `Protocol` structure.
+fn field<T: TryFrom<i64>>(value: Option<framework::Integer>, name: &str) -> Result<T, Status> {
+    let value = value.ok_or_else(|| invalid_protocol(format!("{} is missing", name)))?.value;
+    T::try_from(value).map_err(|_| invalid_protocol(format!("{} is out of range: {}", name, value)))
+}
+
+fn invalid_protocol(message: String) -> Status {
+    validation_error(&format!("{}/Command/SetProtocol/Parameter/Protocol", CONTROLLER_FEATURE), &message)
+}
+
+fn join_issues(issues: impl Iterator<Item=String>) -> String {
+    issues.collect::<Vec<String>>().join(" ")
+}
+
+/// Run a command of the motors, which waits for the firmware, outside of the threads of the runtime.
+async fn blocking(action: impl FnOnce() -> Result<(), Status> + Send + 'static) -> Result<(), Status> {
+    tokio::task::spawn_blocking(action).await.unwrap_or_else(|err| Err(undefined_error(&err.to_string())))
+}
+
+/// The SiLA errors are sent as the base64 encoded `SiLAError` of a gRPC status ABORTED.
+fn sila_error(error: framework::si_la_error::Error) -> Status {
+    let error = framework::SiLaError { error: Some(error) };
+    Status::aborted(BASE64_STANDARD.encode(error.encode_to_vec()))
+}
+
+fn validation_error(parameter: &str, message: &str) -> Status {
+    sila_error(framework::si_la_error::Error::ValidationError(framework::ValidationError { parameter: parameter.to_string(), message: message.to_string() }))
+}
+
+fn defined_error(feature: &str, identifier: &str, message: &str) -> Status {
+    let error_identifier = format!("{}/DefinedExecutionError/{}", feature, identifier);
+    sila_error(framework::si_la_error::Error::DefinedExecutionError(framework::DefinedExecutionError { error_identifier, message: message.to_string() }))
+}
+
+fn undefined_error(message: &str) -> Status {
+    sila_error(framework::si_la_error::Error::UndefinedExecutionError(framework::UndefinedExecutionError { message: message.to_string() }))
+}
+
+fn string(value: &str) -> Option<framework::String> {
+    Some(framework::String { value: value.to_string() })
+}
+
+fn integer(value: i64) -> Option<framework::Integer> {
+    Some(framework::Integer { value })
+}
+
+fn boolean(value: bool) -> Option<framework::Boolean> {
+    Some(framework::Boolean { value })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::future::Future;
+
+    use cellspinnercontroller::cell_spinner_controller_client::CellSpinnerControllerClient;
+    use cellspinnercontroller::data_type_rotation::RotationStruct;
+    use framework::si_la_error::Error as SilaError;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::utils::constants::MAX_RPM;
+    use crate::utils::simulator::FirmwareSimulator;
+    use crate::utils::structs::SerialSettings;
+
+    fn rotation(rpm: i64) -> Option<cellspinnercontroller::DataTypeRotation> {
+        Some(cellspinnercontroller::DataTypeRotation {
+            rotation: Some(RotationStruct {
+                rpm: integer(rpm),
+                acceleration: integer(100),
+                step_mode: string(&StepMode128::Full.to_string()),
+                direction: string(&Direction::Forward.to_string()),
+                cycle_duration: integer(1_000),
+                pause_before_direction_change: integer(0),
+            }),
+        })
+    }
+
+    fn set_protocol(motor_id: i64, rpm: i64) -> cellspinnercontroller::SetProtocolParameters {
+        let protocol = ProtocolStruct {
+            rotation: rotation(rpm),
+            rotation_duration: integer(60_000),
+            pause_pre_agitation: integer(0),
+            agitation: rotation(30),
+            agitation_duration: integer(0),
+            pause_post_agitation: integer(0),
+            global_duration: integer(60_000),
+        };
+        cellspinnercontroller::SetProtocolParameters { motor_id: integer(motor_id), protocol: Some(cellspinnercontroller::DataTypeProtocol { protocol: Some(protocol) }) }
+    }
+
+    /// SiLA error sent in the status of a failed call.
+    fn sila_error(status: Status) -> SilaError {
+        let bytes = BASE64_STANDARD.decode(status.message()).unwrap();
+        framework::SiLaError::decode(&bytes[..]).unwrap().error.unwrap()
+    }
+
+    fn defined_error_identifier(status: Status) -> String {
+        match sila_error(status) {
+            SilaError::DefinedExecutionError(error) => error.error_identifier,
+            error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    async fn within<T>(future: impl Future<Output=T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), future).await.expect("Timed out")
+    }
+
+    #[test]
+    fn protocol_is_set_started_followed_and_stopped() {
+        let mut simulator = FirmwareSimulator::new(None).unwrap();
+        let port_name = simulator.slave_path().to_string_lossy().to_string();
+        thread::spawn(move || simulator.run());
+        let motor = Motor::new(port_name, "Motor".into(), SerialSettings::default(), Arc::new(Mutex::new(vec![]))).unwrap();
+        let motors = Arc::new(DashMap::new());
+        motors.insert(1, motor);
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server = SilaServer::start(&SilaSettings { port, ..Default::default() }, motors.clone(), None).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut client = CellSpinnerControllerClient::connect(format!("http://127.0.0.1:{}", port)).await.unwrap();
+            let error = client.set_protocol(set_protocol(1, MAX_RPM as i64 + 1)).await.unwrap_err();
+            assert_eq!(defined_error_identifier(error), format!("{}/DefinedExecutionError/InvalidProtocol", CONTROLLER_FEATURE));
+            let error = client.set_protocol(set_protocol(2, 60)).await.unwrap_err();
+            assert!(matches!(sila_error(error), SilaError::ValidationError(_)));
+            client.set_protocol(set_protocol(1, 60)).await.unwrap();
+            assert_eq!(motors.get(&1).unwrap().protocol.rotation.rpm, 60);
+
+            let mut phases = client.subscribe_phase(cellspinnercontroller::SubscribePhaseParameters {}).await.unwrap().into_inner();
+            let phase = within(phases.next()).await.unwrap().unwrap().phase.remove(0);
+            assert_eq!(phase.motor_id.unwrap().value, 1);
+            assert!(!phase.is_running.unwrap().value);
+
+            client.start_motor(cellspinnercontroller::StartMotorParameters { motor_id: integer(1) }).await.unwrap();
+            let error = client.start_motor(cellspinnercontroller::StartMotorParameters { motor_id: integer(1) }).await.unwrap_err();
+            assert_eq!(defined_error_identifier(error), format!("{}/DefinedExecutionError/MotorRunning", CONTROLLER_FEATURE));
+            loop {
+                let phase = within(phases.next()).await.unwrap().unwrap().phase.remove(0);
+                if phase.is_running.unwrap().value && phase.main_phase.unwrap().value == "StartRotation" {
+                    break;
+                }
+            }
+
+            client.stop_motor(cellspinnercontroller::StopMotorParameters { motor_id: integer(1) }).await.unwrap();
+            loop {
+                let phase = within(phases.next()).await.unwrap().unwrap().phase.remove(0);
+                if !phase.is_running.unwrap().value {
+                    break;
+                }
+            }
+            let error = client.stop_motor(cellspinnercontroller::StopMotorParameters { motor_id: integer(1) }).await.unwrap_err();
+            assert_eq!(defined_error_identifier(error), format!("{}/DefinedExecutionError/MotorNotRunning", CONTROLLER_FEATURE));
+        });
+        drop(runtime);
+        drop(server);
+        motors.get(&1).unwrap().disconnect(None);
+    }
+}
@@ -0,0 +1,515 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::iter::Peekable;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::{bail, Error};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::openpty;
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::ttyname;
+
+use crate::utils::constants::{BYTES, MAX_STEPS, THREAD_SLEEP};
+use crate::utils::enums::StepperState;
+use crate::utils::frame::{CommandFrame, FRAME_HEADER, FRAME_OVERHEAD, FrameCommand, MAX_FRAME_PAYLOAD};
+use crate::utils::protocols::{Protocol, Rotation};
+use crate::utils::status::{MotorStatus, STATUS_QUERY};
+use crate::utils::step_protocol::StepProtocol;
+
+/// Virtual Raspberry answering on a Linux pseudo-terminal like the real firmware does.
+/// `Serial::new` can connect to `slave_path()` exactly as it connects to a COM port.
+pub struct FirmwareSimulator {
+    master: File,
+    // Kept open so the master side does not return EIO between two connections.
+    _slave: File,
+    slave_path: PathBuf,
+    fault: Option<(StepperState, u64)>,
+}
+
+impl FirmwareSimulator {
+    /// Open a new pseudo-terminal. `fault` injects a state code after the given run time in milliseconds.
+    pub fn new(fault: Option<(StepperState, u64)>) -> Result<Self, Error> {
+        let pty = openpty(None, None)?;
+        let mut termios = tcgetattr(pty.slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(pty.slave, SetArg::TCSANOW, &termios)?;
+        let slave_path = ttyname(pty.slave)?;
+        // Safety: both file descriptors have just been opened by openpty and are owned by nobody else.
+        let master = unsafe { File::from_raw_fd(pty.master) };
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+        Ok(Self {
+            master,
+            _slave: slave,
+            slave_path,
+            fault,
+        })
+    }
+
+    pub fn slave_path(&self) -> &PathBuf {
+        &self.slave_path
+    }
+
+    /// Answer the commands sent to the pseudo-terminal until an I/O error occurs.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut run: Option<ActiveRun> = None;
+        loop {
+            // Wait for the next command or the next event of the current run, whichever comes first.
+            let mut timeout_ms = THREAD_SLEEP * 10;
+            if let Some(active_run) = run.as_mut() {
+                let elapsed_ms = active_run.start_time.elapsed().as_millis() as u64;
+                if let Some((event_ms, _)) = active_run.events.peek() {
+                    timeout_ms = timeout_ms.min(event_ms.saturating_sub(elapsed_ms));
+                }
+                if let Some((_, fault_ms)) = self.fault {
+                    timeout_ms = timeout_ms.min(fault_ms.saturating_sub(elapsed_ms));
+                }
+            }
+            let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, timeout_ms as i32)?;
+            let is_readable = fds[0].revents().map_or(false, |revents| revents.contains(PollFlags::POLLIN));
+            if is_readable {
+                let mut read_buffer = [0u8; 256];
+                let n = self.master.read(&mut read_buffer)?;
+                buffer.extend_from_slice(&read_buffer[..n]);
+                self.handle_commands(&mut buffer, &mut run)?;
+            }
+
+            // Emit every event that is due.
+            let mut is_run_over = false;
+            if let Some(active_run) = run.as_mut() {
+                let elapsed_ms = active_run.start_time.elapsed().as_millis() as u64;
+                if let Some((fault_state, fault_ms)) = self.fault {
+                    if elapsed_ms >= fault_ms {
+                        tracing::info!("Simulator: injecting {}", fault_state);
+                        self.master.write_all(&fault_state.as_bytes())?;
+                        is_run_over = true;
+                    }
+                }
+                while !is_run_over {
+                    match active_run.events.peek() {
+                        Some((event_ms, state)) if *event_ms <= elapsed_ms => {
+                            let (event_ms, state) = (*event_ms, *state);
+                            self.master.write_all(&state.as_bytes())?;
+                            is_run_over = state == StepperState::Finished;
+                            active_run.record(event_ms, state);
+                            active_run.events.next();
+                        }
+                        Some(_) => break,
+                        None => {
+                            is_run_over = true;
+                        }
+                    }
+                }
+            }
+            if is_run_over {
+                run = None;
+            }
+        }
+    }
+
+    fn start_run(&self, protocol: Protocol) -> ActiveRun {
+        tracing::info!("Simulator: starting {}", protocol);
+        self.start_events(Box::new(SimulatedRun::new(protocol)), protocol)
+    }
+
+    fn start_step_run(&self, step_protocol: StepProtocol) -> ActiveRun {
+        tracing::info!("Simulator: starting {}", step_protocol);
+        // The status only carries the fixed protocol.
+        let protocol = step_protocol.as_protocol().unwrap_or_default();
+        self.start_events(Box::new(SimulatedStepRun::new(step_protocol)), protocol)
+    }
+
+    fn start_events(&self, events: Box<dyn Iterator<Item=(u64, StepperState)>>, protocol: Protocol) -> ActiveRun {
+        ActiveRun {
+            start_time: Instant::now(),
+            events: events.peekable(),
+            status: MotorStatus {
+                is_running: true,
+                rotation_direction: protocol.rotation.direction,
+                agitation_direction: protocol.agitation.direction,
+                protocol,
+                ..Default::default()
+            },
+            main_phase_ms: 0,
+            sub_phase_ms: 0,
+        }
+    }
+
+    fn handle_commands(&mut self, buffer: &mut Vec<u8>, run: &mut Option<ActiveRun>) -> Result<(), Error> {
+        loop {
+            if buffer.first() == Some(&b'a') {
+                if buffer.len() < BYTES {
+                    return Ok(());
+                }
+                let frame: Vec<u8> = buffer.drain(..BYTES).collect();
+                match Protocol::from_bytes(&frame) {
+                    Ok(protocol) => {
+                        self.master.write_all(b"ok!")?;
+                        *run = Some(self.start_run(protocol));
+                    }
+                    Err(err) => {
+                        tracing::warn!("Simulator: invalid protocol frame received: {}", err);
+                    }
+                }
+            } else if buffer.starts_with(&FRAME_HEADER[..buffer.len().min(FRAME_HEADER.len())]) {
+                let Some(frame_length) = CommandFrame::expected_length(buffer) else {
+                    return Ok(());
+                };
+                if frame_length > FRAME_OVERHEAD + MAX_FRAME_PAYLOAD {
+                    tracing::warn!("Simulator: corrupted frame length {}", frame_length);
+                    // Drop the first byte only to resynchronize on the next header.
+                    buffer.remove(0);
+                    continue;
+                }
+                if buffer.len() < frame_length {
+                    return Ok(());
+                }
+                let bytes: Vec<u8> = buffer.drain(..frame_length).collect();
+                let sequence_id = bytes[3];
+                let new_run = match CommandFrame::from_bytes(&bytes) {
+                    Ok(frame) => match frame.command {
+                        FrameCommand::StartProtocol => Protocol::from_payload_bytes(&frame.payload).map(|protocol| self.start_run(protocol)).map_err(Error::from),
+                        FrameCommand::StartStepProtocol => StepProtocol::from_payload_bytes(&frame.payload).map_err(Error::from).and_then(|step_protocol| {
+                            let step_count = step_protocol.steps().count();
+                            if step_count > MAX_STEPS {
+                                bail!("{} steps, more than the {} the firmware can number", step_count, MAX_STEPS);
+                            }
+                            Ok(self.start_step_run(step_protocol))
+                        }),
+                    }.map_err(|err| tracing::warn!("Simulator: {}", err)).ok(),
+                    Err(err) => {
+                        tracing::warn!("Simulator: {}", err);
+                        None
+                    }
+                };
+                match new_run {
+                    Some(new_run) => {
+                        self.master.write_all(&StepperState::Acknowledged(sequence_id).as_bytes())?;
+                        *run = Some(new_run);
+                    }
+                    None => {
+                        self.master.write_all(&StepperState::NotAcknowledged(sequence_id).as_bytes())?;
+                    }
+                }
+            } else {
+                if buffer.len() < 4 {
+                    return Ok(());
+                }
+                match &buffer[..4] {
+                    b"helo" => {
+                        self.master.write_all(b"ok!")?;
+                    }
+                    command if command == STATUS_QUERY => {
+                        let status = run.as_ref().map(|active_run| active_run.status()).unwrap_or_default();
+                        self.master.write_all(&status.as_bytes())?;
+                    }
+                    b"stop" => {
+                        tracing::info!("Simulator: stop received");
+                        self.master.write_all(b"ok!")?;
+                        *run = None;
+                    }
+                    b"bye!" => {
+                        tracing::info!("Simulator: client disconnected");
+                        *run = None;
+                    }
+                    command => {
+                        tracing::warn!("Simulator: unknown command {:?}", command);
+                        // Drop the first byte only to resynchronize on the next command.
+                        buffer.remove(0);
+                        continue;
+                    }
+                }
+                buffer.drain(..4);
+            }
+        }
+    }
+}
+
+/// Run in progress and the state reported to a status query.
+struct ActiveRun {
+    start_time: Instant,
+    events: Peekable<Box<dyn Iterator<Item=(u64, StepperState)>>>,
+    status: MotorStatus,
+    main_phase_ms: u64,
+    sub_phase_ms: u64,
+}
+
+impl ActiveRun {
+    /// Track the phases and directions like the app does when it receives the event.
+    fn record(&mut self, event_ms: u64, state: StepperState) {
+        match state {
+            StepperState::StartRotation | StepperState::StartAgitation | StepperState::StartStep(_) => {
+                self.status.main_phase = state;
+                self.main_phase_ms = event_ms;
+            }
+            StepperState::OscillationRotation => {
+                self.status.rotation_direction = self.status.rotation_direction.reverse();
+                self.status.sub_phase = state;
+                self.sub_phase_ms = event_ms;
+            }
+            StepperState::OscillationAgitation => {
+                self.status.agitation_direction = self.status.agitation_direction.reverse();
+                self.status.sub_phase = state;
+                self.sub_phase_ms = event_ms;
+            }
+            _ => {
+                self.status.sub_phase = state;
+                self.sub_phase_ms = event_ms;
+            }
+        }
+    }
+
+    fn status(&self) -> MotorStatus {
+        let elapsed_ms = self.start_time.elapsed().as_millis() as u64;
+        MotorStatus {
+            elapsed_global_ms: elapsed_ms,
+            elapsed_main_phase_ms: elapsed_ms.saturating_sub(self.main_phase_ms),
+            elapsed_sub_phase_ms: elapsed_ms.saturating_sub(self.sub_phase_ms),
+            ..self.status
+        }
+    }
+}
+
+/// Events emitted by the firmware for a protocol, as (milliseconds since the start, state).
+/// The events are generated lazily because a cycle can be a few milliseconds long in a run of several days.
+pub struct SimulatedRun {
+    protocol: Protocol,
+    loop_start_ms: u64,
+    cursor: RunCursor,
+}
+
+#[derive(Copy, Clone)]
+enum RunCursor {
+    SegmentStart(usize),
+    Cycle { segment: usize, cycle_start_ms: u64, is_paused: bool },
+    Finished,
+    Done,
+}
+
+impl SimulatedRun {
+    pub fn new(protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            loop_start_ms: 0,
+            cursor: RunCursor::SegmentStart(0),
+        }
+    }
+
+    // Segments of one loop: rotation, pause pre-agitation, agitation, pause post-agitation.
+    fn segment_duration(&self, segment: usize) -> u64 {
+        match segment {
+            0 => self.protocol.rotation_duration_ms,
+            1 => self.protocol.pause_pre_agitation_ms,
+            2 => self.protocol.agitation_duration_ms,
+            _ => self.protocol.pause_post_agitation_ms,
+        }
+    }
+
+    fn segment_start(&self, segment: usize) -> u64 {
+        self.loop_start_ms + (0..segment).map(|i| self.segment_duration(i)).sum::<u64>()
+    }
+
+    fn segment_rotation(&self, segment: usize) -> Rotation {
+        if segment == 0 { self.protocol.rotation } else { self.protocol.agitation }
+    }
+}
+
+impl Iterator for SimulatedRun {
+    type Item = (u64, StepperState);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let global_duration_ms = self.protocol.global_duration_ms;
+        loop {
+            match self.cursor {
+                RunCursor::Done => return None,
+                RunCursor::Finished => {
+                    self.cursor = RunCursor::Done;
+                    return Some((global_duration_ms, StepperState::Finished));
+                }
+                RunCursor::SegmentStart(4) => {
+                    let loop_duration_ms = self.segment_start(4) - self.loop_start_ms;
+                    if loop_duration_ms == 0 {
+                        self.cursor = RunCursor::Finished;
+                        continue;
+                    }
+                    self.loop_start_ms += loop_duration_ms;
+                    self.cursor = RunCursor::SegmentStart(0);
+                }
+                RunCursor::SegmentStart(segment) => {
+                    let start_ms = self.segment_start(segment);
+                    if start_ms >= global_duration_ms {
+                        self.cursor = RunCursor::Finished;
+                        continue;
+                    }
+                    if self.segment_duration(segment) == 0 {
+                        self.cursor = RunCursor::SegmentStart(segment + 1);
+                        continue;
+                    }
+                    let state = match segment {
+                        0 => StepperState::StartRotation,
+                        1 => StepperState::StartPausePreAgitation,
+                        2 => StepperState::StartAgitation,
+                        _ => StepperState::StartPausePostAgitation,
+                    };
+                    self.cursor = if segment % 2 == 0 {
+                        RunCursor::Cycle { segment, cycle_start_ms: start_ms, is_paused: false }
+                    } else {
+                        RunCursor::SegmentStart(segment + 1)
+                    };
+                    return Some((start_ms, state));
+                }
+                RunCursor::Cycle { segment, cycle_start_ms, is_paused } => {
+                    let rotation = self.segment_rotation(segment);
+                    let end_ms = (self.segment_start(segment) + self.segment_duration(segment)).min(global_duration_ms);
+                    if !is_paused {
+                        self.cursor = RunCursor::Cycle { segment, cycle_start_ms, is_paused: true };
+                        let pause_ms = cycle_start_ms + rotation.duration_of_one_direction_cycle_ms;
+                        if rotation.pause_before_direction_change_ms != 0 && pause_ms < end_ms {
+                            let state = if segment == 0 { StepperState::StartPauseRotation } else { StepperState::StartPauseAgitation };
+                            return Some((pause_ms, state));
+                        }
+                        continue;
+                    }
+                    let next_cycle_ms = cycle_start_ms + rotation.get_min_duration();
+                    if rotation.get_min_duration() == 0 || next_cycle_ms >= end_ms {
+                        self.cursor = RunCursor::SegmentStart(segment + 1);
+                        continue;
+                    }
+                    self.cursor = RunCursor::Cycle { segment, cycle_start_ms: next_cycle_ms, is_paused: false };
+                    let state = if segment == 0 { StepperState::OscillationRotation } else { StepperState::OscillationAgitation };
+                    return Some((next_cycle_ms, state));
+                }
+            }
+        }
+    }
+}
+
+/// Events emitted by the firmware for a step protocol: the start of each step, then the end of the run.
+pub struct SimulatedStepRun {
+    step_protocol: StepProtocol,
+    duration_ms: u64,
+    time_ms: u64,
+    pass: u32,
+    block: usize,
+    block_loop: u32,
+    step: usize,
+    is_done: bool,
+}
+
+impl SimulatedStepRun {
+    pub fn new(step_protocol: StepProtocol) -> Self {
+        Self {
+            duration_ms: step_protocol.duration_ms(),
+            step_protocol,
+            time_ms: 0,
+            pass: 0,
+            block: 0,
+            block_loop: 0,
+            step: 0,
+            is_done: false,
+        }
+    }
+
+    fn finish(&mut self) -> Option<(u64, StepperState)> {
+        self.is_done = true;
+        Some((self.duration_ms, StepperState::Finished))
+    }
+}
+
+impl Iterator for SimulatedStepRun {
+    type Item = (u64, StepperState);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.is_done {
+                return None;
+            }
+            let blocks = &self.step_protocol.blocks;
+            if self.block == blocks.len() {
+                self.pass += 1;
+                self.block = 0;
+                if self.step_protocol.pass_duration_ms() == 0 || (self.step_protocol.loop_count != 0 && self.pass >= self.step_protocol.loop_count) {
+                    return self.finish();
+                }
+                continue;
+            }
+            let block = &blocks[self.block];
+            if self.block_loop >= block.loop_count || block.steps.is_empty() {
+                self.block += 1;
+                self.block_loop = 0;
+                continue;
+            }
+            if self.step == block.steps.len() {
+                self.block_loop += 1;
+                self.step = 0;
+                continue;
+            }
+            if self.time_ms >= self.duration_ms {
+                return self.finish();
+            }
+            let index = blocks[..self.block].iter().map(|block| block.steps.len()).sum::<usize>() + self.step;
+            let start_ms = self.time_ms;
+            let duration_ms = block.steps[self.step].duration_ms();
+            self.time_ms += duration_ms;
+            self.step += 1;
+            if duration_ms != 0 {
+                // The step protocols of more than `MAX_STEPS` steps are rejected when received.
+                return Some((start_ms, StepperState::StartStep(u8::try_from(index).unwrap_or(u8::MAX))));
+            }
+        }
+    }
+}
+
+/// Parse a fault given as `code@milliseconds`, e.g. `er1@5000`.
+pub fn parse_fault(text: &str) -> Result<(StepperState, u64), Error> {
+    let Some((code, time_ms)) = text.split_once('@') else {
+        bail!("The fault should be written as code@milliseconds, e.g. er1@5000");
+    };
+    let code: [u8; 3] = match code.as_bytes().try_into() {
+        Ok(code) => code,
+        Err(_) => bail!("The fault code should be 3 characters long"),
+    };
+    let state = StepperState::from(&code);
+    if state == StepperState::Invalid {
+        bail!("Unknown fault code {}", text);
+    }
+    Ok((state, time_ms.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// What the simulator answered, as read by the client side of the pseudo-terminal.
+    fn read_answer(simulator: &mut FirmwareSimulator) -> Vec<u8> {
+        let mut fds = [PollFd::new(simulator._slave.as_raw_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, Duration::from_secs(1).as_millis() as i32).unwrap();
+        let mut answer = [0u8; 3];
+        simulator._slave.read_exact(&mut answer).unwrap();
+        answer.to_vec()
+    }
+
+    #[test]
+    fn corrupted_frame_length_resynchronizes_on_the_next_header() {
+        let mut simulator = FirmwareSimulator::new(None).unwrap();
+        let mut protocol = Protocol::default();
+        protocol.rotation.rpm = 60;
+        protocol.rotation.duration_of_one_direction_cycle_ms = 1_000;
+        protocol.rotation_duration_ms = 10_000;
+        protocol.global_duration_ms = 10_000;
+        let mut corrupted = protocol.protocol_as_frame(1).unwrap();
+        corrupted[5..7].copy_from_slice(&u16::MAX.to_le_bytes());
+        let mut buffer = corrupted[..FRAME_OVERHEAD].to_vec();
+        buffer.extend_from_slice(&protocol.protocol_as_frame(2).unwrap());
+        let mut run = None;
+        simulator.handle_commands(&mut buffer, &mut run).unwrap();
+        assert!(buffer.is_empty());
+        assert!(run.is_some());
+        assert_eq!(read_answer(&mut simulator), StepperState::Acknowledged(2).as_bytes());
+    }
+}
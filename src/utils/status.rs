@@ -0,0 +1,72 @@
+use crate::utils::constants::BYTES;
+use crate::utils::enums::{Direction, StepperState};
+use crate::utils::errors::DecodeError;
+use crate::utils::protocols::Protocol;
+
+/// Command asking the firmware for its current state.
+pub const STATUS_QUERY: [u8; 4] = *b"stat";
+/// First 3 bytes of the reply to `STATUS_QUERY`.
+pub const STATUS_REPLY: [u8; 3] = *b"sts";
+/// Reply marker (3) + running flag (1) + main and sub phases (3 + 3) + elapsed times (3 * 8) + directions (2) + protocol (BYTES).
+pub const STATUS_BYTES: usize = 36 + BYTES;
+
+/// State of the firmware, used to reattach to a run started before the app was launched.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct MotorStatus {
+    pub is_running: bool,
+    pub main_phase: StepperState,
+    pub sub_phase: StepperState,
+    pub elapsed_global_ms: u64,
+    pub elapsed_main_phase_ms: u64,
+    pub elapsed_sub_phase_ms: u64,
+    pub rotation_direction: Direction,
+    pub agitation_direction: Direction,
+    pub protocol: Protocol,
+}
+
+impl MotorStatus {
+    /// Status to the bytes sent by the firmware
+    pub fn as_bytes(&self) -> [u8; STATUS_BYTES] {
+        let mut bytes = [0u8; STATUS_BYTES];
+        bytes[0..3].copy_from_slice(&STATUS_REPLY);
+        bytes[3] = self.is_running as u8;
+        bytes[4..7].copy_from_slice(&self.main_phase.as_bytes());
+        bytes[7..10].copy_from_slice(&self.sub_phase.as_bytes());
+        bytes[10..18].copy_from_slice(&self.elapsed_global_ms.to_le_bytes());
+        bytes[18..26].copy_from_slice(&self.elapsed_main_phase_ms.to_le_bytes());
+        bytes[26..34].copy_from_slice(&self.elapsed_sub_phase_ms.to_le_bytes());
+        bytes[34..35].copy_from_slice(self.rotation_direction.convert_to_byte_slice());
+        bytes[35..36].copy_from_slice(self.agitation_direction.convert_to_byte_slice());
+        bytes[36..].copy_from_slice(&self.protocol.protocol_as_bytes());
+        bytes
+    }
+
+    /// Status from the bytes produced by `as_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < STATUS_BYTES {
+            return Err(DecodeError::Truncated { expected: STATUS_BYTES, found: bytes.len() });
+        }
+        for (index, expected) in STATUS_REPLY.iter().enumerate() {
+            if bytes[index] != *expected {
+                return Err(DecodeError::InvalidMarker { index, expected: *expected, found: bytes[index] });
+            }
+        }
+        let is_running = match bytes[3] {
+            0 => false,
+            1 => true,
+            byte => return Err(DecodeError::UnknownEnumByte { field: "running flag", byte }),
+        };
+        let u64_at = |index: usize| u64::from_le_bytes(bytes[index..index + 8].try_into().unwrap());
+        Ok(Self {
+            is_running,
+            main_phase: StepperState::from(&[bytes[4], bytes[5], bytes[6]]),
+            sub_phase: StepperState::from(&[bytes[7], bytes[8], bytes[9]]),
+            elapsed_global_ms: u64_at(10),
+            elapsed_main_phase_ms: u64_at(18),
+            elapsed_sub_phase_ms: u64_at(26),
+            rotation_direction: Direction::from_bytes(&bytes[34..35])?,
+            agitation_direction: Direction::from_bytes(&bytes[35..36])?,
+            protocol: Protocol::from_bytes(&bytes[36..STATUS_BYTES])?,
+        })
+    }
+}
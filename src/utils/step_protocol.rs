@@ -0,0 +1,449 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::constants::{MAX_DURATION_MS, MAX_STEPS};
+use crate::utils::enums::{Direction, StepMode128};
+use crate::utils::errors::DecodeError;
+use crate::utils::frame::{CommandFrame, FrameCommand, MAX_FRAME_PAYLOAD};
+use crate::utils::protocols::{validate_rotation, Protocol, Rotation, ROTATION_BYTES};
+use crate::utils::structs::DurationHelper;
+use crate::utils::validation::{ProtocolField, ValidationIssue};
+
+/// Loop count (4) and step count (2) of a block.
+const BLOCK_HEADER_BYTES: usize = 6;
+/// Length of the shortest step, a pause.
+const MIN_STEP_BYTES: usize = 1 + 8;
+
+/// Step of a step-list protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Step {
+    Rotation { rotation: Rotation, duration_ms: u64 },
+    Agitation { agitation: Rotation, duration_ms: u64 },
+    Pause { duration_ms: u64 },
+    /// Constant acceleration from `start_rpm` to `end_rpm` in one direction.
+    Ramp { start_rpm: u32, end_rpm: u32, step_mode: StepMode128, direction: Direction, duration_ms: u64 },
+}
+
+impl Step {
+    pub fn get_kinds() -> [Step; 4] {
+        [
+            Step::Rotation { rotation: Rotation::default(), duration_ms: 0 },
+            Step::Agitation { agitation: Rotation::default(), duration_ms: 0 },
+            Step::Pause { duration_ms: 0 },
+            Step::Ramp { start_rpm: 1, end_rpm: 1, step_mode: StepMode128::Full, direction: Direction::Forward, duration_ms: 0 },
+        ]
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Step::Rotation { .. } => "Rotation",
+            Step::Agitation { .. } => "Agitation",
+            Step::Pause { .. } => "Pause",
+            Step::Ramp { .. } => "Ramp",
+        }
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        match self {
+            Step::Rotation { duration_ms, .. } | Step::Agitation { duration_ms, .. } | Step::Pause { duration_ms } | Step::Ramp { duration_ms, .. } => *duration_ms,
+        }
+    }
+
+    pub fn duration_ms_mut(&mut self) -> &mut u64 {
+        match self {
+            Step::Rotation { duration_ms, .. } | Step::Agitation { duration_ms, .. } | Step::Pause { duration_ms } | Step::Ramp { duration_ms, .. } => duration_ms,
+        }
+    }
+
+    /// Step to bytes for serial communication: a kind byte followed by the fields.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + ROTATION_BYTES + 8);
+        match self {
+            Step::Rotation { rotation, duration_ms } => {
+                bytes.push(b'r');
+                bytes.extend_from_slice(&rotation.convert_to_bytes());
+                bytes.extend_from_slice(&duration_ms.to_le_bytes());
+            }
+            Step::Agitation { agitation, duration_ms } => {
+                bytes.push(b'a');
+                bytes.extend_from_slice(&agitation.convert_to_bytes());
+                bytes.extend_from_slice(&duration_ms.to_le_bytes());
+            }
+            Step::Pause { duration_ms } => {
+                bytes.push(b'p');
+                bytes.extend_from_slice(&duration_ms.to_le_bytes());
+            }
+            Step::Ramp { start_rpm, end_rpm, step_mode, direction, duration_ms } => {
+                bytes.push(b'm');
+                bytes.extend_from_slice(&start_rpm.to_le_bytes());
+                bytes.extend_from_slice(&end_rpm.to_le_bytes());
+                bytes.extend_from_slice(step_mode.convert_to_bytes_slice());
+                bytes.extend_from_slice(direction.convert_to_byte_slice());
+                bytes.extend_from_slice(&duration_ms.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Step from the start of `bytes`, with the number of bytes read.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let Some(kind) = bytes.first() else {
+            return Err(DecodeError::Truncated { expected: 1, found: 0 });
+        };
+        let length = match kind {
+            b'r' | b'a' => 1 + ROTATION_BYTES + 8,
+            b'p' => 1 + 8,
+            b'm' => 1 + 4 + 4 + 1 + 1 + 8,
+            byte => return Err(DecodeError::UnknownEnumByte { field: "step kind", byte: *byte }),
+        };
+        if bytes.len() < length {
+            return Err(DecodeError::Truncated { expected: length, found: bytes.len() });
+        }
+        let u64_at = |index: usize| u64::from_le_bytes(bytes[index..index + 8].try_into().unwrap());
+        let u32_at = |index: usize| u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap());
+        let step = match kind {
+            b'r' => Step::Rotation { rotation: Rotation::from_bytes(&bytes[1..1 + ROTATION_BYTES])?, duration_ms: u64_at(1 + ROTATION_BYTES) },
+            b'a' => Step::Agitation { agitation: Rotation::from_bytes(&bytes[1..1 + ROTATION_BYTES])?, duration_ms: u64_at(1 + ROTATION_BYTES) },
+            b'p' => Step::Pause { duration_ms: u64_at(1) },
+            _ => Step::Ramp {
+                start_rpm: u32_at(1),
+                end_rpm: u32_at(5),
+                step_mode: StepMode128::from_bytes(&bytes[9..10])?,
+                direction: Direction::from_bytes(&bytes[10..11])?,
+                duration_ms: u64_at(11),
+            },
+        };
+        Ok((step, length))
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let duration = DurationHelper::new_from_milliseconds(self.duration_ms());
+        match self {
+            Step::Rotation { rotation, .. } => write!(f, "Rotation for {} ({})", duration, rotation),
+            Step::Agitation { agitation, .. } => write!(f, "Agitation for {} ({})", duration, agitation),
+            Step::Pause { .. } => write!(f, "Pause for {}", duration),
+            Step::Ramp { start_rpm, end_rpm, step_mode, direction, .. } => write!(f, "Ramp for {} ({} -> {} RPM, StepMode: {}, Direction: {})", duration, start_rpm, end_rpm, step_mode, direction),
+        }
+    }
+}
+
+/// Steps run `loop_count` times in a row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepBlock {
+    pub steps: Vec<Step>,
+    pub loop_count: u32,
+}
+
+impl Default for StepBlock {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            loop_count: 1,
+        }
+    }
+}
+
+impl StepBlock {
+    pub fn duration_ms(&self) -> u64 {
+        self.steps.iter().map(|step| step.duration_ms()).sum::<u64>() * self.loop_count as u64
+    }
+}
+
+/// Protocol made of an ordered list of blocks of steps.
+/// The list is repeated `loop_count` times, or until `global_duration_ms` is reached when `loop_count` is 0.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StepProtocol {
+    pub blocks: Vec<StepBlock>,
+    pub loop_count: u32,
+    pub global_duration_ms: u64,
+}
+
+impl From<Protocol> for StepProtocol {
+    /// Rotation, pause pre agitation, agitation and pause post agitation repeated until the global duration.
+    fn from(protocol: Protocol) -> Self {
+        let steps = vec![
+            Step::Rotation { rotation: protocol.rotation, duration_ms: protocol.rotation_duration_ms },
+            Step::Pause { duration_ms: protocol.pause_pre_agitation_ms },
+            Step::Agitation { agitation: protocol.agitation, duration_ms: protocol.agitation_duration_ms },
+            Step::Pause { duration_ms: protocol.pause_post_agitation_ms },
+        ];
+        Self {
+            blocks: vec![StepBlock { steps, loop_count: 1 }],
+            loop_count: 0,
+            global_duration_ms: protocol.global_duration_ms,
+        }
+    }
+}
+
+impl StepProtocol {
+    /// The fixed rotation/agitation protocol, when the steps still follow its template.
+    pub fn as_protocol(&self) -> Option<Protocol> {
+        if self.loop_count != 0 || self.blocks.len() != 1 || self.blocks[0].loop_count != 1 {
+            return None;
+        }
+        match self.blocks[0].steps[..] {
+            [Step::Rotation { rotation, duration_ms: rotation_duration_ms },
+            Step::Pause { duration_ms: pause_pre_agitation_ms },
+            Step::Agitation { agitation, duration_ms: agitation_duration_ms },
+            Step::Pause { duration_ms: pause_post_agitation_ms }] => Some(Protocol {
+                rotation,
+                rotation_duration_ms,
+                pause_pre_agitation_ms,
+                agitation,
+                agitation_duration_ms,
+                pause_post_agitation_ms,
+                global_duration_ms: self.global_duration_ms,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Duration of one pass through the blocks.
+    pub fn pass_duration_ms(&self) -> u64 {
+        self.blocks.iter().map(|block| block.duration_ms()).sum()
+    }
+
+    /// Duration of the whole run. A non zero `global_duration_ms` always ends the run.
+    pub fn duration_ms(&self) -> u64 {
+        let loops_duration_ms = self.pass_duration_ms() * self.loop_count as u64;
+        match (self.loop_count, self.global_duration_ms) {
+            (0, global_duration_ms) => global_duration_ms,
+            (_, 0) => loops_duration_ms,
+            (_, global_duration_ms) => loops_duration_ms.min(global_duration_ms),
+        }
+    }
+
+    /// Steps in order, as indexed by `StepperState::StartStep`.
+    pub fn steps(&self) -> impl Iterator<Item=&Step> {
+        self.blocks.iter().flat_map(|block| block.steps.iter())
+    }
+
+    /// Every issue of the step protocol. The rotations and agitations of the steps follow the rules of `Protocol::validate_run`.
+    /// The protocol should not be sent to the motor if one of them is an error.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+        for (index, step) in self.steps().enumerate() {
+            let field = ProtocolField::Step(index);
+            match step {
+                Step::Rotation { rotation, duration_ms } => validate_rotation(&mut issues, "rotation", rotation, *duration_ms, true, [field; 5]),
+                Step::Agitation { agitation, duration_ms } => validate_rotation(&mut issues, "agitation", agitation, *duration_ms, true, [field; 5]),
+                Step::Pause { duration_ms } => {
+                    if *duration_ms > MAX_DURATION_MS {
+                        issues.push(ValidationIssue::error(field, "Longer than one year."));
+                    }
+                }
+                Step::Ramp { start_rpm, end_rpm, step_mode, duration_ms, .. } => {
+                    let max_rpm = Rotation { step_mode: *step_mode, ..Default::default() }.max_rpm_for_stepmode();
+                    if *start_rpm == 0 || *end_rpm == 0 {
+                        issues.push(ValidationIssue::error(field, "The RPM of the ramp is 0."));
+                    } else if *start_rpm > max_rpm || *end_rpm > max_rpm {
+                        issues.push(ValidationIssue::error(field, format!("The RPM of the ramp is higher than {}, the maximum for the step mode {}.", max_rpm, step_mode)));
+                    }
+                    if *duration_ms > MAX_DURATION_MS {
+                        issues.push(ValidationIssue::error(field, "Longer than one year."));
+                    }
+                }
+            }
+        }
+        let step_count = self.steps().count();
+        if step_count > MAX_STEPS {
+            issues.push(ValidationIssue::error(ProtocolField::Steps, format!("{} steps, more than the {} the firmware can number.", step_count, MAX_STEPS)));
+        } else if !self.payload_bytes().map_or(false, |bytes| bytes.len() <= MAX_FRAME_PAYLOAD) {
+            issues.push(ValidationIssue::error(ProtocolField::Steps, format!("Too many blocks and steps to send in one frame of {} bytes.", MAX_FRAME_PAYLOAD)));
+        }
+        if self.global_duration_ms > MAX_DURATION_MS {
+            issues.push(ValidationIssue::error(ProtocolField::GlobalDuration, "Longer than one year."));
+        }
+        if self.duration_ms() == 0 {
+            issues.push(ValidationIssue::error(ProtocolField::Steps, "The duration of the step protocol is 0. Please check the steps and the loops."));
+        }
+        issues
+    }
+
+    /// Step protocol to bytes for serial communication.
+    /// Global duration (8) + loop count (4) + block count (2), then for each block its loop count (4), step count (2) and steps.
+    /// Fails if there are more blocks, or more steps in a block, than their counts can hold.
+    pub fn payload_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.global_duration_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.loop_count.to_le_bytes());
+        bytes.extend_from_slice(&u16::try_from(self.blocks.len()).map_err(|_| anyhow!("Too many blocks: {}", self.blocks.len()))?.to_le_bytes());
+        for block in &self.blocks {
+            bytes.extend_from_slice(&block.loop_count.to_le_bytes());
+            bytes.extend_from_slice(&u16::try_from(block.steps.len()).map_err(|_| anyhow!("Too many steps in a block: {}", block.steps.len()))?.to_le_bytes());
+            block.steps.iter().for_each(|step| bytes.extend_from_slice(&step.as_bytes()));
+        }
+        Ok(bytes)
+    }
+
+    /// Step protocol from the bytes produced by `payload_bytes`.
+    /// The counts are checked against the bytes left and `MAX_STEPS` before anything is allocated for them.
+    pub fn from_payload_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let take = |index: usize, length: usize| {
+            bytes.get(index..index + length).ok_or(DecodeError::Truncated { expected: index + length, found: bytes.len() })
+        };
+        let check_count = |field: &'static str, count: u16, max: usize| {
+            let count = count as usize;
+            if count > max { Err(DecodeError::TooMany { field, count, max }) } else { Ok(count) }
+        };
+        let global_duration_ms = u64::from_le_bytes(take(0, 8)?.try_into().unwrap());
+        let loop_count = u32::from_le_bytes(take(8, 4)?.try_into().unwrap());
+        let block_count = u16::from_le_bytes(take(12, 2)?.try_into().unwrap());
+        let mut index = 14;
+        let block_count = check_count("blocks", block_count, (bytes.len() - index) / BLOCK_HEADER_BYTES)?;
+        let mut blocks = Vec::with_capacity(block_count);
+        for _ in 0..block_count {
+            let block_loop_count = u32::from_le_bytes(take(index, 4)?.try_into().unwrap());
+            let step_count = u16::from_le_bytes(take(index + 4, 2)?.try_into().unwrap());
+            index += BLOCK_HEADER_BYTES;
+            let step_count = check_count("steps", step_count, MAX_STEPS.min((bytes.len() - index) / MIN_STEP_BYTES))?;
+            let mut steps = Vec::with_capacity(step_count);
+            for _ in 0..step_count {
+                let (step, length) = Step::from_bytes(&bytes[index..])?;
+                steps.push(step);
+                index += length;
+            }
+            blocks.push(StepBlock { steps, loop_count: block_loop_count });
+        }
+        Ok(Self {
+            blocks,
+            loop_count,
+            global_duration_ms,
+        })
+    }
+
+    /// Step protocol to a framed command for serial communication.
+    pub fn step_protocol_as_frame(&self, sequence_id: u8) -> Result<Vec<u8>, Error> {
+        CommandFrame::new(sequence_id, FrameCommand::StartStepProtocol, self.payload_bytes()?).as_bytes()
+    }
+}
+
+impl Display for StepProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Step protocol:")?;
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "Block {} (x{}):", index + 1, block.loop_count)?;
+            for step in &block.steps {
+                writeln!(f, "  {}", step)?;
+            }
+        }
+        writeln!(f, "Loops: {}", if self.loop_count == 0 { "until global duration".to_string() } else { self.loop_count.to_string() })?;
+        write!(f, "Global duration: {}", DurationHelper::new_from_milliseconds(self.global_duration_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_protocol() -> StepProtocol {
+        let rotation = Rotation { rpm: 60, acceleration: 100, duration_of_one_direction_cycle_ms: 1_000, ..Default::default() };
+        StepProtocol {
+            blocks: vec![
+                StepBlock { steps: vec![Step::Rotation { rotation, duration_ms: 10_000 }, Step::Pause { duration_ms: 1_000 }], loop_count: 2 },
+                StepBlock { steps: vec![Step::Agitation { agitation: rotation, duration_ms: 5_000 }, Step::Ramp { start_rpm: 10, end_rpm: 120, step_mode: StepMode128::M16, direction: Direction::Backward, duration_ms: 3_000 }], loop_count: 1 },
+            ],
+            loop_count: 3,
+            global_duration_ms: 0,
+        }
+    }
+
+    fn errors(step_protocol: &StepProtocol) -> Vec<ValidationIssue> {
+        step_protocol.validate().into_iter().filter(|issue| issue.is_error()).collect()
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let step_protocol = step_protocol();
+        assert_eq!(StepProtocol::from_payload_bytes(&step_protocol.payload_bytes().unwrap()), Ok(step_protocol.clone()));
+        let frame = CommandFrame::from_bytes(&step_protocol.step_protocol_as_frame(3).unwrap()).unwrap();
+        assert_eq!(frame.command, FrameCommand::StartStepProtocol);
+        assert_eq!(StepProtocol::from_payload_bytes(&frame.payload), Ok(step_protocol));
+    }
+
+    #[test]
+    fn protocol_conversion_is_lossless() {
+        let protocol = Protocol {
+            rotation: Rotation { rpm: 123, acceleration: 456, step_mode: StepMode128::M32, duration_of_one_direction_cycle_ms: 7_890, steps_for_one_direction_cycle: 11, direction: Direction::Backward, pause_before_direction_change_ms: 12 },
+            rotation_duration_ms: 60_000,
+            pause_pre_agitation_ms: 1_000,
+            agitation: Rotation { rpm: 30, acceleration: 100, step_mode: StepMode128::M128, duration_of_one_direction_cycle_ms: 500, steps_for_one_direction_cycle: 0, direction: Direction::Forward, pause_before_direction_change_ms: 0 },
+            agitation_duration_ms: 30_000,
+            pause_post_agitation_ms: 2_000,
+            global_duration_ms: 3_600_000,
+        };
+        let converted = StepProtocol::from(protocol);
+        assert_eq!(converted.as_protocol(), Some(protocol));
+        assert_eq!(converted.duration_ms(), protocol.global_duration_ms);
+        assert_eq!(StepProtocol::from_payload_bytes(&converted.payload_bytes().unwrap()).unwrap().as_protocol(), Some(protocol));
+        assert_eq!(step_protocol().as_protocol(), None);
+    }
+
+    #[test]
+    fn corrupt_counts_are_rejected_before_allocating() {
+        let mut bytes = step_protocol().payload_bytes().unwrap();
+        // Block count, after the global duration and the loop count.
+        bytes[12..14].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(StepProtocol::from_payload_bytes(&bytes), Err(DecodeError::TooMany { field: "blocks", count: u16::MAX as usize, max: (bytes.len() - 14) / BLOCK_HEADER_BYTES }));
+        let mut bytes = step_protocol().payload_bytes().unwrap();
+        // Step count of the first block, after its loop count.
+        bytes[18..20].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(StepProtocol::from_payload_bytes(&bytes), Err(DecodeError::TooMany { field: "steps", count: 65_535, .. })));
+        let mut bytes = StepProtocol { blocks: vec![StepBlock { steps: vec![Step::Pause { duration_ms: 1 }; MAX_STEPS + 1], loop_count: 1 }], loop_count: 1, global_duration_ms: 0 }.payload_bytes().unwrap();
+        assert_eq!(StepProtocol::from_payload_bytes(&bytes), Err(DecodeError::TooMany { field: "steps", count: MAX_STEPS + 1, max: MAX_STEPS }));
+        bytes.truncate(bytes.len() - 1);
+        assert!(matches!(StepProtocol::from_payload_bytes(&bytes), Err(DecodeError::TooMany { field: "steps", .. })));
+    }
+
+    #[test]
+    fn truncated_payload_is_rejected() {
+        let bytes = step_protocol().payload_bytes().unwrap();
+        for length in 0..bytes.len() {
+            assert!(StepProtocol::from_payload_bytes(&bytes[..length]).is_err(), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn valid_step_protocol_has_no_error() {
+        assert_eq!(errors(&step_protocol()), vec![]);
+    }
+
+    #[test]
+    fn steps_follow_the_rules_of_the_protocols() {
+        let mut step_protocol = step_protocol();
+        step_protocol.blocks[0].steps[0] = Step::Rotation { rotation: Rotation { rpm: 0, duration_of_one_direction_cycle_ms: 500, ..Default::default() }, duration_ms: 1_000 };
+        step_protocol.blocks[0].steps[1] = Step::Pause { duration_ms: MAX_DURATION_MS + 1 };
+        step_protocol.blocks[1].steps[1] = Step::Ramp { start_rpm: 10, end_rpm: 100_000, step_mode: StepMode128::M128, direction: Direction::Forward, duration_ms: 1_000 };
+        let fields: Vec<ProtocolField> = errors(&step_protocol).into_iter().map(|issue| issue.field).collect();
+        assert_eq!(fields, vec![ProtocolField::Step(0), ProtocolField::Step(1), ProtocolField::Step(3)]);
+    }
+
+    #[test]
+    fn zero_duration_is_rejected() {
+        let step_protocol = StepProtocol { blocks: vec![StepBlock { steps: vec![Step::Pause { duration_ms: 0 }], loop_count: 1 }], loop_count: 1, global_duration_ms: 0 };
+        assert_eq!(errors(&step_protocol).iter().map(|issue| issue.field).collect::<Vec<_>>(), vec![ProtocolField::Steps]);
+    }
+
+    #[test]
+    fn too_many_steps_are_rejected() {
+        let pauses = |count: usize| StepProtocol {
+            blocks: vec![StepBlock { steps: vec![Step::Pause { duration_ms: 1_000 }; count], loop_count: 1 }],
+            loop_count: 1,
+            global_duration_ms: 0,
+        };
+        assert_eq!(errors(&pauses(MAX_STEPS)), vec![]);
+        assert!(pauses(MAX_STEPS).step_protocol_as_frame(0).is_ok());
+        assert_eq!(errors(&pauses(MAX_STEPS + 1)).iter().map(|issue| issue.field).collect::<Vec<_>>(), vec![ProtocolField::Steps]);
+    }
+
+    #[test]
+    fn too_long_payload_is_not_framed() {
+        let step_protocol = StepProtocol { blocks: vec![StepBlock::default(); MAX_FRAME_PAYLOAD], loop_count: 1, global_duration_ms: 1_000 };
+        assert!(step_protocol.step_protocol_as_frame(0).is_err());
+        assert!(errors(&step_protocol).iter().any(|issue| issue.field == ProtocolField::Steps));
+    }
+}
@@ -1,15 +1,23 @@
 use std::fmt::Display;
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
+#[cfg(feature = "gui")]
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::Instant;
 
 use anyhow::Error;
 use chrono::{DateTime, Local};
-use egui_toast::{Toast, ToastKind};
+#[cfg(feature = "gui")]
+use egui_toast::Toast;
+use serde::{Deserialize, Serialize};
+use serialport::{DataBits, FlowControl, Parity, StopBits};
 
-use crate::utils::enums::{Direction, StepperState};
+use crate::utils::constants::MAX_RECONNECT_BACKOFF_MS;
+use crate::utils::enums::{Direction, FrameFormat, MessageKind, StepperState};
+use crate::utils::errors::{MotorError, SerialError};
+use crate::utils::protocols::Protocol;
 
+#[cfg(feature = "gui")]
 pub struct FontAndButtonSize {
     pub font_table: f32,
     pub font_default: f32,
@@ -19,7 +27,7 @@ pub struct FontAndButtonSize {
 }
 
 pub struct Message {
-    pub kind: ToastKind,
+    pub kind: MessageKind,
     pub message: String,
     pub error: Option<Error>,
     pub origin: Option<String>,
@@ -28,7 +36,7 @@ pub struct Message {
 }
 
 impl Message {
-    pub fn new(kind: ToastKind, message: &str, error: Option<Error>, origin: Option<String>, duration: u64, is_waiting: bool) -> Self {
+    pub fn new(kind: MessageKind, message: &str, error: Option<Error>, origin: Option<String>, duration: u64, is_waiting: bool) -> Self {
         Self {
             kind,
             message: message.into(),
@@ -38,8 +46,21 @@ impl Message {
             is_waiting,
         }
     }
+
+    /// Serial error carried by the message, directly or through a motor error.
+    pub fn serial_error(&self) -> Option<&SerialError> {
+        let error = self.error.as_ref()?;
+        if let Some(serial_error) = error.downcast_ref::<SerialError>() {
+            return Some(serial_error);
+        }
+        match error.downcast_ref::<MotorError>() {
+            Some(MotorError::Serial(serial_error)) => Some(serial_error),
+            _ => None,
+        }
+    }
 }
 
+#[cfg(feature = "gui")]
 #[derive(Default)]
 pub struct Channels {
     pub toast_tx: Option<Sender<Toast>>,
@@ -52,6 +73,12 @@ pub struct Channels {
 pub struct WindowsState {
     pub is_confirmation_dialog_open: bool,
     pub is_error_log_open: bool,
+    pub is_save_config_open: bool,
+    pub is_api_open: bool,
+    pub is_mqtt_open: bool,
+    pub is_modbus_open: bool,
+    pub is_sila_open: bool,
+    pub is_notifications_open: bool,
 }
 
 #[derive(Default)]
@@ -102,6 +129,85 @@ pub struct Durations {
     pub global_duration: DurationHelper,
 }
 
+impl Durations {
+    pub fn update_from_protocol(&mut self, protocol: &Protocol) {
+        self.duration_of_one_direction_cycle_rotation.self_from_milliseconds(protocol.rotation.duration_of_one_direction_cycle_ms);
+        self.pause_before_direction_change_rotation.self_from_milliseconds(protocol.rotation.pause_before_direction_change_ms);
+        self.rotation_duration.self_from_milliseconds(protocol.rotation_duration_ms);
+        self.pause_pre_agitation.self_from_milliseconds(protocol.pause_pre_agitation_ms);
+        self.duration_of_one_direction_cycle_agitation.self_from_milliseconds(protocol.agitation.duration_of_one_direction_cycle_ms);
+        self.pause_before_direction_change_agitation.self_from_milliseconds(protocol.agitation.pause_before_direction_change_ms);
+        self.agitation_duration.self_from_milliseconds(protocol.agitation_duration_ms);
+        self.pause_post_agitation.self_from_milliseconds(protocol.pause_post_agitation_ms);
+        self.global_duration.self_from_milliseconds(protocol.global_duration_ms);
+    }
+}
+
+/// Automatic reconnection of a serial port after a read error.
+/// The delay before each attempt doubles, starting from `backoff_ms`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    pub max_retries: u32,
+    pub backoff_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_retries: 10,
+            backoff_ms: 1000,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before the attempt, starting at 1.
+    pub fn delay_ms(&self, attempt: u32) -> u64 {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        self.backoff_ms.saturating_mul(factor).min(MAX_RECONNECT_BACKOFF_MS)
+    }
+}
+
+/// Settings of the serial link to a Raspberry, remembered per port between sessions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub timeout_ms: u64,
+    pub handshake_retries: u32,
+    pub retry_delay_ms: u64,
+    /// Deadline for the acknowledgement of a command.
+    pub ack_timeout_ms: u64,
+    pub command_retries: u32,
+    pub frame_format: FrameFormat,
+    pub reconnect_policy: ReconnectPolicy,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: 500000,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            timeout_ms: 2000,
+            handshake_retries: 15,
+            retry_delay_ms: 500,
+            ack_timeout_ms: 500,
+            command_retries: 2,
+            frame_format: FrameFormat::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct TimersAndPhases {
     pub global_start_time: Option<Instant>,
@@ -137,6 +243,11 @@ impl TimersAndPhases {
         }
     }
 
+    /// Elapsed time of the ongoing run, or duration of the last one once stopped. `None` before the first run.
+    pub fn get_run_elapsed_ms(&self) -> Option<u64> {
+        self.global_start_time.map(|_| self.global_stop_time_ms.unwrap_or_else(|| self.get_elapsed_time_since_global_start_as_millis()))
+    }
+
     pub fn set_global_stop_time_stopped(&mut self) {
         self.global_stop_time_ms = Some(self.get_elapsed_time_since_global_start_as_millis());
     }
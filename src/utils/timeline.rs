@@ -0,0 +1,340 @@
+use std::fmt::{Display, Formatter};
+
+use fugit::TimerInstantU64;
+
+use crate::utils::enums::Direction;
+use crate::utils::protocols::{Protocol, Rotation};
+
+/// Phases of one loop of a protocol, in the order they run.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PhaseKind {
+    Rotation,
+    PausePreAgitation,
+    Agitation,
+    PausePostAgitation,
+}
+
+impl PhaseKind {
+    pub const ALL: [PhaseKind; 4] = [PhaseKind::Rotation, PhaseKind::PausePreAgitation, PhaseKind::Agitation, PhaseKind::PausePostAgitation];
+
+    pub fn is_pause(&self) -> bool {
+        matches!(self, PhaseKind::PausePreAgitation | PhaseKind::PausePostAgitation)
+    }
+}
+
+impl Display for PhaseKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhaseKind::Rotation => write!(f, "Rotation"),
+            PhaseKind::PausePreAgitation => write!(f, "Pause pre-agitation"),
+            PhaseKind::Agitation => write!(f, "Agitation"),
+            PhaseKind::PausePostAgitation => write!(f, "Pause post-agitation"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SubPhaseKind {
+    /// One direction cycle.
+    Turning(Direction),
+    /// Pause before changing direction.
+    Pause,
+}
+
+/// Part of a rotation or agitation phase, in milliseconds since the start of the run.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SubPhase {
+    pub kind: SubPhaseKind,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+/// Phase of the run, in milliseconds since the start of the run. The last phases are cut at the global duration.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Phase {
+    pub kind: PhaseKind,
+    pub loop_index: u64,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// `None` for the pauses.
+    pub rotation: Option<Rotation>,
+}
+
+impl Phase {
+    pub fn duration_ms(&self) -> u64 {
+        self.end_ms - self.start_ms
+    }
+
+    /// Direction cycles and pauses of the phase. Empty for the pauses, and when the motor does not move because the cycle duration is 0.
+    pub fn sub_phases(&self) -> impl Iterator<Item=SubPhase> {
+        let (start_ms, end_ms) = (self.start_ms, self.end_ms);
+        let rotation = self.rotation.filter(|rotation| rotation.duration_of_one_direction_cycle_ms != 0).unwrap_or_default();
+        let period_ms = rotation.get_min_duration();
+        let cycle_count = div_ceil(end_ms - start_ms, period_ms);
+        (0..cycle_count).flat_map(move |cycle| {
+            let cycle_start_ms = start_ms + cycle * period_ms;
+            let turning_end_ms = (cycle_start_ms + rotation.duration_of_one_direction_cycle_ms).min(end_ms);
+            let direction = if cycle % 2 == 0 { rotation.direction } else { rotation.direction.reverse() };
+            [
+                SubPhase { kind: SubPhaseKind::Turning(direction), start_ms: cycle_start_ms, end_ms: turning_end_ms },
+                SubPhase { kind: SubPhaseKind::Pause, start_ms: turning_end_ms, end_ms: (cycle_start_ms + period_ms).min(end_ms) },
+            ].into_iter().filter(|sub_phase| sub_phase.end_ms > sub_phase.start_ms)
+        })
+    }
+
+    /// Times at which the motor starts turning in the other direction.
+    pub fn direction_changes(&self) -> impl Iterator<Item=u64> {
+        self.sub_phases()
+            .filter(|sub_phase| matches!(sub_phase.kind, SubPhaseKind::Turning(_)))
+            .skip(1)
+            .map(|sub_phase| sub_phase.start_ms)
+    }
+}
+
+/// Steps and revolutions of a whole run.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct MotionTotals {
+    pub steps: u64,
+    pub revolutions: f64,
+}
+
+/// Whole run of a protocol, as executed by the firmware: the loop of phases repeated until `global_duration_ms`.
+/// Phases and sub-phases are generated on demand since a run of several days can hold millions of cycles.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProtocolTimeline {
+    protocol: Protocol,
+    /// 0 when there is nothing to run.
+    pub duration_ms: u64,
+    pub loop_duration_ms: u64,
+    /// Loops started before the end of the run, the last one being possibly cut.
+    pub loop_count: u64,
+    /// Time spent turning, without the pauses.
+    pub active_ms: u64,
+}
+
+impl ProtocolTimeline {
+    pub fn new(protocol: &Protocol) -> Self {
+        let loop_duration_ms = PhaseKind::ALL.iter().map(|kind| phase_duration_ms(protocol, *kind)).sum::<u64>();
+        let duration_ms = if loop_duration_ms == 0 { 0 } else { protocol.global_duration_ms };
+        let loop_count = div_ceil(duration_ms, loop_duration_ms);
+        let mut timeline = Self {
+            protocol: *protocol,
+            duration_ms,
+            loop_duration_ms,
+            loop_count,
+            active_ms: 0,
+        };
+        timeline.active_ms = timeline.loop_phases().map(|(phase, repeat)| turning_ms(&phase) * repeat).sum();
+        timeline
+    }
+
+    pub fn phases(&self) -> impl Iterator<Item=Phase> + '_ {
+        (0..self.loop_count).flat_map(move |loop_index| PhaseKind::ALL.into_iter().filter_map(move |kind| self.phase(loop_index, kind)))
+    }
+
+    /// Phase running `elapsed_ms` after the start of the run.
+    pub fn phase_at(&self, elapsed_ms: u64) -> Option<Phase> {
+        if elapsed_ms >= self.duration_ms {
+            return None;
+        }
+        let loop_index = elapsed_ms / self.loop_duration_ms;
+        PhaseKind::ALL.into_iter()
+            .filter_map(|kind| self.phase(loop_index, kind))
+            .find(|phase| (phase.start_ms..phase.end_ms).contains(&elapsed_ms))
+    }
+
+    /// Times at which the motor starts turning in the other direction, across the whole run.
+    pub fn direction_changes(&self) -> impl Iterator<Item=u64> + '_ {
+        self.phases().flat_map(|phase| phase.direction_changes())
+    }
+
+    /// Steps counted by running the step generator on every distinct cycle of the run.
+    pub fn motion_totals(&self) -> MotionTotals {
+        let mut totals = MotionTotals::default();
+        for (phase, repeat) in self.loop_phases() {
+            let Some(rotation) = phase.rotation else { continue };
+            let period_ms = rotation.get_min_duration();
+            if rotation.duration_of_one_direction_cycle_ms == 0 || period_ms == 0 {
+                continue;
+            }
+            let full_cycles = phase.duration_ms() / period_ms;
+            let last_cycle_ms = (phase.duration_ms() % period_ms).min(rotation.duration_of_one_direction_cycle_ms);
+            let steps = full_cycles * steps_within(&rotation, rotation.duration_of_one_direction_cycle_ms) + steps_within(&rotation, last_cycle_ms);
+            totals.steps += steps * repeat;
+            totals.revolutions += (steps * repeat) as f64 / (rotation.step_mode.get_multiplier() as f64 * 200.0);
+        }
+        totals
+    }
+
+    fn phase(&self, loop_index: u64, kind: PhaseKind) -> Option<Phase> {
+        let offset_ms = PhaseKind::ALL.iter().take_while(|other| **other != kind).map(|other| phase_duration_ms(&self.protocol, *other)).sum::<u64>();
+        let start_ms = loop_index * self.loop_duration_ms + offset_ms;
+        let duration_ms = phase_duration_ms(&self.protocol, kind);
+        if duration_ms == 0 || start_ms >= self.duration_ms {
+            return None;
+        }
+        let rotation = match kind {
+            PhaseKind::Rotation => Some(self.protocol.rotation),
+            PhaseKind::Agitation => Some(self.protocol.agitation),
+            _ => None,
+        };
+        Some(Phase { kind, loop_index, start_ms, end_ms: (start_ms + duration_ms).min(self.duration_ms), rotation })
+    }
+
+    /// Phases of the first loop, repeated by every full loop, then the phases of the last loop if it is cut.
+    fn loop_phases(&self) -> impl Iterator<Item=(Phase, u64)> + '_ {
+        let full_loops = self.duration_ms.checked_div(self.loop_duration_ms).unwrap_or(0);
+        let first_loop = PhaseKind::ALL.into_iter().filter_map(move |kind| self.phase(0, kind)).map(move |phase| (phase, full_loops));
+        let last_loop = (self.loop_count > full_loops).then_some(full_loops).into_iter()
+            .flat_map(move |loop_index| PhaseKind::ALL.into_iter().filter_map(move |kind| self.phase(loop_index, kind)))
+            .map(|phase| (phase, 1));
+        first_loop.filter(|(_, repeat)| *repeat != 0).chain(last_loop)
+    }
+}
+
+fn phase_duration_ms(protocol: &Protocol, kind: PhaseKind) -> u64 {
+    match kind {
+        PhaseKind::Rotation => protocol.rotation_duration_ms,
+        PhaseKind::PausePreAgitation => protocol.pause_pre_agitation_ms,
+        PhaseKind::Agitation => protocol.agitation_duration_ms,
+        PhaseKind::PausePostAgitation => protocol.pause_post_agitation_ms,
+    }
+}
+
+/// `value / divisor` rounded up, 0 when `divisor` is 0.
+fn div_ceil(value: u64, divisor: u64) -> u64 {
+    (value + divisor.saturating_sub(1)).checked_div(divisor).unwrap_or(0)
+}
+
+fn turning_ms(phase: &Phase) -> u64 {
+    let Some(rotation) = phase.rotation else { return 0 };
+    let period_ms = rotation.get_min_duration();
+    if rotation.duration_of_one_direction_cycle_ms == 0 || period_ms == 0 {
+        return 0;
+    }
+    let full_cycles = phase.duration_ms() / period_ms;
+    full_cycles * rotation.duration_of_one_direction_cycle_ms + (phase.duration_ms() % period_ms).min(rotation.duration_of_one_direction_cycle_ms)
+}
+
+/// Steps generated during the first `duration_ms` of a direction cycle.
+fn steps_within(rotation: &Rotation, duration_ms: u64) -> u64 {
+    if duration_ms == 0 {
+        return 0;
+    }
+    let mut stepgen = Rotation { duration_of_one_direction_cycle_ms: duration_ms, ..*rotation }.create_stepgen();
+    let mut elapsed_us = 0;
+    while let Some(delay_us) = stepgen.next_delay(Some(TimerInstantU64::from_ticks(elapsed_us / 1000))) {
+        elapsed_us += delay_us;
+    }
+    stepgen.get_current_step()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loop of 5000 ms: rotation of 3000 ms by cycles of 1000 ms and pauses of 200 ms, pause of 500 ms,
+    /// agitation of 900 ms by cycles of 300 ms without pause, pause of 600 ms. The third loop is cut after 2000 ms.
+    fn protocol() -> Protocol {
+        Protocol {
+            rotation: Rotation { rpm: 60, acceleration: 1000, duration_of_one_direction_cycle_ms: 1000, pause_before_direction_change_ms: 200, ..Default::default() },
+            rotation_duration_ms: 3000,
+            pause_pre_agitation_ms: 500,
+            agitation: Rotation { rpm: 60, acceleration: 1000, duration_of_one_direction_cycle_ms: 300, direction: Direction::Backward, ..Default::default() },
+            agitation_duration_ms: 900,
+            pause_post_agitation_ms: 600,
+            global_duration_ms: 12_000,
+        }
+    }
+
+    fn kind_at(timeline: &ProtocolTimeline, elapsed_ms: u64) -> Option<(PhaseKind, u64)> {
+        timeline.phase_at(elapsed_ms).map(|phase| (phase.kind, phase.loop_index))
+    }
+
+    #[test]
+    fn global_duration_cuts_the_last_loop() {
+        let timeline = ProtocolTimeline::new(&protocol());
+        assert_eq!(timeline.loop_duration_ms, 5000);
+        assert_eq!(timeline.duration_ms, 12_000);
+        assert_eq!(timeline.loop_count, 3);
+        let phases: Vec<Phase> = timeline.phases().collect();
+        assert_eq!(phases.len(), 9);
+        let last = phases.last().unwrap();
+        assert_eq!((last.kind, last.loop_index, last.start_ms, last.end_ms), (PhaseKind::Rotation, 2, 10_000, 12_000));
+        let last_sub_phases: Vec<SubPhase> = last.sub_phases().collect();
+        assert_eq!(last_sub_phases.last().unwrap(), &SubPhase { kind: SubPhaseKind::Turning(Direction::Backward), start_ms: 11_200, end_ms: 12_000 });
+    }
+
+    #[test]
+    fn zero_length_phases_are_skipped() {
+        let protocol = Protocol { pause_pre_agitation_ms: 0, pause_post_agitation_ms: 0, ..protocol() };
+        let timeline = ProtocolTimeline::new(&protocol);
+        assert_eq!(timeline.loop_duration_ms, 3900);
+        let kinds: Vec<PhaseKind> = timeline.phases().take(4).map(|phase| phase.kind).collect();
+        assert_eq!(kinds, [PhaseKind::Rotation, PhaseKind::Agitation, PhaseKind::Rotation, PhaseKind::Agitation]);
+        assert_eq!(kind_at(&timeline, 3000), Some((PhaseKind::Agitation, 0)));
+        assert_eq!(kind_at(&timeline, 3900), Some((PhaseKind::Rotation, 1)));
+    }
+
+    #[test]
+    fn nothing_runs_without_phases() {
+        let protocol = Protocol { rotation_duration_ms: 0, pause_pre_agitation_ms: 0, agitation_duration_ms: 0, pause_post_agitation_ms: 0, ..protocol() };
+        let timeline = ProtocolTimeline::new(&protocol);
+        assert_eq!((timeline.duration_ms, timeline.loop_count, timeline.active_ms), (0, 0, 0));
+        assert_eq!(timeline.phases().count(), 0);
+        assert_eq!(timeline.phase_at(0), None);
+    }
+
+    #[test]
+    fn phase_at_boundaries() {
+        let timeline = ProtocolTimeline::new(&protocol());
+        assert_eq!(kind_at(&timeline, 0), Some((PhaseKind::Rotation, 0)));
+        assert_eq!(kind_at(&timeline, 2999), Some((PhaseKind::Rotation, 0)));
+        assert_eq!(kind_at(&timeline, 3000), Some((PhaseKind::PausePreAgitation, 0)));
+        assert_eq!(kind_at(&timeline, 3500), Some((PhaseKind::Agitation, 0)));
+        assert_eq!(kind_at(&timeline, 4400), Some((PhaseKind::PausePostAgitation, 0)));
+        assert_eq!(kind_at(&timeline, 4999), Some((PhaseKind::PausePostAgitation, 0)));
+        assert_eq!(kind_at(&timeline, 5000), Some((PhaseKind::Rotation, 1)));
+        assert_eq!(kind_at(&timeline, 11_999), Some((PhaseKind::Rotation, 2)));
+        assert_eq!(kind_at(&timeline, 12_000), None);
+    }
+
+    #[test]
+    fn active_time_of_the_run() {
+        // Full loop: rotation 2 × 1000 + 600 of the third cycle, agitation 3 × 300, i.e. 3500.
+        // Cut loop: rotation 1000 + 800 of the second cycle, i.e. 1800.
+        let timeline = ProtocolTimeline::new(&protocol());
+        assert_eq!(timeline.active_ms, 2 * 3500 + 1800);
+    }
+
+    #[test]
+    fn direction_changes_of_a_phase() {
+        let timeline = ProtocolTimeline::new(&protocol());
+        let first = timeline.phases().next().unwrap();
+        assert_eq!(first.direction_changes().collect::<Vec<u64>>(), [1200, 2400]);
+    }
+
+    #[test]
+    fn motion_totals_of_the_turning_time() {
+        // Same cycles as `active_time_of_the_run`: the pauses add no step.
+        let protocol = protocol();
+        let rotation = |duration_ms| steps_within(&protocol.rotation, duration_ms);
+        let agitation = |duration_ms| steps_within(&protocol.agitation, duration_ms);
+        let full_loop = 2 * rotation(1000) + rotation(600) + 3 * agitation(300);
+        let cut_loop = rotation(1000) + rotation(800);
+        let totals = ProtocolTimeline::new(&protocol).motion_totals();
+        assert_eq!(totals.steps, 2 * full_loop + cut_loop);
+        let steps_per_revolution = protocol.rotation.step_mode.get_multiplier() as f64 * 200.0;
+        assert!((totals.revolutions - totals.steps as f64 / steps_per_revolution).abs() < 1e-9);
+        // 1 revolution per second once the ramp of 60 ms is over.
+        let revolutions = rotation(1000) as f64 / steps_per_revolution;
+        assert!((0.9..1.0).contains(&revolutions), "{} revolutions", revolutions);
+    }
+
+    #[test]
+    fn motion_totals_without_pauses_between_phases() {
+        let protocol = Protocol { pause_pre_agitation_ms: 0, pause_post_agitation_ms: 0, global_duration_ms: 2 * 3900, ..protocol() };
+        let full_loop = 2 * steps_within(&protocol.rotation, 1000) + steps_within(&protocol.rotation, 600) + 3 * steps_within(&protocol.agitation, 300);
+        assert_eq!(ProtocolTimeline::new(&protocol).motion_totals().steps, 2 * full_loop);
+    }
+}
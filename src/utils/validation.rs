@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter};
+
+/// Ordered from the most severe.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    /// The protocol is refused.
+    Error,
+    /// The protocol runs, but probably not as expected.
+    Warning,
+}
+
+/// Field of a `Protocol`, as displayed in the tab.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProtocolField {
+    RotationRpm,
+    RotationAcceleration,
+    RotationCycleDuration,
+    RotationPause,
+    RotationDuration,
+    PausePreAgitation,
+    AgitationRpm,
+    AgitationAcceleration,
+    AgitationCycleDuration,
+    AgitationPause,
+    AgitationDuration,
+    PausePostAgitation,
+    GlobalDuration,
+    /// Step of a step protocol, by its index in `StepProtocol::steps`.
+    Step(usize),
+    /// Blocks and loops of a step protocol.
+    Steps,
+}
+
+impl Display for ProtocolField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolField::RotationRpm => write!(f, "Rotation RPM"),
+            ProtocolField::RotationAcceleration => write!(f, "Rotation acceleration"),
+            ProtocolField::RotationCycleDuration => write!(f, "Rotation cycle duration"),
+            ProtocolField::RotationPause => write!(f, "Rotation pause"),
+            ProtocolField::RotationDuration => write!(f, "Rotation duration"),
+            ProtocolField::PausePreAgitation => write!(f, "Pause pre-agitation"),
+            ProtocolField::AgitationRpm => write!(f, "Agitation RPM"),
+            ProtocolField::AgitationAcceleration => write!(f, "Agitation acceleration"),
+            ProtocolField::AgitationCycleDuration => write!(f, "Agitation cycle duration"),
+            ProtocolField::AgitationPause => write!(f, "Agitation pause"),
+            ProtocolField::AgitationDuration => write!(f, "Agitation duration"),
+            ProtocolField::PausePostAgitation => write!(f, "Pause post-agitation"),
+            ProtocolField::GlobalDuration => write!(f, "Global duration"),
+            ProtocolField::Step(index) => write!(f, "Step {}", index + 1),
+            ProtocolField::Steps => write!(f, "Steps"),
+        }
+    }
+}
+
+/// Problem found by `Protocol::validate` or `StepProtocol::validate`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ValidationIssue {
+    pub field: ProtocolField,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl ValidationIssue {
+    pub fn error(field: ProtocolField, message: impl Into<String>) -> Self {
+        Self { field, severity: Severity::Error, message: message.into() }
+    }
+
+    pub fn warning(field: ProtocolField, message: impl Into<String>) -> Self {
+        Self { field, severity: Severity::Warning, message: message.into() }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Most severe issue of the field.
+pub fn field_issue(issues: &[ValidationIssue], field: ProtocolField) -> Option<&ValidationIssue> {
+    issues.iter().filter(|issue| issue.field == field).min_by_key(|issue| issue.severity)
+}
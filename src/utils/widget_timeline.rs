@@ -0,0 +1,95 @@
+use chrono::{DateTime, Local};
+use eframe::emath::{Pos2, Rect, Vec2};
+use egui::{Color32, Stroke, Widget};
+
+use crate::app::THEME;
+use crate::utils::structs::DurationHelper;
+use crate::utils::timeline::{Phase, PhaseKind, ProtocolTimeline};
+
+/// Minimum width in points between two direction changes for their ticks to be drawn.
+const MIN_TICK_SPACING: f32 = 3.0;
+
+/// Gantt chart of the phases of a whole run, with a cursor at the current time of the run.
+#[derive(Copy, Clone)]
+pub struct PhaseTimeline {
+    pub timeline: ProtocolTimeline,
+    /// Elapsed time of the run, `None` when the motor is not running.
+    pub elapsed_ms: Option<u64>,
+    /// Used to show the wall-clock times of the phases on hover.
+    pub expected_end_date: Option<DateTime<Local>>,
+    pub height: f32,
+}
+
+impl PhaseTimeline {
+    pub fn new(timeline: ProtocolTimeline, elapsed_ms: Option<u64>, expected_end_date: Option<DateTime<Local>>) -> Self {
+        Self {
+            timeline,
+            elapsed_ms,
+            expected_end_date,
+            height: 24.0,
+        }
+    }
+
+    fn color(kind: PhaseKind) -> Color32 {
+        match kind {
+            PhaseKind::Rotation => THEME.sapphire,
+            PhaseKind::Agitation => THEME.blue,
+            PhaseKind::PausePreAgitation | PhaseKind::PausePostAgitation => THEME.surface0,
+        }
+    }
+
+    fn hover_text(&self, phase: &Phase) -> String {
+        let duration = DurationHelper::new_from_milliseconds(phase.duration_ms());
+        let mut text = format!("{} (loop {})\nDuration: {}", phase.kind, phase.loop_index + 1, duration);
+        if let Some(expected_end_date) = self.expected_end_date {
+            let start_date = expected_end_date - chrono::Duration::milliseconds(self.timeline.duration_ms as i64);
+            let format = |offset_ms: u64| (start_date + chrono::Duration::milliseconds(offset_ms as i64)).format("%Y/%m/%d %H:%M:%S").to_string();
+            text.push_str(&format!("\nStart: {}\nEnd: {}", format(phase.start_ms), format(phase.end_ms)));
+        }
+        text
+    }
+}
+
+impl Widget for PhaseTimeline {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let desired_size = Vec2::new(ui.available_width(), self.height);
+        let (rect, response) = ui.allocate_exact_size(desired_size, egui::Sense::hover());
+        if self.timeline.duration_ms == 0 {
+            return response;
+        }
+        let ms_per_point = self.timeline.duration_ms as f64 / rect.width() as f64;
+        let x_of = |ms: u64| rect.left() + (ms as f64 / ms_per_point) as f32;
+        if ui.is_rect_visible(rect) {
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 0.0, THEME.crust);
+            // One lookup per point of width, so that a run of millions of cycles costs the same as a short one.
+            let mut previous: Option<Phase> = None;
+            for column in 0..rect.width().ceil() as u64 {
+                let Some(phase) = self.timeline.phase_at((column as f64 * ms_per_point) as u64) else { continue };
+                if previous.map_or(false, |previous| previous.start_ms == phase.start_ms) {
+                    continue;
+                }
+                previous = Some(phase);
+                let block = Rect::from_min_max(Pos2::new(x_of(phase.start_ms), rect.top()), Pos2::new(x_of(phase.end_ms).max(x_of(phase.start_ms) + 1.0), rect.bottom()));
+                painter.rect_filled(block, 0.0, Self::color(phase.kind));
+                let is_spaced = phase.rotation.map_or(false, |rotation| rotation.get_min_duration() as f64 / ms_per_point >= MIN_TICK_SPACING as f64);
+                if is_spaced {
+                    for change_ms in phase.direction_changes() {
+                        let x = x_of(change_ms);
+                        painter.line_segment([Pos2::new(x, rect.top()), Pos2::new(x, rect.top() + rect.height() * 0.4)], Stroke::new(1.0, THEME.crust));
+                    }
+                }
+            }
+            if let Some(elapsed_ms) = self.elapsed_ms {
+                let x = x_of(elapsed_ms.min(self.timeline.duration_ms));
+                painter.line_segment([Pos2::new(x, rect.top()), Pos2::new(x, rect.bottom())], Stroke::new(2.0, THEME.red));
+            }
+        }
+        let hovered_phase = response.hover_pos()
+            .and_then(|position| self.timeline.phase_at(((position.x - rect.left()) as f64 * ms_per_point) as u64));
+        match hovered_phase {
+            Some(phase) => response.on_hover_text_at_pointer(self.hover_text(&phase)),
+            None => response,
+        }
+    }
+}